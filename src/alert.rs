@@ -0,0 +1,659 @@
+//! Detection rules and the alerts they raise
+//!
+//! Rules are loaded from a built-in rule set or yaml rule files. A rule either matches single
+//! records, fires once enough matching records show up within an event id span (`threshold`), or
+//! fires when a path goes through a `sequence` of events. The records are checked in event id
+//! order (see `replay`) so the windows and sequences follow the order the events happened in.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use bus::BusReader;
+
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
+use hashbrown::HashMap;
+use jiff::Timestamp;
+use regex::Regex;
+#[cfg(feature = "hex")]
+use serde_hex::{CompactCapPfx, SerHex, SerHexOpt};
+
+use crate::{
+    expr::{Expr, PathMatch},
+    flags,
+    normalize::PathOpts,
+    opts::{CompressOpts, CsvOpts, Format},
+    output::RecordWriter,
+    record::{self, Record},
+    replay,
+};
+
+/// The built-in macOS rule set
+const MACOS_DEFAULT: &str = r#"
+name: macos-default
+rules:
+  - id: tmp-executable
+    severity: high
+    description: A script or binary was created in a temp directory
+    path: '^/?(private/)?(var/)?tmp/'
+    flags: [Created]
+    where: >-
+      flags has FileEvent and
+      (ext ~ "(?i)^(sh|command|py|pl|rb|js|scpt|dylib|so|bin|macho)$" or not name contains ".")
+  - id: hidden-launch-item
+    severity: high
+    description: A hidden file was created in a LaunchAgents or LaunchDaemons directory
+    path: '(^|/)Library/Launch(Agents|Daemons)/\.[^/]+$'
+    flags: [Created, Renamed]
+  - id: launch-item-created
+    severity: medium
+    description: A launch agent or daemon was created
+    path: '(^|/)Library/Launch(Agents|Daemons)/[^/.][^/]*\.plist$'
+    flags: [Created, Renamed]
+  - id: fseventsd-removed
+    severity: critical
+    description: The fseventsd logs were removed
+    path: '(^|/)\.fseventsd(/|$)'
+    flags: [Removed]
+  - id: mass-deletion
+    severity: medium
+    description: Many files were removed within a short span of events
+    flags: [Removed]
+    where: 'flags has FileEvent'
+    threshold:
+      count: 500
+      within: 5000
+  - id: tmp-drop-and-remove
+    severity: low
+    description: A file was created and then removed from a temp directory
+    path: '^/?(private/)?(var/)?tmp/'
+    sequence:
+      - flags: [Created]
+      - flags: [Removed]
+    within: 10000
+"#;
+
+/// The names of the built-in rule sets
+pub const BUILTIN_RULE_SETS: &[&str] = &["macos-default"];
+
+/// How many records are checked between dropping the stale partial sequences
+const SEQUENCE_PRUNE_INTERVAL: u64 = 10_000;
+
+/// How serious an alert is
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+/// The tests of a rule (or of a sequence step); every given test has to match
+#[derive(Debug, Default, Deserialize)]
+struct Condition {
+    /// A path regex
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    glob: Option<String>,
+    /// Matches if any of these flags are present
+    #[serde(default)]
+    flags: Vec<String>,
+    #[serde(default, rename = "where")]
+    where_expr: Option<String>,
+}
+
+impl Condition {
+    /// Compiles the tests, `Expr::True` if there aren't any
+    fn compile(&self, name: &str) -> Result<Expr> {
+        let mut terms = vec![];
+
+        if let Some(rex) = &self.path {
+            terms.push(Expr::Path(PathMatch::Regex(
+                Regex::new(rex).map_err(|e| eyre!("Invalid path in rule {name}: {e}"))?,
+            )));
+        }
+        if let Some(glob) = &self.glob {
+            terms.push(Expr::Path(PathMatch::glob(glob)?));
+        }
+        if !self.flags.is_empty() {
+            let mut mask = 0;
+            for flag in self.flags.iter() {
+                mask |= flags::flag_id(flag)
+                    .ok_or_else(|| eyre!("Unknown flag '{flag}' in rule {name}"))?;
+            }
+            terms.push(Expr::Flags { mask, all: false });
+        }
+        if let Some(expr) = &self.where_expr {
+            terms.push(Expr::parse(expr).wrap_err_with(|| format!("Invalid rule {name}"))?);
+        }
+
+        Ok(Expr::and(terms))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Threshold {
+    /// How many matching records fire the rule
+    count: usize,
+    /// The event id span the records have to be within
+    within: u64,
+}
+
+/// A rule set as written in a rules file
+#[derive(Debug, Deserialize)]
+struct RuleSet {
+    #[serde(default)]
+    name: Option<String>,
+    rules: Vec<RuleSpec>,
+}
+
+/// A single rule as written in a rules file
+#[derive(Debug, Deserialize)]
+struct RuleSpec {
+    id: String,
+    severity: Severity,
+    #[serde(default)]
+    description: String,
+    #[serde(flatten)]
+    condition: Condition,
+    #[serde(default)]
+    threshold: Option<Threshold>,
+    /// The steps a path has to go through (in order) for the rule to fire
+    #[serde(default)]
+    sequence: Vec<Condition>,
+    /// The event id span a sequence has to complete within
+    #[serde(default)]
+    within: Option<u64>,
+}
+
+impl RuleSpec {
+    fn build(self, set: &str, path_opts: PathOpts) -> Result<Rule> {
+        let name = format!("{set}/{}", self.id);
+        let expr = self.condition.compile(&name)?.with_path_opts(path_opts)?;
+
+        let kind = match (self.threshold, self.sequence.is_empty()) {
+            (Some(_), false) => {
+                return Err(eyre!(
+                    "The rule {name} can't have a threshold and a sequence"
+                ));
+            }
+            (Some(Threshold { count, within }), true) => {
+                if count == 0 {
+                    return Err(eyre!("The threshold of rule {name} needs a count"));
+                }
+                RuleKind::Threshold {
+                    count,
+                    within,
+                    ids: VecDeque::new(),
+                }
+            }
+            (None, false) => RuleKind::Sequence {
+                steps: self
+                    .sequence
+                    .iter()
+                    .map(|c| c.compile(&name)?.with_path_opts(path_opts))
+                    .collect::<Result<_>>()?,
+                within: self
+                    .within
+                    .ok_or_else(|| eyre!("The sequence of rule {name} needs a `within` span"))?,
+                pending: HashMap::new(),
+            },
+            (None, true) => RuleKind::Match,
+        };
+
+        if matches!(kind, RuleKind::Match | RuleKind::Threshold { .. })
+            && matches!(expr, Expr::True)
+        {
+            return Err(eyre!("The rule {name} doesn't have anything to match"));
+        }
+
+        Ok(Rule {
+            id: Arc::from(self.id),
+            severity: self.severity,
+            description: Arc::from(self.description),
+            expr,
+            kind,
+        })
+    }
+}
+
+/// How a rule fires
+#[derive(Debug)]
+enum RuleKind {
+    /// On every matching record
+    Match,
+    /// When `count` matching records are within `within` event ids of each other
+    Threshold {
+        count: usize,
+        within: u64,
+        /// The event ids of the matching records in the current window
+        ids: VecDeque<u64>,
+    },
+    /// When a path matches every step in order within `within` event ids
+    Sequence {
+        steps: Vec<Expr>,
+        within: u64,
+        /// The next step and first event id of the paths part way through the sequence
+        pending: HashMap<String, (usize, u64)>,
+    },
+}
+
+/// A compiled rule and its state
+#[derive(Debug)]
+struct Rule {
+    id: Arc<str>,
+    severity: Severity,
+    description: Arc<str>,
+    /// Every record the rule looks at has to match this (the sequence steps are on top of it)
+    expr: Expr,
+    kind: RuleKind,
+}
+
+impl Rule {
+    /// Checks the next record, returning the first event id and record count if the rule fires
+    fn check(&mut self, rec: &Record, path: &str) -> Option<(u64, u64)> {
+        if !self.expr.eval_path(rec, path) {
+            return None;
+        }
+
+        match &mut self.kind {
+            RuleKind::Match => Some((rec.event_id, 1)),
+            RuleKind::Threshold { count, within, ids } => {
+                ids.push_back(rec.event_id);
+                while ids
+                    .front()
+                    .is_some_and(|first| rec.event_id.saturating_sub(*first) > *within)
+                {
+                    ids.pop_front();
+                }
+
+                // Start a new window after firing so a single burst only raises one alert
+                (ids.len() >= *count).then(|| {
+                    let first = ids[0];
+                    let n = ids.len() as u64;
+                    ids.clear();
+                    (first, n)
+                })
+            }
+            RuleKind::Sequence {
+                steps,
+                within,
+                pending,
+            } => {
+                let (mut next, first) = match pending.get(path) {
+                    Some(&(next, first)) if rec.event_id.saturating_sub(first) <= *within => {
+                        (next, first)
+                    }
+                    Some(_) => {
+                        pending.remove(path);
+                        (0, rec.event_id)
+                    }
+                    None => (0, rec.event_id),
+                };
+
+                // Coalesced records (e.g. `Created | Removed`) can complete several steps at once
+                let start = next;
+                while next < steps.len() && steps[next].eval_path(rec, path) {
+                    next += 1;
+                }
+
+                if next == steps.len() {
+                    pending.remove(path);
+                    Some((first, steps.len() as u64))
+                } else {
+                    if next > start {
+                        pending.insert(path.to_string(), (next, first));
+                    }
+                    None
+                }
+            }
+        }
+    }
+
+    /// Drops the partial sequences that can't complete anymore
+    fn prune(&mut self, event_id: u64) {
+        if let RuleKind::Sequence {
+            within, pending, ..
+        } = &mut self.kind
+        {
+            pending.retain(|_, (_, first)| event_id.saturating_sub(*first) <= *within);
+        }
+    }
+}
+
+/// An alert raised by a rule
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    pub rule: Arc<str>,
+    pub severity: Severity,
+    pub description: Arc<str>,
+    /// The path of the record that fired the rule
+    pub path: String,
+    #[cfg_attr(feature = "hex", serde(with = "SerHex::<CompactCapPfx>"))]
+    pub event_id: u64,
+    /// The first event id of the window or sequence (the record's own id for single matches)
+    #[cfg_attr(feature = "hex", serde(with = "SerHex::<CompactCapPfx>"))]
+    pub first_event_id: u64,
+    /// How many records the alert covers
+    pub count: u64,
+    pub flags: &'static str,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub node_id: Option<u64>,
+    #[serde(serialize_with = "record::serialize_optional_timestamp")]
+    pub file_timestamp: Option<Timestamp>,
+}
+
+/// Checks the records against every rule
+#[derive(Debug, Default)]
+pub struct Detector {
+    rules: Vec<Rule>,
+    path_opts: PathOpts,
+    checked: u64,
+}
+
+impl Detector {
+    /// Loads the rule sets (built-in names or yaml files), `macos-default` if none are given
+    pub fn load(rule_sets: &[String], path_opts: PathOpts) -> Result<Self> {
+        let default = [BUILTIN_RULE_SETS[0].to_string()];
+        let rule_sets = match rule_sets.is_empty() {
+            true => &default[..],
+            false => rule_sets,
+        };
+
+        let mut rules = vec![];
+        for set in rule_sets {
+            rules.extend(load_rule_set(set, path_opts)?);
+        }
+
+        let mut ids = hashbrown::HashSet::new();
+        if let Some(dupe) = rules.iter().find(|r| !ids.insert(r.id.clone())) {
+            return Err(eyre!("The rule id '{}' is used more than once", dupe.id));
+        }
+
+        info!("Loaded {} detection rules", rules.len());
+        Ok(Self {
+            rules,
+            path_opts,
+            checked: 0,
+        })
+    }
+
+    /// Checks the next record (in event id order) and returns any alerts it raised
+    pub fn check(&mut self, rec: &Record) -> Vec<Alert> {
+        let path = self.path_opts.normalize(rec.key_path());
+
+        self.checked += 1;
+        if self.checked.is_multiple_of(SEQUENCE_PRUNE_INTERVAL) {
+            self.rules.iter_mut().for_each(|r| r.prune(rec.event_id));
+        }
+
+        self.rules
+            .iter_mut()
+            .filter_map(|rule| {
+                let (first_event_id, count) = rule.check(rec, &path)?;
+                Some(Alert {
+                    rule: rule.id.clone(),
+                    severity: rule.severity,
+                    description: rule.description.clone(),
+                    path: rec.path.clone(),
+                    event_id: rec.event_id,
+                    first_event_id,
+                    count,
+                    flags: rec.flags,
+                    node_id: rec.node_id,
+                    file_timestamp: rec.file_timestamp,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Where the alerts are written
+#[derive(Debug)]
+pub struct AlertOutput {
+    pub path: PathBuf,
+    pub format: Format,
+    pub detector: Detector,
+}
+
+impl AlertOutput {
+    /// If the alerts are written to stdout
+    #[cfg(feature = "watch")]
+    pub fn is_stdout(&self) -> bool {
+        self.path.as_os_str() == "-"
+    }
+
+    fn open(&self, copts: &CompressOpts) -> Result<Box<dyn Write>> {
+        copts
+            .make_output(&self.path)
+            .wrap_err_with(|| format!("Couldn't create the alerts output {}", self.path.display()))
+    }
+}
+
+/// Checks the records from a bus receiver (in event id order) and writes the alerts they raise
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving record updates
+/// * `out` - The rules and where to write their alerts
+/// * `copts` - The compression options used to open the output
+/// * `csv_opts` - The csv dialect to use
+/// * `flush_all` - Whether to flush after each alert (and check the buffered records whenever
+///   the bus is idle so alerts stream out while watching)
+pub fn alert_write(
+    recv: BusReader<Arc<Record>>,
+    out: AlertOutput,
+    copts: CompressOpts,
+    csv_opts: CsvOpts,
+    flush_all: bool,
+) {
+    let writer = match out.open(&copts) {
+        Ok(w) => w,
+        Err(err) => {
+            error!("{err:#}");
+            return;
+        }
+    };
+
+    let AlertOutput {
+        format,
+        mut detector,
+        ..
+    } = out;
+    let mut writer = RecordWriter::new(format, writer, &csv_opts, false);
+    let mut count = 0u64;
+    let idle = flush_all.then(|| Duration::from_millis(200));
+
+    replay::replay(recv, idle, |rec| {
+        for alert in detector.check(rec) {
+            count += 1;
+            if let Err(err) = writer.write(&alert) {
+                error!("Couldn't write the alert: {err}");
+            }
+            if flush_all && let Err(err) = writer.flush() {
+                error!("Couldn't flush the alerts: {err}");
+            }
+        }
+    });
+
+    if let Err(err) = writer.flush() {
+        error!("Couldn't flush the alerts: {err}");
+    }
+    info!("Raised {count} alerts");
+}
+
+fn parse_rule_set(yaml: &str, default_name: &str, path_opts: PathOpts) -> Result<Vec<Rule>> {
+    let set: RuleSet = serde_yaml::from_str(yaml)?;
+    let name = set.name.as_deref().unwrap_or(default_name).to_string();
+
+    set.rules
+        .into_iter()
+        .map(|r| r.build(&name, path_opts))
+        .collect()
+}
+
+/// Loads a rule set by its built-in name or from a yaml file
+fn load_rule_set(set: &str, path_opts: PathOpts) -> Result<Vec<Rule>> {
+    match set {
+        "macos-default" => parse_rule_set(MACOS_DEFAULT, set, path_opts),
+        path => {
+            let path = Path::new(path);
+            let yaml = fs::read_to_string(path).wrap_err_with(|| {
+                format!(
+                    "'{}' isn't a built-in rule set ({}) or a readable file",
+                    path.display(),
+                    BUILTIN_RULE_SETS.join(", ")
+                )
+            })?;
+
+            let default_name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.display().to_string());
+            parse_rule_set(&yaml, &default_name, path_opts)
+                .wrap_err_with(|| format!("Invalid rules file {}", path.display()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(yaml: &str) -> Detector {
+        Detector {
+            rules: parse_rule_set(yaml, "test", PathOpts::default()).unwrap(),
+            ..Detector::default()
+        }
+    }
+
+    fn fired(d: &mut Detector, rec: &Record) -> Vec<String> {
+        d.check(rec)
+            .into_iter()
+            .map(|a| a.rule.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_builtin_rules() {
+        let mut d = Detector::load(&[], PathOpts::default()).unwrap();
+
+        let rec = Record::event("private/tmp/payload", 1, &["Created", "FileEvent"]);
+        assert_eq!(fired(&mut d, &rec), vec!["tmp-executable"]);
+        let rec = Record::event("private/tmp/notes.txt", 2, &["Created", "FileEvent"]);
+        assert!(fired(&mut d, &rec).is_empty());
+
+        let rec = Record::event(
+            "Users/bob/Library/LaunchAgents/.hidden.plist",
+            3,
+            &["Created", "FileEvent"],
+        );
+        assert_eq!(fired(&mut d, &rec), vec!["hidden-launch-item"]);
+
+        let rec = Record::event(
+            "Library/LaunchDaemons/com.evil.plist",
+            4,
+            &["Created", "FileEvent"],
+        );
+        assert_eq!(fired(&mut d, &rec), vec!["launch-item-created"]);
+
+        let rec = Record::event(".fseventsd/000000000342c4f2", 5, &["Removed", "FileEvent"]);
+        assert_eq!(fired(&mut d, &rec), vec!["fseventsd-removed"]);
+    }
+
+    #[test]
+    fn test_threshold_rule() {
+        let mut d = detector(
+            r#"
+rules:
+  - id: burst
+    severity: medium
+    flags: [Removed]
+    threshold: { count: 3, within: 10 }
+"#,
+        );
+
+        let mut fires = vec![];
+        for id in [1, 20, 25, 26, 27, 28, 29, 30, 100] {
+            let alerts = d.check(&Record::event("a", id, &["Removed"]));
+            fires.extend(
+                alerts
+                    .into_iter()
+                    .map(|a| (a.first_event_id, a.event_id, a.count)),
+            );
+        }
+
+        // 1 is too far from the rest and the window starts over after firing
+        assert_eq!(fires, vec![(20, 26, 3), (27, 29, 3)]);
+    }
+
+    #[test]
+    fn test_sequence_rule() {
+        let mut d = detector(
+            r#"
+rules:
+  - id: dropper
+    severity: low
+    path: '^tmp/'
+    sequence:
+      - flags: [Created]
+      - flags: [Modified]
+      - flags: [Removed]
+    within: 100
+"#,
+        );
+
+        assert!(fired(&mut d, &Record::event("tmp/a", 1, &["Created"])).is_empty());
+        assert!(fired(&mut d, &Record::event("tmp/b", 2, &["Created"])).is_empty());
+        assert!(fired(&mut d, &Record::event("tmp/a", 3, &["Modified"])).is_empty());
+        let alerts = d.check(&Record::event("tmp/a", 4, &["Removed"]));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].first_event_id, alerts[0].count), (1, 3));
+
+        // Too slow to complete
+        assert!(fired(&mut d, &Record::event("tmp/b", 200, &["Modified"])).is_empty());
+        assert!(fired(&mut d, &Record::event("tmp/b", 201, &["Removed"])).is_empty());
+
+        // Coalesced records complete several steps at once
+        let rec = Record::event("tmp/c", 300, &["Created", "Modified", "Removed"]);
+        assert_eq!(fired(&mut d, &rec), vec!["dropper"]);
+        assert!(
+            fired(
+                &mut d,
+                &Record::event("other/c", 301, &["Created", "Modified", "Removed"])
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let bad = |yaml: &str| parse_rule_set(yaml, "test", PathOpts::default()).is_err();
+
+        assert!(bad("rules:\n  - id: a\n    severity: low\n"));
+        assert!(bad(
+            "rules:\n  - id: a\n    severity: bad\n    flags: [Removed]\n"
+        ));
+        assert!(bad(
+            "rules:\n  - id: a\n    severity: low\n    sequence:\n      - flags: [Created]\n"
+        ));
+        assert!(bad(
+            "rules:\n  - id: a\n    severity: low\n    flags: [Nope]\n"
+        ));
+        assert!(!bad(
+            "rules:\n  - id: a\n    severity: low\n    flags: [Removed]\n"
+        ));
+    }
+}
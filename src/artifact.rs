@@ -0,0 +1,358 @@
+//! Forensic artifact classification
+//!
+//! Artifact rules label the paths of well known artifacts (browser history, launch agents, the
+//! TCC database, ssh keys, ...) with a category and a name. The built-in table can be extended
+//! with yaml rule files; their rules are checked first so they can override the built-in ones.
+//! Every rule is compiled into one `RegexSet` and the first matching rule wins.
+
+use std::{collections::BTreeSet, fs, path::Path, sync::Arc};
+
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
+use regex::RegexSetBuilder;
+
+use crate::expr;
+
+/// The built-in artifact rules
+const BUILTIN_RULES: &str = r#"
+artifacts:
+  # Browsers
+  - name: safari-history
+    category: browser
+    path: '(^|/)Users/[^/]+/Library/Safari/History\.db(-wal|-shm)?$'
+  - name: safari-downloads
+    category: browser
+    path: '(^|/)Users/[^/]+/Library/Safari/Downloads\.plist$'
+  - name: chrome-history
+    category: browser
+    path: '(^|/)Users/[^/]+/Library/Application Support/Google/Chrome/[^/]+/(History|Cookies|Login Data)(-journal)?$'
+  - name: edge-history
+    category: browser
+    path: '(^|/)Users/[^/]+/Library/Application Support/Microsoft Edge/[^/]+/(History|Cookies|Login Data)(-journal)?$'
+  - name: brave-history
+    category: browser
+    path: '(^|/)Users/[^/]+/Library/Application Support/BraveSoftware/Brave-Browser/[^/]+/(History|Cookies|Login Data)(-journal)?$'
+  - name: firefox-history
+    category: browser
+    path: '(^|/)Users/[^/]+/Library/Application Support/Firefox/Profiles/[^/]+/(places|cookies|formhistory)\.sqlite(-wal|-shm)?$'
+
+  # Persistence
+  - name: launch-agent
+    category: persistence
+    path: '(^|/)Library/LaunchAgents/[^/]+$'
+  - name: launch-daemon
+    category: persistence
+    path: '(^|/)Library/LaunchDaemons/[^/]+$'
+  - name: login-items
+    category: persistence
+    path: '(^|/)(backgrounditems\.btm|BackgroundItems-v\d+\.btm)$'
+  - name: login-hook
+    category: persistence
+    path: '(^|/)Library/Preferences/com\.apple\.loginwindow\.plist$'
+  - name: startup-item
+    category: persistence
+    path: '(^|/)Library/StartupItems/'
+  - name: kernel-extension
+    category: persistence
+    path: '(^|/)Library/Extensions/[^/]+\.kext(/|$)'
+  - name: cron
+    category: persistence
+    path: '(^|/)(private/)?var/at/tabs/[^/]+$|(^|/)(private/)?etc/crontab$'
+  - name: periodic
+    category: persistence
+    path: '(^|/)(private/)?etc/periodic/'
+  - name: emond
+    category: persistence
+    path: '(^|/)(private/)?etc/emond\.d/'
+  - name: shell-profile
+    category: persistence
+    path: '(^|/)Users/[^/]+/\.(zshrc|zshenv|zprofile|zlogin|bashrc|bash_profile|profile)$|(^|/)(private/)?etc/(zshrc|zprofile|bashrc|profile)$'
+
+  # Privacy and execution
+  - name: tcc-db
+    category: privacy
+    path: '(^|/)Library/Application Support/com\.apple\.TCC/TCC\.db(-wal|-shm)?$'
+  - name: quarantine-events
+    category: download
+    path: '(^|/)Users/[^/]+/Library/Preferences/com\.apple\.LaunchServices\.QuarantineEventsV2(-journal)?$'
+  - name: knowledgec
+    category: execution
+    path: '(^|/)Library/Application Support/Knowledge/knowledgeC\.db(-wal|-shm)?$'
+
+  # Credentials
+  - name: ssh-key
+    category: credentials
+    path: '(^|/)Users/[^/]+/\.ssh/'
+  - name: root-ssh-key
+    category: credentials
+    path: '(^|/)(private/)?var/root/\.ssh/'
+  - name: keychain
+    category: credentials
+    path: '(^|/)Library/Keychains/[^/]+\.keychain(-db)?$'
+  - name: sudoers
+    category: credentials
+    path: '(^|/)(private/)?etc/sudoers(\.d/|$)'
+
+  # Shell history
+  - name: shell-history
+    category: shell-history
+    path: '(^|/)\.(zsh_history|bash_history|sh_history|python_history)$|(^|/)\.(zsh|bash)_sessions/'
+
+  # Trash
+  - name: trash
+    category: trash
+    path: '(^|/)Users/[^/]+/\.Trash(/|$)|(^|/)\.Trashes(/|$)'
+
+  # Cloud sync
+  - name: icloud-drive
+    category: cloud-sync
+    path: '(^|/)Users/[^/]+/Library/Mobile Documents(/|$)'
+  - name: dropbox
+    category: cloud-sync
+    path: '(^|/)Users/[^/]+/(Dropbox|Library/CloudStorage/Dropbox[^/]*)(/|$)'
+  - name: google-drive
+    category: cloud-sync
+    path: '(^|/)Users/[^/]+/(Google Drive|Library/CloudStorage/GoogleDrive[^/]*)(/|$)'
+  - name: onedrive
+    category: cloud-sync
+    path: '(^|/)Users/[^/]+/(OneDrive[^/]*|Library/CloudStorage/OneDrive[^/]*)(/|$)'
+  - name: cloud-storage
+    category: cloud-sync
+    path: '(^|/)Users/[^/]+/Library/CloudStorage(/|$)'
+
+  # Logs
+  - name: unified-log
+    category: logs
+    path: '(^|/)(private/)?var/db/(diagnostics|uuidtext)/'
+  - name: system-log
+    category: logs
+    path: '(^|/)(private/)?var/log/'
+  - name: user-log
+    category: logs
+    path: '(^|/)Users/[^/]+/Library/Logs/'
+"#;
+
+/// An artifact rule file
+#[derive(Debug, Deserialize)]
+struct RuleFile {
+    artifacts: Vec<Rule>,
+}
+
+/// A single rule; it needs a path regex or a glob
+#[derive(Debug, Deserialize)]
+struct Rule {
+    name: String,
+    category: String,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    glob: Option<String>,
+}
+
+impl Rule {
+    /// The rule's pattern as a regex
+    fn pattern(&self) -> Result<String> {
+        match (&self.path, &self.glob) {
+            (Some(rex), None) => Ok(rex.clone()),
+            (None, Some(glob)) => {
+                let glob = glob.trim_start_matches('/');
+                let rex = expr::glob_regex(glob)?.as_str().to_string();
+                // Globs without a `/` match the file name
+                Ok(match glob.contains('/') {
+                    true => rex,
+                    false => format!("(^|/){}", rex.trim_start_matches('^')),
+                })
+            }
+            _ => Err(eyre!(
+                "The artifact rule {} needs either a path or a glob",
+                self.name
+            )),
+        }
+    }
+}
+
+/// The compiled artifact rules
+#[derive(Debug)]
+pub struct Classifier {
+    /// The `(category, name)` of each rule in the set
+    labels: Vec<(Arc<str>, Arc<str>)>,
+    set: regex::RegexSet,
+    /// The label of the paths that don't match any rule
+    none: Arc<str>,
+}
+
+impl Classifier {
+    /// Compiles the rules from the rule files (checked in order) followed by the built-in ones
+    pub fn load(files: &[impl AsRef<Path>]) -> Result<Self> {
+        let mut rules = vec![];
+        for path in files {
+            let path = path.as_ref();
+            let yaml = fs::read_to_string(path)
+                .wrap_err_with(|| format!("Couldn't read the artifact rules {}", path.display()))?;
+            rules.extend(
+                parse_rules(&yaml)
+                    .wrap_err_with(|| format!("Invalid artifact rules {}", path.display()))?,
+            );
+        }
+        rules.extend(parse_rules(BUILTIN_RULES)?);
+
+        Self::new(rules)
+    }
+
+    fn new(rules: Vec<Rule>) -> Result<Self> {
+        let pats = rules
+            .iter()
+            .map(Rule::pattern)
+            .collect::<Result<Vec<_>>>()?;
+        // macOS volumes are case insensitive by default
+        let set = RegexSetBuilder::new(&pats)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| eyre!("Invalid artifact rule: {e}"))?;
+
+        Ok(Self {
+            labels: rules
+                .into_iter()
+                .map(|r| (Arc::from(r.category), Arc::from(r.name)))
+                .collect(),
+            set,
+            none: Arc::from(""),
+        })
+    }
+
+    /// The `(category, name)` of the first rule matching the path
+    pub fn classify(&self, path: &str) -> Option<(&Arc<str>, &Arc<str>)> {
+        let idx = self.set.matches(path).iter().next()?;
+        let (category, name) = &self.labels[idx];
+        Some((category, name))
+    }
+
+    /// The shared empty label for the paths that aren't artifacts
+    #[inline]
+    pub fn none(&self) -> &Arc<str> {
+        &self.none
+    }
+
+    /// Every category the rules have
+    pub fn categories(&self) -> BTreeSet<&str> {
+        self.labels.iter().map(|(c, _)| &**c).collect()
+    }
+}
+
+fn parse_rules(yaml: &str) -> Result<Vec<Rule>> {
+    let file: RuleFile = serde_yaml::from_str(yaml)?;
+    Ok(file.artifacts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(c: &Classifier, path: &str) -> Option<(String, String)> {
+        c.classify(path)
+            .map(|(cat, name)| (cat.to_string(), name.to_string()))
+    }
+
+    #[test]
+    fn test_builtin_rules() {
+        let c = Classifier::load(&[] as &[&Path]).unwrap();
+        let is = |path: &str, cat: &str, name: &str| {
+            assert_eq!(
+                label(&c, path),
+                Some((cat.to_string(), name.to_string())),
+                "{path}"
+            );
+        };
+
+        is(
+            "Users/bob/Library/Safari/History.db",
+            "browser",
+            "safari-history",
+        );
+        is(
+            "/Users/bob/Library/Application Support/Google/Chrome/Default/History",
+            "browser",
+            "chrome-history",
+        );
+        is(
+            "Users/bob/Library/LaunchAgents/com.evil.plist",
+            "persistence",
+            "launch-agent",
+        );
+        is(
+            "Library/LaunchDaemons/com.evil.plist",
+            "persistence",
+            "launch-daemon",
+        );
+        is(
+            "System/Volumes/Data/Library/Application Support/com.apple.TCC/TCC.db",
+            "privacy",
+            "tcc-db",
+        );
+        is(
+            "Users/bob/Library/Preferences/com.apple.LaunchServices.QuarantineEventsV2",
+            "download",
+            "quarantine-events",
+        );
+        is("Users/bob/.ssh/id_ed25519", "credentials", "ssh-key");
+        is(
+            "Users/bob/Library/Keychains/login.keychain-db",
+            "credentials",
+            "keychain",
+        );
+        is("Users/bob/.Trash/a.dmg", "trash", "trash");
+        is(
+            "Users/bob/Library/CloudStorage/Dropbox/a.txt",
+            "cloud-sync",
+            "dropbox",
+        );
+        is(
+            "Users/bob/Library/CloudStorage/Box-Box/a.txt",
+            "cloud-sync",
+            "cloud-storage",
+        );
+        is("users/bob/.zsh_history", "shell-history", "shell-history");
+        is("private/var/log/system.log", "logs", "system-log");
+
+        assert_eq!(label(&c, "Users/bob/Documents/a.txt"), None);
+        assert_eq!(label(&c, "Users/bob/Library/LaunchAgents"), None);
+        assert!(c.categories().contains("persistence"));
+    }
+
+    #[test]
+    fn test_user_rules() {
+        let mut rules = parse_rules(
+            r#"
+artifacts:
+  - name: evil-agent
+    category: malware
+    glob: 'Users/*/Library/LaunchAgents/com.evil.*'
+  - name: notes
+    category: notes
+    glob: 'NoteStore.sqlite'
+"#,
+        )
+        .unwrap();
+        rules.extend(parse_rules(BUILTIN_RULES).unwrap());
+        let c = Classifier::new(rules).unwrap();
+
+        // The user rules come first so they win over the built-in ones
+        assert_eq!(
+            label(&c, "Users/bob/Library/LaunchAgents/com.evil.plist"),
+            Some(("malware".into(), "evil-agent".into()))
+        );
+        assert_eq!(
+            label(&c, "Users/bob/Library/LaunchAgents/com.good.plist"),
+            Some(("persistence".into(), "launch-agent".into()))
+        );
+        assert_eq!(
+            label(&c, "Users/bob/Library/Group Containers/x/NoteStore.sqlite"),
+            Some(("notes".into(), "notes".into()))
+        );
+
+        let bad = parse_rules("artifacts:\n  - name: x\n    category: y\n").unwrap();
+        assert!(Classifier::new(bad).is_err());
+    }
+}
@@ -0,0 +1,474 @@
+//! Burst detection over the event id order
+//!
+//! Ransomware and wipers touch hundreds of files in a short span of events, usually renaming them
+//! to a new extension (`report.docx` -> `report.docx.locked`). A window slides over the renamed,
+//! modified and removed records in event id order and a burst lasts as long as the window holds
+//! enough of them. Each burst is reported with its event id range, a time estimate, how many
+//! directories it touched, how many renames changed the extension and a few sample paths.
+//!
+//! The event id order is per volume dir: the files of each dir are parsed in event id order
+//! (even when they're listed out of order) but the dirs are replayed one after the other, as
+//! every volume has its own event ids.
+
+use std::{collections::VecDeque, path::Path, sync::Arc};
+
+use bus::BusReader;
+use hashbrown::{HashMap, HashSet};
+use jiff::Timestamp;
+#[cfg(feature = "hex")]
+use serde_hex::{CompactCapPfx, SerHex};
+
+use crate::{
+    derived, flags,
+    opts::{BurstOpts, CompressOpts, CsvOpts, Format},
+    output::RecordWriter,
+    record::{self, Record},
+    replay, route,
+};
+
+/// How many sample paths each burst keeps
+const SAMPLE_PATHS: usize = 5;
+
+/// How many of the new extensions each burst lists
+const TOP_EXTS: usize = 3;
+
+/// How many renames have to change the extension before a burst can be suspected ransomware
+const MIN_RANSOMWARE_EXT_CHANGES: u64 = 10;
+
+/// The records bursts are made of
+const ACTIVITY_FLAGS: &[&str] = &["Renamed", "Modified", "Removed"];
+
+/// A reported burst
+#[derive(Clone, Debug, Serialize)]
+pub struct Burst {
+    #[cfg_attr(feature = "hex", serde(with = "SerHex::<CompactCapPfx>"))]
+    pub first_event_id: u64,
+    #[cfg_attr(feature = "hex", serde(with = "SerHex::<CompactCapPfx>"))]
+    pub last_event_id: u64,
+    /// How many renamed, modified or removed records the burst has
+    pub events: u64,
+    pub created: u64,
+    pub modified: u64,
+    pub renamed: u64,
+    pub removed: u64,
+    /// The distinct directories the burst touched
+    pub dirs: u64,
+    /// The rename pairs (old and new path) found in the burst
+    pub renames: u64,
+    /// The renames that changed the extension
+    pub ext_changes: u64,
+    /// `ext_changes / renames`
+    pub ext_change_ratio: f64,
+    /// The most common extensions files were renamed to (most common first)
+    #[serde(serialize_with = "serialize_list")]
+    pub new_exts: Vec<String>,
+    /// If the burst looks like ransomware (enough renames changed the extension and the ratio is
+    /// over the threshold)
+    pub suspected_ransomware: bool,
+    /// The events happened after this (the timestamp of the file before the burst's first one)
+    #[serde(serialize_with = "record::serialize_optional_timestamp")]
+    pub time_from: Option<Timestamp>,
+    /// The events happened before this (the timestamp of the file with the burst's last event)
+    #[serde(serialize_with = "record::serialize_optional_timestamp")]
+    pub time_to: Option<Timestamp>,
+    #[serde(serialize_with = "serialize_list")]
+    pub sample_paths: Vec<String>,
+}
+
+fn serialize_list<S>(list: &[String], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&list.join(" | "))
+}
+
+/// The running totals of a burst
+#[derive(Debug, Default)]
+struct BurstStats {
+    first_event_id: u64,
+    last_event_id: u64,
+    events: u64,
+    created: u64,
+    modified: u64,
+    renamed: u64,
+    removed: u64,
+    dirs: HashSet<String>,
+    renames: u64,
+    ext_changes: u64,
+    new_exts: HashMap<String, u64>,
+    /// The renamed record waiting for the other half of its pair
+    rename_from: Option<Arc<Record>>,
+    time_from: Option<Timestamp>,
+    time_to: Option<Timestamp>,
+    sample_paths: Vec<String>,
+}
+
+impl BurstStats {
+    fn add(&mut self, entry: &Entry, masks: &Masks) {
+        let rec = &entry.rec;
+        if self.events == 0 {
+            self.first_event_id = rec.event_id;
+            self.time_from = entry.after;
+        }
+        self.last_event_id = rec.event_id;
+        self.time_to = rec.file_timestamp.or(self.time_to);
+        self.events += 1;
+
+        let has = |mask: u32| rec.flag & mask != 0;
+        self.created += has(masks.created) as u64;
+        self.modified += has(masks.modified) as u64;
+        self.removed += has(masks.removed) as u64;
+
+        let path = rec.key_path();
+        if !self.dirs.contains(derived::dir(path)) {
+            self.dirs.insert(derived::dir(path).to_string());
+        }
+        if self.sample_paths.len() < SAMPLE_PATHS && !self.sample_paths.iter().any(|p| p == path) {
+            self.sample_paths.push(path.to_string());
+        }
+
+        if has(masks.renamed) {
+            self.renamed += 1;
+            self.pair_rename(rec);
+        }
+    }
+
+    /// Renames are logged as the old path followed by the new one (with the same node id when
+    /// the file has one)
+    fn pair_rename(&mut self, rec: &Arc<Record>) {
+        let Some(from) = self.rename_from.take() else {
+            self.rename_from = Some(rec.clone());
+            return;
+        };
+
+        let paired = match (from.node_id, rec.node_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => rec.event_id == from.event_id + 1,
+        };
+        if !paired {
+            self.rename_from = Some(rec.clone());
+            return;
+        }
+
+        self.renames += 1;
+        let (from, to) = (from.key_path(), rec.key_path());
+        // Atomic saves rename a temp file (`a.plist.GuqrKuP`) to its final name so they don't
+        // count as changing the extension
+        let atomic_save = derived::name(from).starts_with(derived::name(to));
+        let (old, new) = (derived::ext(from), derived::ext(to));
+        if old != new && !atomic_save {
+            self.ext_changes += 1;
+            if let Some(ext) = new {
+                *self.new_exts.entry(ext.to_ascii_lowercase()).or_default() += 1;
+            }
+        }
+    }
+
+    fn finish(self, ext_ratio: f64) -> Burst {
+        let ratio = match self.renames {
+            0 => 0.0,
+            n => self.ext_changes as f64 / n as f64,
+        };
+
+        let mut new_exts: Vec<_> = self.new_exts.into_iter().collect();
+        new_exts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        Burst {
+            first_event_id: self.first_event_id,
+            last_event_id: self.last_event_id,
+            events: self.events,
+            created: self.created,
+            modified: self.modified,
+            renamed: self.renamed,
+            removed: self.removed,
+            dirs: self.dirs.len() as u64,
+            renames: self.renames,
+            ext_changes: self.ext_changes,
+            ext_change_ratio: (ratio * 1000.0).round() / 1000.0,
+            new_exts: new_exts
+                .into_iter()
+                .take(TOP_EXTS)
+                .map(|(ext, _)| ext)
+                .collect(),
+            suspected_ransomware: self.ext_changes >= MIN_RANSOMWARE_EXT_CHANGES
+                && ratio >= ext_ratio,
+            time_from: self.time_from,
+            time_to: self.time_to,
+            sample_paths: self.sample_paths,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Masks {
+    activity: u32,
+    created: u32,
+    modified: u32,
+    renamed: u32,
+    removed: u32,
+}
+
+impl Default for Masks {
+    fn default() -> Self {
+        let id = |f| flags::flag_id(f).expect("Known flag");
+        Self {
+            activity: ACTIVITY_FLAGS.iter().map(|f| id(f)).fold(0, |a, b| a | b),
+            created: id("Created"),
+            modified: id("Modified"),
+            renamed: id("Renamed"),
+            removed: id("Removed"),
+        }
+    }
+}
+
+/// A record in the window
+#[derive(Debug)]
+struct Entry {
+    rec: Arc<Record>,
+    /// The timestamp of the file before the record's own one
+    after: Option<Timestamp>,
+}
+
+/// Finds the bursts in the records (given in event id order)
+#[derive(Debug)]
+pub struct BurstDetector {
+    /// The event id span of the window
+    window: u64,
+    /// How many records the window needs to hold for a burst
+    min_events: usize,
+    /// The extension change ratio at which a burst is suspected ransomware
+    ext_ratio: f64,
+    masks: Masks,
+    entries: VecDeque<Entry>,
+    burst: Option<BurstStats>,
+    /// The source and timestamp of the current file and the timestamp of the one before it
+    source: Option<Arc<Path>>,
+    file_timestamp: Option<Timestamp>,
+    prev_timestamp: Option<Timestamp>,
+}
+
+impl BurstDetector {
+    pub fn new(window: u64, min_events: usize, ext_ratio: f64) -> Self {
+        Self {
+            window,
+            min_events: min_events.max(1),
+            ext_ratio,
+            masks: Masks::default(),
+            entries: VecDeque::new(),
+            burst: None,
+            source: None,
+            file_timestamp: None,
+            prev_timestamp: None,
+        }
+    }
+
+    /// Adds the next record, returning the burst it ended (if any)
+    pub fn push(&mut self, rec: &Arc<Record>) -> Option<Burst> {
+        if rec.source != self.source {
+            self.source = rec.source.clone();
+            self.prev_timestamp = self.file_timestamp.take().or(self.prev_timestamp);
+            self.file_timestamp = rec.file_timestamp;
+        }
+
+        if rec.flag & self.masks.activity == 0 {
+            return None;
+        }
+
+        let entry = Entry {
+            rec: rec.clone(),
+            after: self.prev_timestamp,
+        };
+        while self
+            .entries
+            .front()
+            .is_some_and(|e| rec.event_id.saturating_sub(e.rec.event_id) > self.window)
+        {
+            self.entries.pop_front();
+        }
+
+        // The burst is over once the window can't hold enough records with this one
+        let mut ended = None;
+        if self.burst.is_some() && self.entries.len() + 1 < self.min_events {
+            ended = self.finish();
+        }
+
+        if let Some(burst) = &mut self.burst {
+            burst.add(&entry, &self.masks);
+        }
+        self.entries.push_back(entry);
+
+        if self.burst.is_none() && self.entries.len() >= self.min_events {
+            let mut burst = BurstStats::default();
+            for e in self.entries.iter() {
+                burst.add(e, &self.masks);
+            }
+            self.burst = Some(burst);
+        }
+
+        ended
+    }
+
+    /// Ends the current burst (if any)
+    pub fn finish(&mut self) -> Option<Burst> {
+        // A new burst can't reuse the records of the last one
+        self.entries.clear();
+        self.burst.take().map(|b| b.finish(self.ext_ratio))
+    }
+}
+
+/// Writes the bursts found in the records from a bus receiver
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving record updates
+/// * `opts` - The burst options (with the output path)
+/// * `copts` - The compression options used to open the output
+/// * `csv_opts` - The csv dialect to use
+pub fn burst_write(
+    recv: BusReader<Arc<Record>>,
+    opts: BurstOpts,
+    copts: CompressOpts,
+    csv_opts: CsvOpts,
+) {
+    let Some(path) = opts.bursts else {
+        return;
+    };
+    let writer = match copts.make_output(&path) {
+        Ok(w) => w,
+        Err(err) => {
+            error!(
+                "Couldn't create the bursts output {}: {err}",
+                path.display()
+            );
+            return;
+        }
+    };
+
+    let format = route::infer_format(&path).unwrap_or(Format::Json);
+    let mut writer = RecordWriter::new(format, writer, &csv_opts, false);
+    let mut detector = BurstDetector::new(
+        opts.burst_window,
+        opts.burst_min_events,
+        opts.burst_ext_ratio,
+    );
+    let mut count = 0u64;
+
+    let mut write = |burst: Burst| {
+        count += 1;
+        if let Err(err) = writer.write(&burst) {
+            error!("Couldn't write the burst: {err}");
+        }
+    };
+
+    replay::replay(recv, None, |rec| {
+        if let Some(burst) = detector.push(rec) {
+            write(burst);
+        }
+    });
+    if let Some(burst) = detector.finish() {
+        write(burst);
+    }
+
+    if let Err(err) = writer.flush() {
+        error!("Couldn't flush the bursts: {err}");
+    }
+    info!("Found {count} bursts");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every thousand event ids are a second later and in the next file
+    fn burst_record(path: &str, event_id: u64, flag: &str, node_id: Option<u64>) -> Arc<Record> {
+        Arc::new(
+            Record::event(path, event_id, &[flag])
+                .with_node_id(node_id)
+                .with_timestamp(Timestamp::new(1_700_000_000 + event_id as i64 / 1000, 0).ok())
+                .with_source(format!("{:016x}", event_id / 1000)),
+        )
+    }
+
+    fn run(detector: &mut BurstDetector, recs: &[Arc<Record>]) -> Vec<Burst> {
+        let mut bursts: Vec<_> = recs.iter().filter_map(|r| detector.push(r)).collect();
+        bursts.extend(detector.finish());
+        bursts
+    }
+
+    #[test]
+    fn test_ransomware_burst() {
+        let mut recs = vec![burst_record("Users/bob/notes.txt", 10, "Modified", None)];
+        // Every document renamed to .locked in quick succession
+        for i in 0..20u64 {
+            let id = 1000 + i * 2;
+            let old = format!("Users/bob/Documents/d{}/f{i}.docx", i % 4);
+            recs.push(burst_record(&old, id, "Renamed", Some(i)));
+            recs.push(burst_record(
+                &format!("{old}.locked"),
+                id + 1,
+                "Renamed",
+                Some(i),
+            ));
+        }
+        recs.push(burst_record("Users/bob/late.txt", 9000, "Removed", None));
+
+        let bursts = run(&mut BurstDetector::new(100, 10, 0.5), &recs);
+        assert_eq!(bursts.len(), 1);
+
+        let b = &bursts[0];
+        assert_eq!((b.first_event_id, b.last_event_id), (1000, 1039));
+        assert_eq!((b.events, b.renamed, b.renames), (40, 40, 20));
+        assert_eq!((b.ext_changes, b.ext_change_ratio), (20, 1.0));
+        assert_eq!(b.new_exts, vec!["locked"]);
+        assert_eq!(b.dirs, 4);
+        assert!(b.suspected_ransomware);
+        assert_eq!(b.sample_paths.len(), SAMPLE_PATHS);
+        assert_eq!(b.time_from, Timestamp::new(1_700_000_000, 0).ok());
+        assert_eq!(b.time_to, Timestamp::new(1_700_000_001, 0).ok());
+    }
+
+    #[test]
+    fn test_separate_bursts() {
+        let mut recs = vec![];
+        for base in [0, 5000] {
+            for i in 0..10 {
+                recs.push(burst_record(
+                    &format!("a/{base}/{i}"),
+                    base + i,
+                    "Removed",
+                    None,
+                ));
+            }
+        }
+
+        // Atomic saves aren't extension changes
+        for i in 0..10 {
+            let name = format!("a/0/s{i}.plist");
+            recs.insert(
+                0,
+                burst_record(&format!("{name}.Gq{i}"), 10 + i * 2, "Renamed", Some(i)),
+            );
+            recs.insert(1, burst_record(&name, 11 + i * 2, "Renamed", Some(i)));
+        }
+        recs.sort_by_key(|r| r.event_id);
+
+        let bursts = run(&mut BurstDetector::new(50, 5, 0.5), &recs);
+        assert_eq!(bursts.len(), 2);
+        assert_eq!((bursts[0].renames, bursts[0].ext_changes), (10, 0));
+        assert_eq!((bursts[0].first_event_id, bursts[0].last_event_id), (0, 29));
+        assert_eq!(
+            (bursts[1].first_event_id, bursts[1].last_event_id),
+            (5000, 5009)
+        );
+        assert!(
+            bursts
+                .iter()
+                .all(|b| b.removed == 10 && !b.suspected_ransomware)
+        );
+
+        // Sparse events never fill the window
+        let sparse: Vec<_> = (0..20)
+            .map(|i| burst_record("a", i * 100, "Removed", None))
+            .collect();
+        assert!(run(&mut BurstDetector::new(50, 5, 0.5), &sparse).is_empty());
+    }
+}
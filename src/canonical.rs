@@ -0,0 +1,223 @@
+//! Path canonicalization
+//!
+//! Since Catalina the data volume is firmlinked into the root so the same file can show up as
+//! `System/Volumes/Data/Users/...` or `Users/...`, and `var`, `tmp` and `etc` are symlinks into
+//! `private`. The canonicalizer rewrites the known aliases (and any from a user mapping file) to a
+//! single form so filters and uniques see one path per file.
+
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+
+use color_eyre::{Result, eyre::WrapErr};
+
+/// The firmlinks from the data volume into the root (`/usr/share/firmlinks`)
+const FIRMLINKS: &[&str] = &[
+    "AppleInternal",
+    "Applications",
+    "Library",
+    "System/Library/Caches",
+    "System/Library/Assets",
+    "System/Library/PreinstalledAssets",
+    "System/Library/AssetsV2",
+    "System/Library/PreinstalledAssetsV2",
+    "System/Library/CoreServices/CoreTypes.bundle/Contents/Library",
+    "System/Library/Speech",
+    "Users",
+    "Volumes",
+    "cores",
+    "opt",
+    "private",
+    "usr/local",
+    "usr/libexec/cups",
+    "usr/share/snmp",
+];
+
+/// The root symlinks into `private`
+const SYMLINKS: &[(&str, &str)] = &[
+    ("var", "private/var"),
+    ("tmp", "private/tmp"),
+    ("etc", "private/etc"),
+];
+
+/// The data volume the firmlinks point into
+const DATA_VOLUME: &str = "System/Volumes/Data";
+
+/// Rewrites path prefixes (whole components only) with the longest matching rule
+#[derive(Clone, Debug, Default)]
+struct Stage {
+    /// The `(from, to)` rules sorted by the longest `from` first
+    rules: Vec<(String, String)>,
+}
+
+impl Stage {
+    fn new(rules: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut rules: Vec<_> = rules
+            .into_iter()
+            .map(|(from, to)| (trim(&from).to_string(), trim(&to).to_string()))
+            .collect();
+        rules.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        Self { rules }
+    }
+
+    fn apply(&self, path: &str) -> Option<String> {
+        self.rules.iter().find_map(|(from, to)| {
+            let rest = path.strip_prefix(from.as_str())?;
+            if !(rest.is_empty() || rest.starts_with('/')) {
+                return None;
+            }
+
+            Some(if to.is_empty() {
+                rest.trim_start_matches('/').to_string()
+            } else {
+                format!("{to}{rest}")
+            })
+        })
+    }
+}
+
+#[inline]
+fn trim(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+/// Rewrites aliased paths to their canonical form
+#[derive(Clone, Debug)]
+pub struct Canonicalizer {
+    /// The user mappings, then the firmlinks and then the symlinks
+    stages: Vec<Stage>,
+}
+
+impl Default for Canonicalizer {
+    fn default() -> Self {
+        Self {
+            stages: vec![
+                Stage::new(
+                    FIRMLINKS
+                        .iter()
+                        .map(|f| (format!("{DATA_VOLUME}/{f}"), f.to_string())),
+                ),
+                Stage::new(SYMLINKS.iter().map(|(f, t)| (f.to_string(), t.to_string()))),
+            ],
+        }
+    }
+}
+
+impl Canonicalizer {
+    /// Creates a canonicalizer applying the user mappings before the built-in ones
+    pub fn new(mappings: BTreeMap<String, String>) -> Self {
+        let mut canon = Self::default();
+        if !mappings.is_empty() {
+            canon.stages.insert(0, Stage::new(mappings));
+        }
+        canon
+    }
+
+    /// Creates a canonicalizer with the mappings from the yaml files (`from: to` pairs)
+    pub fn load(files: &[impl AsRef<Path>]) -> Result<Self> {
+        let mut mappings = BTreeMap::new();
+        for path in files {
+            let path = path.as_ref();
+            let f = File::open(path)
+                .wrap_err_with(|| format!("Couldn't open the path map {}", path.display()))?;
+            let map: BTreeMap<String, String> = serde_yaml::from_reader(BufReader::new(f))
+                .wrap_err_with(|| format!("Invalid path map {}", path.display()))?;
+            mappings.extend(map);
+        }
+
+        Ok(Self::new(mappings))
+    }
+
+    /// The canonical form of the path (keeping a leading `/` if it has one)
+    pub fn canonicalize(&self, path: &str) -> String {
+        let (root, mut cur) = match path.strip_prefix('/') {
+            Some(rest) => ("/", rest.to_string()),
+            None => ("", path.to_string()),
+        };
+
+        for stage in self.stages.iter() {
+            if let Some(new) = stage.apply(&cur) {
+                cur = new;
+            }
+        }
+
+        format!("{root}{cur}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_aliases() {
+        let canon = Canonicalizer::default();
+
+        assert_eq!(
+            canon.canonicalize("System/Volumes/Data/Users/bob/a.txt"),
+            "Users/bob/a.txt"
+        );
+        assert_eq!(
+            canon.canonicalize("/System/Volumes/Data/private/var/db/x"),
+            "/private/var/db/x"
+        );
+        assert_eq!(
+            canon.canonicalize("var/log/system.log"),
+            "private/var/log/system.log"
+        );
+        assert_eq!(canon.canonicalize("/tmp"), "/private/tmp");
+        assert_eq!(
+            canon.canonicalize("System/Volumes/Data/usr/local/bin/x"),
+            "usr/local/bin/x"
+        );
+
+        // Only whole components are rewritten and non-firmlinked paths are left alone
+        assert_eq!(canon.canonicalize("variable/x"), "variable/x");
+        assert_eq!(
+            canon.canonicalize("System/Volumes/Data/Usersx"),
+            "System/Volumes/Data/Usersx"
+        );
+        assert_eq!(
+            canon.canonicalize("System/Volumes/Data/bin/x"),
+            "System/Volumes/Data/bin/x"
+        );
+        assert_eq!(canon.canonicalize("Users/bob"), "Users/bob");
+    }
+
+    #[test]
+    fn test_user_mappings() {
+        let canon = Canonicalizer::new(BTreeMap::from([
+            ("/Volumes/Evidence".to_string(), "/".to_string()),
+            (
+                "/Volumes/Evidence/Old Home".to_string(),
+                "/Users/bob".to_string(),
+            ),
+        ]));
+
+        // The user mappings run first so the built-in ones apply to their output
+        assert_eq!(
+            canon.canonicalize("Volumes/Evidence/System/Volumes/Data/Users/a"),
+            "Users/a"
+        );
+        assert_eq!(
+            canon.canonicalize("Volumes/Evidence/Old Home/x"),
+            "Users/bob/x"
+        );
+        assert_eq!(
+            canon.canonicalize("Volumes/Evidence/var/x"),
+            "private/var/x"
+        );
+        assert_eq!(canon.canonicalize("Volumes/Other/x"), "Volumes/Other/x");
+    }
+
+    #[test]
+    fn test_load_path_map() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("path_map.yaml");
+        std::fs::write(&path, "/Volumes/Image: /\n'/Volumes/Image 2': /\n").unwrap();
+
+        let canon = Canonicalizer::load(&[&path]).unwrap();
+        assert_eq!(canon.canonicalize("Volumes/Image 2/tmp/x"), "private/tmp/x");
+
+        std::fs::write(&path, "- not a map\n").unwrap();
+        assert!(Canonicalizer::load(&[&path]).is_err());
+    }
+}
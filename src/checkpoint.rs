@@ -0,0 +1,325 @@
+//! Watch checkpoints
+//!
+//! Watch only sees the files created while it's running, so the files fseventsd wrote while it
+//! was down would never be parsed. The checkpoint keeps, for every watched dir, the event id (from
+//! the file names) up to which every file was parsed and the names of the newer files that were
+//! parsed out of order, so the rest can be backfilled on startup without parsing any file twice
+//! or skipping one that was parsed late.
+//!
+//! fseventsd starts over with new event ids when its dir is reset (which gives it a new
+//! `fseventsd-uuid`) so a dir's checkpoint is dropped, and all of its files backfilled, when its
+//! uuid changes.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Result, eyre::WrapErr};
+
+use crate::file_parser::file_event_id;
+
+/// The name of the file fseventsd keeps its uuid in
+const UUID_FILE: &str = "fseventsd-uuid";
+
+/// What has been parsed from a single watched dir
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DirState {
+    /// The fseventsd uuid of the dir when the checkpoint was taken
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// Every file up to this event id (from the file names) has been parsed (or was already in
+    /// the dir when the checkpoint was started)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_event_id: Option<u64>,
+    /// The files parsed past `max_event_id` (while an older file hasn't been parsed yet) and the
+    /// ones that aren't named after an event id
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub processed: BTreeSet<String>,
+}
+
+impl DirState {
+    /// If the file (named relative to the dir) hasn't been parsed yet
+    fn is_new(&self, name: &str, event_id: Option<u64>) -> bool {
+        if self.processed.contains(name) {
+            return false;
+        }
+
+        match (event_id, self.max_event_id) {
+            (Some(id), Some(max)) => id > max,
+            _ => true,
+        }
+    }
+
+    /// Marks the file as parsed
+    fn mark(&mut self, name: &str) {
+        self.processed.insert(name.to_string());
+    }
+
+    /// Raises `max_event_id` past the parsed files in the dir, stopping at the oldest one that
+    /// hasn't been parsed yet
+    ///
+    /// # Arguments
+    /// * `files` - The files in the dir named after an event id, in event id order
+    fn advance(&mut self, files: &[(u64, String)]) {
+        for (id, name) in files {
+            if self.max_event_id.is_some_and(|max| *id <= max) {
+                continue;
+            }
+            if !self.processed.contains(name) {
+                break;
+            }
+            self.max_event_id = Some(*id);
+        }
+
+        // Only the names the event id doesn't already cover need to be kept
+        if let Some(max) = self.max_event_id {
+            self.processed
+                .retain(|n| file_event_id(Path::new(n)).is_none_or(|id| id > max));
+        }
+    }
+}
+
+/// The checkpoint of every watched dir, saved after every parsed file
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    /// The watched dirs (canonicalized) along with their checkpoint key
+    dirs: Vec<(PathBuf, String)>,
+    state: BTreeMap<String, DirState>,
+}
+
+/// Reads the fseventsd uuid of the dir
+fn read_uuid(dir: &Path) -> Option<String> {
+    fs::read_to_string(dir.join(UUID_FILE))
+        .ok()
+        .map(|uuid| uuid.trim().to_string())
+}
+
+/// The files in the dir named after an event id (with their name relative to the dir), in event
+/// id order
+fn dir_files(dir: &Path) -> Vec<(u64, String)> {
+    let mut files: Vec<(u64, String)> = walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                error!("Couldn't read a watched dir: {err}");
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let id = file_event_id(entry.path())?;
+            let name = entry.path().strip_prefix(dir).ok()?;
+            Some((id, name.to_string_lossy().into_owned()))
+        })
+        .collect();
+
+    files.sort();
+    files
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint (if it exists yet) for the watched dirs
+    ///
+    /// The checkpoint of a dir that doesn't have one yet starts at its newest file so the first
+    /// run only parses the files created while it's watching (like watch without a checkpoint)
+    pub fn load(path: PathBuf, watch_dirs: &[PathBuf]) -> Result<Self> {
+        let mut state: BTreeMap<String, DirState> = if path.exists() {
+            let f = File::open(&path)
+                .wrap_err_with(|| format!("Couldn't open the state file {}", path.display()))?;
+            serde_json::from_reader(BufReader::new(f))
+                .wrap_err_with(|| format!("Couldn't read the state file {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut dirs = Vec::with_capacity(watch_dirs.len());
+        for dir in watch_dirs {
+            let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.clone());
+            let key = dir.to_string_lossy().into_owned();
+            let uuid = read_uuid(&dir);
+
+            match state.get(&key) {
+                Some(dir_state) if dir_state.uuid == uuid => {}
+                Some(_) => {
+                    // Every file left in the dir was written since it was reset
+                    warn!(
+                        "The fseventsd uuid of {} changed so all of its files are backfilled",
+                        dir.display()
+                    );
+                    state.insert(key.clone(), DirState::default());
+                }
+                None => {
+                    let dir_state = DirState {
+                        max_event_id: dir_files(&dir).last().map(|(id, _)| *id),
+                        ..DirState::default()
+                    };
+                    state.insert(key.clone(), dir_state);
+                }
+            }
+            state.entry(key.clone()).or_default().uuid = uuid;
+            dirs.push((dir, key));
+        }
+
+        Ok(Self { path, dirs, state })
+    }
+
+    /// The index of the watched dir the file is in, with the file's name relative to it
+    fn locate(&self, file: &Path) -> Option<(usize, String)> {
+        let file = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+        self.dirs.iter().enumerate().find_map(|(i, (dir, _))| {
+            file.strip_prefix(dir)
+                .ok()
+                .map(|name| (i, name.to_string_lossy().into_owned()))
+        })
+    }
+
+    /// If the file hasn't been parsed yet (files outside of the watched dirs always are new)
+    pub fn is_new(&self, file: &Path) -> bool {
+        let Some((i, name)) = self.locate(file) else {
+            return true;
+        };
+        self.state
+            .get(&self.dirs[i].1)
+            .is_none_or(|dir_state| dir_state.is_new(&name, file_event_id(file)))
+    }
+
+    /// Marks the file as parsed and saves the checkpoint
+    pub fn mark(&mut self, file: &Path) -> Result<()> {
+        let Some((i, name)) = self.locate(file) else {
+            return Ok(());
+        };
+        let (dir, key) = &self.dirs[i];
+        let dir_state = self.state.entry(key.clone()).or_default();
+        dir_state.mark(&name);
+        dir_state.advance(&dir_files(dir));
+        self.save()
+    }
+
+    /// Writes the checkpoint (to a temp file that replaces it so it's never half written)
+    fn save(&self) -> Result<()> {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut w, &self.state)?;
+        writeln!(w)?;
+        w.flush()?;
+        drop(w);
+
+        fs::rename(&tmp, &self.path)
+            .wrap_err_with(|| format!("Couldn't save the state file {}", self.path.display()))
+    }
+
+    /// The files in the watched dirs that haven't been parsed yet, in event id order
+    pub fn backfill(&self) -> Vec<PathBuf> {
+        let mut files: Vec<(u64, PathBuf)> = self
+            .dirs
+            .iter()
+            .flat_map(|(dir, key)| {
+                let dir_state = self.state.get(key);
+                dir_files(dir)
+                    .into_iter()
+                    .filter(move |(id, name)| {
+                        dir_state.is_none_or(|state| state.is_new(name, Some(*id)))
+                    })
+                    .map(move |(id, name)| (id, dir.join(name)))
+            })
+            .collect();
+
+        files.sort();
+        files.into_iter().map(|(_, path)| path).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn touch(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, b"").unwrap();
+        path
+    }
+
+    #[test]
+    fn test_dir_state() {
+        let files: Vec<(u64, String)> = [0x10, 0x20, 0x30]
+            .into_iter()
+            .map(|id| (id, format!("{id:016x}")))
+            .collect();
+        let mut state = DirState::default();
+        assert!(state.is_new(&files[0].1, Some(0x10)));
+
+        // The newer file is parsed first so the mark waits for the older one
+        state.mark(&files[1].1);
+        state.advance(&files);
+        assert_eq!(state.max_event_id, None);
+        assert!(state.is_new(&files[0].1, Some(0x10)));
+        assert!(!state.is_new(&files[1].1, Some(0x20)));
+
+        state.mark(&files[0].1);
+        state.advance(&files);
+        assert_eq!(state.max_event_id, Some(0x20));
+        assert!(state.processed.is_empty());
+        assert!(state.is_new(&files[2].1, Some(0x30)));
+
+        state.mark("other");
+        state.advance(&files);
+        assert!(!state.is_new("other", None));
+        assert!(state.is_new("another", None));
+        assert_eq!(state.processed.len(), 1);
+    }
+
+    #[test]
+    fn test_backfill() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("fseventsd");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join(UUID_FILE), "uuid-1\n").unwrap();
+        let state_file = tmp.path().join("state.json");
+        let dirs = [dir.clone()];
+
+        // The first run doesn't backfill the files that were already there
+        let first = touch(&dir, "0000000000000010");
+        let mut checkpoint = Checkpoint::load(state_file.clone(), &dirs).unwrap();
+        assert!(checkpoint.backfill().is_empty());
+        assert!(!checkpoint.is_new(&first));
+        let second = touch(&dir, "0000000000000020");
+        checkpoint.mark(&second).unwrap();
+
+        // The files newer than the checkpoint are backfilled in order
+        let fourth = touch(&dir, "0000000000000040");
+        let third = touch(&dir, "0000000000000030");
+        let mut checkpoint = Checkpoint::load(state_file.clone(), &dirs).unwrap();
+        let canonical = |p: &PathBuf| fs::canonicalize(p).unwrap();
+        assert_eq!(
+            checkpoint.backfill(),
+            vec![canonical(&third), canonical(&fourth)]
+        );
+
+        // Parsing the newer file first doesn't lose the older one
+        checkpoint.mark(&fourth).unwrap();
+        assert!(checkpoint.is_new(&third));
+        assert!(!checkpoint.is_new(&fourth));
+        let mut checkpoint = Checkpoint::load(state_file.clone(), &dirs).unwrap();
+        assert_eq!(checkpoint.backfill(), vec![canonical(&third)]);
+        checkpoint.mark(&third).unwrap();
+
+        let checkpoint = Checkpoint::load(state_file.clone(), &dirs).unwrap();
+        assert!(checkpoint.backfill().is_empty());
+
+        // A new uuid means fseventsd started over so every file is backfilled
+        fs::write(dir.join(UUID_FILE), "uuid-2\n").unwrap();
+        let checkpoint = Checkpoint::load(state_file, &dirs).unwrap();
+        assert!(checkpoint.is_new(&first));
+        assert_eq!(checkpoint.backfill().len(), 4);
+    }
+}
@@ -0,0 +1,133 @@
+//! Fields derived from the record paths
+//!
+//! The fields are slices of the path so they're cheap enough to compute for every filter check;
+//! they're only copied into the record when they're written out (`--derived-fields`).
+
+/// The parts of a path that can be matched like a path
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PathField {
+    /// The parent directory
+    Dir,
+    /// The file name
+    Name,
+    /// The file extension (without the `.`)
+    Ext,
+    /// The user whose home the path is in
+    HomeUser,
+    /// The volume mounted under `/Volumes`
+    Volume,
+}
+
+impl PathField {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dir" | "parent" => Some(PathField::Dir),
+            "name" | "basename" => Some(PathField::Name),
+            "ext" | "extension" => Some(PathField::Ext),
+            "home_user" | "user" => Some(PathField::HomeUser),
+            "volume" => Some(PathField::Volume),
+            _ => None,
+        }
+    }
+
+    /// The field's value for the path (if the path has it)
+    #[inline]
+    pub fn get(self, path: &str) -> Option<&str> {
+        match self {
+            PathField::Dir => Some(dir(path)),
+            PathField::Name => Some(name(path)),
+            PathField::Ext => ext(path),
+            PathField::HomeUser => home_user(path),
+            PathField::Volume => volume(path),
+        }
+    }
+}
+
+/// The parent directory (empty for top level entries)
+#[inline]
+pub fn dir(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(dir, _)| dir)
+        .unwrap_or_default()
+}
+
+/// The file name
+#[inline]
+pub fn name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// The file extension; dotfiles without another `.` don't have one
+#[inline]
+pub fn ext(path: &str) -> Option<&str> {
+    name(path)
+        .rsplit_once('.')
+        .filter(|(stem, ext)| !stem.is_empty() && !ext.is_empty())
+        .map(|(_, ext)| ext)
+}
+
+/// How many components the path has
+#[inline]
+pub fn depth(path: &str) -> u32 {
+    path.split('/').filter(|p| !p.is_empty()).count() as u32
+}
+
+/// The user from `/Users/<name>/` (besides `Shared`) or `root` for `/private/var/root`
+pub fn home_user(path: &str) -> Option<&str> {
+    let mut parts = path.trim_start_matches('/').split('/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("Users"), Some(user), _) if !user.is_empty() && user != "Shared" => Some(user),
+        (Some("private"), Some("var"), Some("root")) | (Some("var"), Some("root"), _) => {
+            Some("root")
+        }
+        _ => None,
+    }
+}
+
+/// The volume from `/Volumes/<name>/`
+pub fn volume(path: &str) -> Option<&str> {
+    let mut parts = path.trim_start_matches('/').split('/');
+    match (parts.next(), parts.next()) {
+        (Some("Volumes"), Some(vol)) if !vol.is_empty() => Some(vol),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_parts() {
+        let path = "Users/bob/Documents/report.final.PDF";
+        assert_eq!(dir(path), "Users/bob/Documents");
+        assert_eq!(name(path), "report.final.PDF");
+        assert_eq!(ext(path), Some("PDF"));
+        assert_eq!(depth(path), 4);
+
+        assert_eq!(dir("top"), "");
+        assert_eq!(name("top"), "top");
+        assert_eq!(depth("/a//b/"), 2);
+        assert_eq!(ext("Users/bob/.zshrc"), None);
+        assert_eq!(ext("Users/bob/trailing."), None);
+        assert_eq!(ext("Users/bob.d/Makefile"), None);
+    }
+
+    #[test]
+    fn test_home_user_and_volume() {
+        assert_eq!(home_user("Users/bob/Desktop/a"), Some("bob"));
+        assert_eq!(home_user("/Users/alice"), Some("alice"));
+        assert_eq!(home_user("Users/Shared/a"), None);
+        assert_eq!(home_user("Users"), None);
+        assert_eq!(home_user("private/var/root/.ssh/id_rsa"), Some("root"));
+        assert_eq!(home_user("private/var/folders/x"), None);
+        assert_eq!(home_user("Library/Users/bob"), None);
+
+        assert_eq!(volume("Volumes/USB Stick/DCIM/a.jpg"), Some("USB Stick"));
+        assert_eq!(volume("/Volumes/Backup"), Some("Backup"));
+        assert_eq!(volume("Users/bob/Volumes/x"), None);
+
+        assert_eq!(PathField::HomeUser.get("Users/bob/a"), Some("bob"));
+        assert_eq!(PathField::Dir.get("a/b"), Some("a"));
+    }
+}
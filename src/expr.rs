@@ -0,0 +1,917 @@
+//! Boolean filter expressions for records
+//!
+//! This module parses the `--where` expression language into an `Expr` tree that is evaluated
+//! against every record. The older filter options (`--path-filter`, `--any-flags` and
+//! `--all-flags`) are compiled into the same tree.
+//!
+//! ```text
+//! expr    = and (("or" | "||") and)*
+//! and     = unary (("and" | "&&") unary)*
+//! unary   = ("not" | "!") unary | "(" expr ")" | test
+//! test    = ("path" | field) path_op STRING
+//!         | "flags" ("has" | "any" | "all") FLAG ("," FLAG)*
+//!         | num_field cmp NUMBER | num_field "in" NUMBER ".." NUMBER
+//!         | "file_timestamp" cmp TIME | "file_timestamp" "in" TIME ".." TIME
+//! field   = "dir" | "name" | "ext" | "home_user" | "volume"
+//! path_op = "~" | "glob" | "prefix" | "suffix" | "contains" | "==" | "!="
+//! cmp     = "==" | "!=" | "<" | "<=" | ">" | ">="
+//! ```
+//!
+//! Keywords are case insensitive, numbers can be decimal or `0x` prefixed hex, ranges are
+//! inclusive and strings can be single or double quoted.
+
+use std::{cmp::Ordering, collections::BTreeSet, fmt, iter::Peekable, str::Chars, sync::Arc};
+
+use color_eyre::{Result, eyre::eyre};
+use jiff::{Timestamp, civil, tz::TimeZone};
+use regex::Regex;
+
+use crate::{
+    derived::{self, PathField},
+    flags,
+    normalize::PathOpts,
+    record::Record,
+};
+
+/// A parsed filter expression
+#[derive(Clone, Debug, Default)]
+pub enum Expr {
+    /// Matches every record
+    #[default]
+    True,
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Path(PathMatch),
+    /// Matches a field derived from the path (like the file name)
+    Field {
+        field: PathField,
+        m: PathMatch,
+    },
+    /// Tests the flag bits; `all` requires every bit in the mask instead of any of them
+    Flags {
+        mask: u32,
+        all: bool,
+    },
+    Num {
+        field: NumField,
+        op: CmpOp,
+        value: u64,
+    },
+    NumRange {
+        field: NumField,
+        lo: u64,
+        hi: u64,
+    },
+    /// Matches if the id is one of the listed ones
+    IdSet {
+        field: NumField,
+        ids: Arc<BTreeSet<u64>>,
+    },
+    Time {
+        op: CmpOp,
+        value: Timestamp,
+    },
+    TimeRange {
+        lo: Timestamp,
+        hi: Timestamp,
+    },
+}
+
+impl Expr {
+    /// Parses an expression
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: lex(s)?,
+            pos: 0,
+        };
+
+        let expr = parser.expr()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(t) => Err(eyre!("Unexpected {t} in the filter expression")),
+        }
+    }
+
+    /// Combines the expressions so all of them have to match
+    pub fn and(exprs: Vec<Expr>) -> Self {
+        let mut exprs: Vec<_> = exprs
+            .into_iter()
+            .filter(|e| !matches!(e, Expr::True))
+            .collect();
+
+        match exprs.len() {
+            0 => Expr::True,
+            1 => exprs.remove(0),
+            _ => Expr::And(exprs),
+        }
+    }
+
+    /// Recompiles every path match with the path options
+    pub fn with_path_opts(self, opts: PathOpts) -> Result<Self> {
+        if opts.is_exact() {
+            return Ok(self);
+        }
+
+        let all = |exprs: Vec<Expr>| -> Result<Vec<Expr>> {
+            exprs.into_iter().map(|e| e.with_path_opts(opts)).collect()
+        };
+
+        Ok(match self {
+            Expr::And(exprs) => Expr::And(all(exprs)?),
+            Expr::Or(exprs) => Expr::Or(all(exprs)?),
+            Expr::Not(e) => Expr::Not(Box::new(e.with_path_opts(opts)?)),
+            Expr::Path(m) => Expr::Path(opts.apply(m)?),
+            Expr::Field { field, m } => Expr::Field {
+                field,
+                m: opts.apply(m)?,
+            },
+            e => e,
+        })
+    }
+
+    /// If the record matches the expression
+    #[cfg(test)]
+    pub fn eval(&self, rec: &Record) -> bool {
+        self.eval_path(rec, &rec.path)
+    }
+
+    /// If the record matches the expression when its path is replaced by `path` (the normalized
+    /// path)
+    pub fn eval_path(&self, rec: &Record, path: &str) -> bool {
+        match self {
+            Expr::True => true,
+            Expr::And(exprs) => exprs.iter().all(|e| e.eval_path(rec, path)),
+            Expr::Or(exprs) => exprs.iter().any(|e| e.eval_path(rec, path)),
+            Expr::Not(e) => !e.eval_path(rec, path),
+            Expr::Path(m) => m.is_match(path),
+            Expr::Field { field, m } => field.get(path).is_some_and(|v| m.is_match(v)),
+            Expr::Flags { mask, all } => {
+                let found = rec.flag & mask;
+                if *all { found == *mask } else { found > 0 }
+            }
+            Expr::Num { field, op, value } => field.get(rec).is_some_and(|v| op.test(v.cmp(value))),
+            Expr::NumRange { field, lo, hi } => {
+                field.get(rec).is_some_and(|v| (*lo..=*hi).contains(&v))
+            }
+            Expr::IdSet { field, ids } => field.get(rec).is_some_and(|v| ids.contains(&v)),
+            Expr::Time { op, value } => rec.file_timestamp.is_some_and(|ts| op.test(ts.cmp(value))),
+            Expr::TimeRange { lo, hi } => rec
+                .file_timestamp
+                .is_some_and(|ts| (*lo..=*hi).contains(&ts)),
+        }
+    }
+}
+
+/// The ways a path can be matched
+#[derive(Clone, Debug)]
+pub enum PathMatch {
+    Regex(Regex),
+    /// A glob converted to a regex; globs without a `/` only match the file name
+    Glob {
+        rex: Regex,
+        name_only: bool,
+    },
+    Prefix(String),
+    Suffix(String),
+    Contains(String),
+    Eq(String),
+}
+
+impl PathMatch {
+    pub fn is_match(&self, path: &str) -> bool {
+        match self {
+            PathMatch::Regex(rex) => rex.is_match(path),
+            PathMatch::Glob { rex, name_only } => {
+                if *name_only {
+                    rex.is_match(path.rsplit('/').next().unwrap_or(path))
+                } else {
+                    rex.is_match(path)
+                }
+            }
+            PathMatch::Prefix(p) => path.starts_with(p.as_str()),
+            PathMatch::Suffix(s) => path.ends_with(s.as_str()),
+            PathMatch::Contains(s) => path.contains(s.as_str()),
+            PathMatch::Eq(s) => path == s,
+        }
+    }
+
+    /// Compiles a shell style glob (`*`, `**`, `?` and `[...]`)
+    pub fn glob(glob: &str) -> Result<Self> {
+        Ok(PathMatch::Glob {
+            rex: glob_regex(glob)?,
+            name_only: !glob.contains('/'),
+        })
+    }
+}
+
+/// Converts a glob to an anchored regex
+pub fn glob_regex(glob: &str) -> Result<Regex> {
+    let mut rex = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                rex.push_str(".*");
+            }
+            '*' => rex.push_str("[^/]*"),
+            '?' => rex.push_str("[^/]"),
+            '[' => {
+                rex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    rex.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some('\\') => rex.push_str("\\\\"),
+                        Some(c) => rex.push(c),
+                        None => return Err(eyre!("Unclosed '[' in the glob '{glob}'")),
+                    }
+                }
+                rex.push(']');
+            }
+            c => rex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    rex.push('$');
+    Regex::new(&rex).map_err(|e| eyre!("Invalid glob '{glob}': {e}"))
+}
+
+/// The numeric fields that can be compared
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NumField {
+    Event,
+    Node,
+    #[cfg(feature = "extra_id")]
+    Extra,
+    /// How many components the (canonical) path has
+    Depth,
+}
+
+impl NumField {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "event_id" | "id" => Some(NumField::Event),
+            "node_id" | "node" => Some(NumField::Node),
+            #[cfg(feature = "extra_id")]
+            "extra_id" => Some(NumField::Extra),
+            "depth" => Some(NumField::Depth),
+            _ => None,
+        }
+    }
+
+    fn get(self, rec: &Record) -> Option<u64> {
+        match self {
+            NumField::Event => Some(rec.event_id),
+            NumField::Node => rec.node_id,
+            #[cfg(feature = "extra_id")]
+            NumField::Extra => rec.extra_id.map(u64::from),
+            NumField::Depth => Some(derived::depth(rec.key_path()).into()),
+        }
+    }
+}
+
+/// Comparison operators
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    /// If the ordering of `value` compared to the wanted value passes the comparison
+    fn test(self, ord: Ordering) -> bool {
+        match self {
+            CmpOp::Eq => ord == Ordering::Equal,
+            CmpOp::Ne => ord != Ordering::Equal,
+            CmpOp::Lt => ord == Ordering::Less,
+            CmpOp::Le => ord != Ordering::Greater,
+            CmpOp::Gt => ord == Ordering::Greater,
+            CmpOp::Ge => ord != Ordering::Less,
+        }
+    }
+}
+
+/// Parses a timestamp given as RFC 3339, a civil date time (UTC) or just a date (UTC midnight)
+pub fn parse_time(s: &str) -> Result<Timestamp> {
+    if let Ok(ts) = s.parse::<Timestamp>() {
+        return Ok(ts);
+    }
+    if let Ok(dt) = s.parse::<civil::DateTime>() {
+        return Ok(dt.to_zoned(TimeZone::UTC)?.timestamp());
+    }
+    if let Ok(d) = s.parse::<civil::Date>() {
+        return Ok(d.to_zoned(TimeZone::UTC)?.timestamp());
+    }
+
+    Err(eyre!("Invalid timestamp '{s}'"))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Comma,
+    DotDot,
+    Tilde,
+    And,
+    Or,
+    Not,
+    Cmp(CmpOp),
+    Str(String),
+    Num(u64),
+    Word(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::Comma => write!(f, "','"),
+            Token::DotDot => write!(f, "'..'"),
+            Token::Tilde => write!(f, "'~'"),
+            Token::And => write!(f, "'and'"),
+            Token::Or => write!(f, "'or'"),
+            Token::Not => write!(f, "'not'"),
+            Token::Cmp(op) => write!(f, "'{op:?}'"),
+            Token::Str(s) => write!(f, "string \"{s}\""),
+            Token::Num(n) => write!(f, "number {n}"),
+            Token::Word(w) => write!(f, "'{w}'"),
+        }
+    }
+}
+
+fn lex(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        let next_is = |chars: &mut Peekable<Chars<'_>>, want: char| {
+            if chars.peek() == Some(&want) {
+                chars.next();
+                true
+            } else {
+                false
+            }
+        };
+
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' | '~' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    ',' => Token::Comma,
+                    _ => Token::Tilde,
+                });
+            }
+            '.' => {
+                chars.next();
+                if !next_is(&mut chars, '.') {
+                    return Err(eyre!("Expected '..' in the filter expression"));
+                }
+                tokens.push(Token::DotDot);
+            }
+            '&' | '|' => {
+                chars.next();
+                if !next_is(&mut chars, c) {
+                    return Err(eyre!("Expected '{c}{c}' in the filter expression"));
+                }
+                tokens.push(if c == '&' { Token::And } else { Token::Or });
+            }
+            '!' => {
+                chars.next();
+                tokens.push(if next_is(&mut chars, '=') {
+                    Token::Cmp(CmpOp::Ne)
+                } else {
+                    Token::Not
+                });
+            }
+            '=' => {
+                chars.next();
+                next_is(&mut chars, '=');
+                tokens.push(Token::Cmp(CmpOp::Eq));
+            }
+            '<' | '>' => {
+                chars.next();
+                let eq = next_is(&mut chars, '=');
+                tokens.push(Token::Cmp(match (c, eq) {
+                    ('<', false) => CmpOp::Lt,
+                    ('<', true) => CmpOp::Le,
+                    (_, false) => CmpOp::Gt,
+                    (_, true) => CmpOp::Ge,
+                }));
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut val = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(eyre!("Unclosed string in the filter expression")),
+                        Some(q) if q == c => break,
+                        // Only the quote and backslash are escaped so regexes can be written
+                        // without doubling every backslash
+                        Some('\\') => match chars.next() {
+                            Some(e) if e == c || e == '\\' => val.push(e),
+                            Some(e) => {
+                                val.push('\\');
+                                val.push(e);
+                            }
+                            None => {
+                                return Err(eyre!("Unclosed string in the filter expression"));
+                            }
+                        },
+                        Some(ch) => val.push(ch),
+                    }
+                }
+                tokens.push(Token::Str(val));
+            }
+            c if c.is_ascii_digit() => {
+                let mut word = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if !ch.is_ascii_alphanumeric() && ch != '_' {
+                        break;
+                    }
+                    word.push(ch);
+                    chars.next();
+                }
+
+                let num = match word.strip_prefix("0x").or(word.strip_prefix("0X")) {
+                    Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+                    None => word.replace('_', "").parse(),
+                };
+                tokens.push(Token::Num(num.map_err(|_| {
+                    eyre!("Invalid number '{word}' in the filter expression")
+                })?));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if !ch.is_alphanumeric() && ch != '_' {
+                        break;
+                    }
+                    word.push(ch);
+                    chars.next();
+                }
+
+                tokens.push(match word.to_ascii_lowercase().as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+            c => return Err(eyre!("Unexpected '{c}' in the filter expression")),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let tok = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| eyre!("Unexpected end of the filter expression"))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn eat(&mut self, want: &Token) -> bool {
+        if self.peek() == Some(want) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, want: &Token) -> Result<()> {
+        match self.next()? {
+            t if &t == want => Ok(()),
+            t => Err(eyre!("Expected {want} but found {t}")),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut terms = vec![self.and()?];
+        while self.eat(&Token::Or) {
+            terms.push(self.and()?);
+        }
+
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::Or(terms)
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut terms = vec![self.unary()?];
+        while self.eat(&Token::And) {
+            terms.push(self.unary()?);
+        }
+
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::And(terms)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+
+        if self.eat(&Token::LParen) {
+            let expr = self.expr()?;
+            self.expect(&Token::RParen)?;
+            return Ok(expr);
+        }
+
+        self.test()
+    }
+
+    fn test(&mut self) -> Result<Expr> {
+        let field = match self.next()? {
+            Token::Word(w) => w.to_ascii_lowercase(),
+            t => return Err(eyre!("Expected a field name but found {t}")),
+        };
+
+        match field.as_str() {
+            "path" => self.path_test(None),
+            "flags" | "flag" => self.flags_test(),
+            "file_timestamp" | "timestamp" => {
+                if self.eat_word("in") {
+                    let lo = self.time()?;
+                    self.expect(&Token::DotDot)?;
+                    let hi = self.time()?;
+                    Ok(Expr::TimeRange { lo, hi })
+                } else {
+                    let op = self.cmp()?;
+                    Ok(Expr::Time {
+                        op,
+                        value: self.time()?,
+                    })
+                }
+            }
+            name => {
+                if let Some(field) = PathField::from_name(name) {
+                    return self.path_test(Some(field));
+                }
+
+                let field = NumField::from_name(name)
+                    .ok_or_else(|| eyre!("Unknown field '{name}' in the filter expression"))?;
+
+                if self.eat_word("in") {
+                    let lo = self.num()?;
+                    self.expect(&Token::DotDot)?;
+                    let hi = self.num()?;
+                    Ok(Expr::NumRange { field, lo, hi })
+                } else {
+                    let op = self.cmp()?;
+                    Ok(Expr::Num {
+                        field,
+                        op,
+                        value: self.num()?,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Parses the test of the path or a field derived from it
+    fn path_test(&mut self, field: Option<PathField>) -> Result<Expr> {
+        let op = match self.next()? {
+            Token::Tilde => "~".to_string(),
+            Token::Cmp(CmpOp::Eq) => "==".to_string(),
+            Token::Cmp(CmpOp::Ne) => "!=".to_string(),
+            Token::Word(w) => w.to_ascii_lowercase(),
+            t => return Err(eyre!("Expected a path operator but found {t}")),
+        };
+        let val = self.string()?;
+
+        let m = match op.as_str() {
+            "~" | "matches" => PathMatch::Regex(
+                Regex::new(&val).map_err(|e| eyre!("Invalid path regex '{val}': {e}"))?,
+            ),
+            // Fields are a single component so their globs always match the whole value
+            "glob" if field.is_some() => PathMatch::Glob {
+                rex: glob_regex(&val)?,
+                name_only: false,
+            },
+            "glob" => PathMatch::glob(&val)?,
+            "prefix" | "startswith" => PathMatch::Prefix(val),
+            "suffix" | "endswith" => PathMatch::Suffix(val),
+            "contains" => PathMatch::Contains(val),
+            "==" | "!=" => PathMatch::Eq(val),
+            op => return Err(eyre!("Unknown path operator '{op}'")),
+        };
+
+        let expr = match field {
+            None => Expr::Path(m),
+            Some(field) => Expr::Field { field, m },
+        };
+        Ok(if op == "!=" {
+            Expr::Not(Box::new(expr))
+        } else {
+            expr
+        })
+    }
+
+    fn flags_test(&mut self) -> Result<Expr> {
+        let all = match self.next()? {
+            Token::Word(w) if w.eq_ignore_ascii_case("has") || w.eq_ignore_ascii_case("all") => {
+                true
+            }
+            Token::Word(w) if w.eq_ignore_ascii_case("any") => false,
+            t => {
+                return Err(eyre!(
+                    "Expected 'has', 'any' or 'all' after flags but found {t}"
+                ));
+            }
+        };
+
+        let mut mask = 0;
+        loop {
+            let name = match self.next()? {
+                Token::Word(w) | Token::Str(w) => w,
+                t => return Err(eyre!("Expected a flag name but found {t}")),
+            };
+            mask |= flags::flag_id(&name).ok_or_else(|| eyre!("Unknown flag '{name}'"))?;
+
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+
+        Ok(Expr::Flags { mask, all })
+    }
+
+    fn eat_word(&mut self, want: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(want) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn cmp(&mut self) -> Result<CmpOp> {
+        match self.next()? {
+            Token::Cmp(op) => Ok(op),
+            t => Err(eyre!("Expected a comparison but found {t}")),
+        }
+    }
+
+    fn num(&mut self) -> Result<u64> {
+        match self.next()? {
+            Token::Num(n) => Ok(n),
+            t => Err(eyre!("Expected a number but found {t}")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Str(s) => Ok(s),
+            t => Err(eyre!("Expected a quoted string but found {t}")),
+        }
+    }
+
+    fn time(&mut self) -> Result<Timestamp> {
+        parse_time(&self.string()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(expr: &str, rec: &Record) -> bool {
+        Expr::parse(expr).unwrap().eval(rec)
+    }
+
+    const CREATED: u32 = 0x0100_0000;
+    const REMOVED: u32 = 0x0200_0000;
+    const MODIFIED: u32 = 0x1000_0000;
+
+    #[test]
+    fn test_request_example() {
+        let expr =
+            r#"flags has Created and not path ~ "^/private/var/folders" and event_id > 0x1000"#;
+
+        assert!(matches(
+            expr,
+            &Record::at("/Users/a/x")
+                .with_flag(CREATED)
+                .with_event_id(0x2000)
+        ));
+        assert!(!matches(
+            expr,
+            &Record::at("/Users/a/x")
+                .with_flag(REMOVED)
+                .with_event_id(0x2000)
+        ));
+        assert!(!matches(
+            expr,
+            &Record::at("/private/var/folders/x")
+                .with_flag(CREATED)
+                .with_event_id(0x2000)
+        ));
+        assert!(!matches(
+            expr,
+            &Record::at("/Users/a/x")
+                .with_flag(CREATED)
+                .with_event_id(0x1000)
+        ));
+    }
+
+    #[test]
+    fn test_precedence_and_grouping() {
+        let rec = Record::at("/a/b.txt")
+            .with_flag(MODIFIED)
+            .with_event_id(5)
+            .with_node_id(Some(1));
+
+        // and binds tighter than or
+        assert!(matches(
+            "event_id == 1 and event_id == 2 or event_id == 5",
+            &rec
+        ));
+        assert!(!matches(
+            "event_id == 1 and (event_id == 2 or event_id == 5)",
+            &rec
+        ));
+        assert!(matches(
+            "!(event_id == 1) && (node_id == 1 || node_id == 2)",
+            &rec
+        ));
+        assert!(matches("not not event_id = 5", &rec));
+    }
+
+    #[test]
+    fn test_path_matches() {
+        let rec = Record::at("Users/bob/Documents/report.PDF")
+            .with_flag(MODIFIED)
+            .with_event_id(1);
+
+        assert!(matches(r#"path ~ "(?i)\.pdf$""#, &rec));
+        assert!(matches(r#"path prefix "Users/""#, &rec));
+        assert!(matches(r#"path suffix ".PDF""#, &rec));
+        assert!(matches(r#"path contains "/bob/""#, &rec));
+        assert!(matches(r#"path == 'Users/bob/Documents/report.PDF'"#, &rec));
+        assert!(matches(r#"path != "other""#, &rec));
+        assert!(matches(r#"path glob "*.PDF""#, &rec));
+        assert!(matches(r#"path glob "Users/*/Documents/*""#, &rec));
+        assert!(!matches(r#"path glob "Users/*.PDF""#, &rec));
+        assert!(matches(r#"path glob "Users/**.PDF""#, &rec));
+        assert!(matches(r#"path glob "report.[Pp]DF""#, &rec));
+        assert!(!matches(r#"path glob "report.[!P]DF""#, &rec));
+    }
+
+    #[test]
+    fn test_derived_fields() {
+        let rec = Record::at("Users/bob/Documents/report.PDF")
+            .with_flag(MODIFIED)
+            .with_event_id(1);
+
+        assert!(matches(r#"name == "report.PDF""#, &rec));
+        assert!(matches(r#"ext ~ "(?i)^pdf$""#, &rec));
+        assert!(matches(r#"dir glob "Users/*/Documents""#, &rec));
+        assert!(!matches(r#"dir glob "Documents""#, &rec));
+        assert!(matches(r#"basename glob "*.PDF""#, &rec));
+        assert!(matches(r#"home_user == "bob" and user != "alice""#, &rec));
+        assert!(matches("depth == 4 and depth in 2..4", &rec));
+
+        // Missing fields never match (but their negation does)
+        assert!(!matches(r#"volume contains """#, &rec));
+        assert!(matches(r#"volume != "USB""#, &rec));
+
+        let rec = Record::at("Volumes/USB/.hidden")
+            .with_flag(MODIFIED)
+            .with_event_id(1);
+        assert!(matches(r#"volume == "USB" and not ext contains """#, &rec));
+    }
+
+    #[test]
+    fn test_flags() {
+        let rec = Record::at("/a")
+            .with_flag(CREATED | MODIFIED)
+            .with_event_id(1);
+
+        assert!(matches("flags has Created", &rec));
+        assert!(matches("flags has Created, Modified", &rec));
+        assert!(!matches("flags all Created, Removed", &rec));
+        assert!(matches("flags any Created, Removed", &rec));
+        assert!(!matches("flags any Removed", &rec));
+        assert!(matches("FLAGS HAS created", &rec));
+    }
+
+    #[test]
+    fn test_numbers() {
+        let rec = Record::at("/a")
+            .with_flag(MODIFIED)
+            .with_event_id(100)
+            .with_node_id(Some(0x20));
+
+        assert!(matches("event_id >= 100 and event_id <= 100", &rec));
+        assert!(!matches("event_id < 100", &rec));
+        assert!(matches("event_id in 0x10..0x64", &rec));
+        assert!(!matches("event_id in 101..200", &rec));
+        assert!(matches("node_id == 0x20", &rec));
+        assert!(matches("node_id != 1_000", &rec));
+
+        let no_node = Record::at("/a").with_flag(MODIFIED).with_event_id(100);
+        assert!(
+            !matches("node_id > 0", &no_node),
+            "Missing values never match"
+        );
+        assert!(matches("not node_id > 0", &no_node));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let rec = Record::at("/a")
+            .with_flag(MODIFIED)
+            .with_event_id(1)
+            .with_timestamp(parse_time("2024-03-01T12:00:00Z").ok());
+
+        assert!(matches(r#"file_timestamp > "2024-03-01""#, &rec));
+        assert!(matches(r#"file_timestamp < "2024-03-01T13:00:00Z""#, &rec));
+        assert!(matches(r#"timestamp in "2024-02-01".."2024-04-01""#, &rec));
+        assert!(!matches(r#"file_timestamp >= "2024-03-01T12:00:01""#, &rec));
+
+        let no_ts = Record::default();
+        assert!(!matches(r#"file_timestamp > "2000-01-01""#, &no_ts));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for bad in [
+            "",
+            "path",
+            "path ~",
+            "path ~ \"(\"",
+            "path glob \"[a\"",
+            "path like \"x\"",
+            "flags has Bogus",
+            "flags maybe Created",
+            "size > 1",
+            "event_id > \"x\"",
+            "event_id in 1..",
+            "event_id > 0xZZ",
+            "file_timestamp > \"yesterday\"",
+            "(event_id > 1",
+            "event_id > 1)",
+            "event_id > 1 and",
+            "event_id > 1 & event_id < 2",
+            "path == \"unclosed",
+            "event_id # 1",
+        ] {
+            assert!(Expr::parse(bad).is_err(), "Should fail to parse: {bad}");
+        }
+    }
+
+    #[test]
+    fn test_and_combines() {
+        assert!(matches!(Expr::and(vec![]), Expr::True));
+        assert!(matches!(
+            Expr::and(vec![Expr::True, Expr::Flags { mask: 1, all: true }]),
+            Expr::Flags { .. }
+        ));
+        assert!(matches!(
+            Expr::and(vec![
+                Expr::True,
+                Expr::True,
+                Expr::Not(Box::new(Expr::True))
+            ]),
+            Expr::Not(_)
+        ));
+    }
+}
@@ -0,0 +1,303 @@
+//! Bounded memory external merge sorting
+//!
+//! This module sorts an arbitrary number of items using a fixed amount of memory: once the
+//! in-memory buffer is full it's sorted and spilled to a temp file as a "run", and at the end
+//! all of the runs are merged back together in order.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Items that can be spilled to (and read back from) a temp file
+pub trait Spill: Sized {
+    /// Writes the item to the writer
+    fn spill<W: Write>(&self, w: &mut W) -> io::Result<()>;
+
+    /// Reads the next item from the reader, returning `None` at the end of the run
+    fn unspill<R: Read>(r: &mut R) -> io::Result<Option<Self>>;
+}
+
+/// Writes a length prefixed byte string
+pub fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(bytes.len() as u32)?;
+    w.write_all(bytes)
+}
+
+/// Reads a length prefixed byte string
+pub fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads a length prefixed utf8 string
+pub fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes an os string (like a path) as is, without going through utf8
+pub fn write_os_str<W: Write>(w: &mut W, s: &OsStr) -> io::Result<()> {
+    write_bytes(w, s.as_encoded_bytes())
+}
+
+/// Reads an os string written by `write_os_str`
+pub fn read_os_string<R: Read>(r: &mut R) -> io::Result<OsString> {
+    let bytes = read_bytes(r)?;
+    // SAFETY: the runs are only ever read back by the process that wrote them, so the bytes
+    // came from `as_encoded_bytes` on this platform and with this version of Rust
+    Ok(unsafe { OsString::from_encoded_bytes_unchecked(bytes) })
+}
+
+/// Writes an optional u64 as a tag byte followed by the value
+pub fn write_opt_u64<W: Write>(w: &mut W, v: Option<u64>) -> io::Result<()> {
+    match v {
+        None => w.write_u8(0),
+        Some(v) => {
+            w.write_u8(1)?;
+            w.write_u64::<LittleEndian>(v)
+        }
+    }
+}
+
+/// Reads an optional u64 written by `write_opt_u64`
+pub fn read_opt_u64<R: Read>(r: &mut R) -> io::Result<Option<u64>> {
+    Ok(match r.read_u8()? {
+        0 => None,
+        _ => Some(r.read_u64::<LittleEndian>()?),
+    })
+}
+
+/// Sorts items in bounded memory, spilling sorted runs to temp files as needed
+pub struct ExternalSorter<T> {
+    buf: Vec<T>,
+    max_items: usize,
+    tmp_dir: PathBuf,
+    runs: Vec<File>,
+}
+
+impl<T> ExternalSorter<T>
+where
+    T: Ord + Spill,
+{
+    /// Creates a new sorter
+    ///
+    /// # Arguments
+    /// * `max_items` - How many items to keep in memory before spilling a run
+    /// * `tmp_dir` - Where the run files should be created
+    pub fn new(max_items: usize, tmp_dir: PathBuf) -> Self {
+        let max_items = max_items.max(1);
+        Self {
+            buf: Vec::with_capacity(max_items.min(1 << 16)),
+            max_items,
+            tmp_dir,
+            runs: Vec::new(),
+        }
+    }
+
+    /// How many runs have been spilled to disk so far
+    #[cfg(test)]
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Adds an item, spilling the buffer to disk if it's full
+    pub fn push(&mut self, item: T) -> io::Result<()> {
+        self.buf.push(item);
+        if self.buf.len() >= self.max_items {
+            self.spill_run()?;
+        }
+        Ok(())
+    }
+
+    /// Spills items that are already in order straight to a run (without buffering them)
+    pub fn push_run(&mut self, items: impl IntoIterator<Item = T>) -> io::Result<()> {
+        let run = Self::write_run(&self.tmp_dir, items)?;
+        debug!("Spilled sort run {}", self.runs.len() + 1);
+        self.runs.push(run);
+        Ok(())
+    }
+
+    fn spill_run(&mut self) -> io::Result<()> {
+        self.buf.sort_unstable();
+
+        let run = Self::write_run(&self.tmp_dir, self.buf.drain(..))?;
+        debug!("Spilled sort run {}", self.runs.len() + 1);
+        self.runs.push(run);
+        Ok(())
+    }
+
+    fn write_run(tmp_dir: &Path, items: impl IntoIterator<Item = T>) -> io::Result<File> {
+        let mut w = BufWriter::new(tempfile::tempfile_in(tmp_dir)?);
+        for item in items {
+            item.spill(&mut w)?;
+        }
+
+        let mut f = w.into_inner().map_err(|e| e.into_error())?;
+        f.seek(SeekFrom::Start(0))?;
+        Ok(f)
+    }
+
+    /// Finishes sorting, returning an iterator over every item in order
+    pub fn finish(mut self) -> io::Result<Sorted<T>> {
+        if self.runs.is_empty() {
+            self.buf.sort_unstable();
+            return Ok(Sorted::Memory(self.buf.into_iter()));
+        }
+
+        if !self.buf.is_empty() {
+            self.spill_run()?;
+        }
+        info!("Merging {} sorted runs", self.runs.len());
+
+        let mut readers: Vec<_> = self.runs.into_iter().map(BufReader::new).collect();
+        let mut heap = BinaryHeap::with_capacity(readers.len());
+        for (idx, r) in readers.iter_mut().enumerate() {
+            if let Some(item) = T::unspill(r)? {
+                heap.push(Reverse((item, idx)));
+            }
+        }
+
+        Ok(Sorted::Merge { readers, heap })
+    }
+}
+
+/// Iterator over the sorted items
+pub enum Sorted<T> {
+    Memory(std::vec::IntoIter<T>),
+    Merge {
+        readers: Vec<BufReader<File>>,
+        heap: BinaryHeap<Reverse<(T, usize)>>,
+    },
+}
+
+impl<T> Iterator for Sorted<T>
+where
+    T: Ord + Spill,
+{
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Sorted::Memory(items) => items.next().map(Ok),
+            Sorted::Merge { readers, heap } => {
+                let Reverse((item, idx)) = heap.pop()?;
+                match T::unspill(&mut readers[idx]) {
+                    Ok(Some(next)) => heap.push(Reverse((next, idx))),
+                    Ok(None) => {}
+                    Err(e) => return Some(Err(e)),
+                }
+                Some(Ok(item))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+    struct Item(u64, String);
+
+    fn read_start<R: Read>(r: &mut R) -> io::Result<bool> {
+        match r.read_u8() {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    impl Spill for Item {
+        fn spill<W: Write>(&self, w: &mut W) -> io::Result<()> {
+            w.write_u8(1)?;
+            w.write_u64::<LittleEndian>(self.0)?;
+            write_bytes(w, self.1.as_bytes())
+        }
+
+        fn unspill<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+            if !read_start(r)? {
+                return Ok(None);
+            }
+            Ok(Some(Item(r.read_u64::<LittleEndian>()?, read_string(r)?)))
+        }
+    }
+
+    fn sort_all(max: usize, items: Vec<Item>) -> (usize, Vec<Item>) {
+        let mut sorter = ExternalSorter::new(max, std::env::temp_dir());
+        for i in items {
+            sorter.push(i).unwrap();
+        }
+        let runs = sorter.runs();
+        (runs, sorter.finish().unwrap().map(|i| i.unwrap()).collect())
+    }
+
+    #[test]
+    fn test_sort_in_memory() {
+        let (runs, sorted) = sort_all(
+            100,
+            vec![
+                Item(3, "c".into()),
+                Item(1, "a".into()),
+                Item(2, "b".into()),
+            ],
+        );
+
+        assert_eq!(runs, 0);
+        assert_eq!(
+            sorted,
+            vec![
+                Item(1, "a".into()),
+                Item(2, "b".into()),
+                Item(3, "c".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_spilled_runs() {
+        let items: Vec<_> = (0..1000u64)
+            .map(|i| Item((i * 7919) % 1000, format!("p{i}")))
+            .collect();
+
+        let (runs, sorted) = sort_all(64, items);
+
+        assert!(runs > 10, "Should have spilled several runs");
+        assert_eq!(sorted.len(), 1000);
+        assert!(sorted.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_sort_pushed_runs() {
+        let mut sorter = ExternalSorter::new(100, std::env::temp_dir());
+        sorter
+            .push_run(vec![Item(1, "a".into()), Item(4, "d".into())])
+            .unwrap();
+        sorter
+            .push_run(vec![Item(2, "b".into()), Item(5, "e".into())])
+            .unwrap();
+        sorter.push(Item(3, "c".into())).unwrap();
+        assert_eq!(sorter.runs(), 2);
+
+        let sorted: Vec<_> = sorter.finish().unwrap().map(|i| i.unwrap().0).collect();
+        assert_eq!(sorted, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_opt_u64_round_trip() {
+        let mut buf = vec![];
+        write_opt_u64(&mut buf, Some(42)).unwrap();
+        write_opt_u64(&mut buf, None).unwrap();
+
+        let mut r = buf.as_slice();
+        assert_eq!(read_opt_u64(&mut r).unwrap(), Some(42));
+        assert_eq!(read_opt_u64(&mut r).unwrap(), None);
+    }
+}
@@ -6,7 +6,7 @@
 use std::{
     fs::File,
     io::{BufReader, ErrorKind, prelude::*},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
@@ -14,13 +14,96 @@ use bus::Bus;
 use byteorder::{LittleEndian, ReadBytesExt};
 use color_eyre::{Result, eyre::eyre};
 use flate2::read::MultiGzDecoder;
+use hashbrown::HashMap;
 use jiff::Timestamp;
 
 use crate::{
-    record::{Record, RecordFilter},
+    record::{EventIdBounds, Record, RecordFilter},
     version,
 };
 
+/// The event id an fsevents file is named after (the id following its last event)
+pub fn file_event_id(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    if name.is_empty() || name.len() > 16 {
+        return None;
+    }
+    u64::from_str_radix(name, 16).ok()
+}
+
+/// Drops the files that can't hold any event within the event id bounds
+///
+/// A file's events fall between the name of the previous file in the same dir and its own
+/// name; files that aren't named after an event id are always kept
+pub fn skip_by_event_id(files: Vec<PathBuf>, bounds: &EventIdBounds) -> Vec<PathBuf> {
+    if !bounds.is_set() {
+        return files;
+    }
+
+    // The sorted file ids in each dir so the previous file of any file can be found
+    let mut dir_ids: HashMap<Option<PathBuf>, Vec<u64>> = HashMap::new();
+    for f in files.iter() {
+        if let Some(id) = file_event_id(f) {
+            dir_ids
+                .entry(f.parent().map(Path::to_path_buf))
+                .or_default()
+                .push(id);
+        }
+    }
+    dir_ids.values_mut().for_each(|ids| ids.sort_unstable());
+
+    let total = files.len();
+    let wanted: Vec<_> = files
+        .into_iter()
+        .filter(|f| {
+            let Some(hi) = file_event_id(f) else {
+                return true;
+            };
+
+            let ids = &dir_ids[&f.parent().map(Path::to_path_buf)];
+            let pos = ids.partition_point(|id| *id < hi);
+            let lo = pos.checked_sub(1).map(|p| ids[p]);
+
+            let want = bounds.may_match(lo, hi);
+            if !want {
+                debug!("Skipping {} due to the event id filters", f.display());
+            }
+            want
+        })
+        .collect();
+
+    if wanted.len() < total {
+        info!(
+            "Skipped {} of {total} files that can't match the event id filters",
+            total - wanted.len()
+        );
+    }
+    wanted
+}
+
+/// Orders the files of each dir by the event id they're named after
+///
+/// The dirs are kept in the order they first show up and the files that aren't named after an
+/// event id keep their order (before the named ones), so files listed out of order are still
+/// replayed in event id order within each volume's dir
+pub fn order_by_event_id(files: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut dirs: Vec<(Option<PathBuf>, Vec<PathBuf>)> = Vec::new();
+    for f in files {
+        let dir = f.parent().map(Path::to_path_buf);
+        match dirs.iter_mut().find(|(d, _)| *d == dir) {
+            Some((_, dir_files)) => dir_files.push(f),
+            None => dirs.push((dir, vec![f])),
+        }
+    }
+
+    dirs.into_iter()
+        .flat_map(|(_, mut dir_files)| {
+            dir_files.sort_by_key(|f| file_event_id(f));
+            dir_files
+        })
+        .collect()
+}
+
 /// Parses an FSEvents file and broadcasts records through the provided bus
 ///
 /// The file is automatically decompressed using gzip if needed.
@@ -49,6 +132,8 @@ pub fn parse_file(in_file: &Path, bus: &mut Bus<Arc<Record>>, filter: &RecordFil
         .and_then(|m| m.modified().ok())
         .and_then(|st| Timestamp::try_from(st).ok());
 
+    let source: Arc<Path> = Arc::from(in_file);
+
     let mut reader = BufReader::new(MultiGzDecoder::new(File::open(in_file)?));
 
     loop {
@@ -91,15 +176,16 @@ pub fn parse_file(in_file: &Path, bus: &mut Bus<Arc<Record>>, filter: &RecordFil
                 }
             };
 
-            // Set the file timestamp on the record
+            // Set the file timestamp and source on the record
             rec.file_timestamp = file_timestamp;
+            rec.source = Some(source.clone());
 
             // Check length before filtering to avoid reading past page boundary
             if read >= p_len {
                 if read == p_len {
                     debug!("Wanted len");
                     // Still broadcast if filter accepts it
-                    if filter.want(&rec) {
+                    if filter.process(&mut rec) {
                         bus.broadcast(Arc::new(rec));
                     } else {
                         debug!("Skipping {rec:?} due to the filters");
@@ -110,7 +196,7 @@ pub fn parse_file(in_file: &Path, bus: &mut Bus<Arc<Record>>, filter: &RecordFil
                 }
             }
 
-            if !filter.want(&rec) {
+            if !filter.process(&mut rec) {
                 debug!("Skipping {rec:?} due to the filters");
                 continue;
             }
@@ -124,13 +210,16 @@ pub fn parse_file(in_file: &Path, bus: &mut Bus<Arc<Record>>, filter: &RecordFil
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
 
     use bus::Bus;
 
-    use crate::record::RecordFilter;
+    use crate::record::{EventIdBounds, RecordFilter};
 
-    use super::parse_file;
+    use super::{file_event_id, order_by_event_id, parse_file, skip_by_event_id};
 
     #[test]
     fn test_v3() {
@@ -145,6 +234,113 @@ mod tests {
         assert_eq!(count, 2730);
     }
 
+    #[test]
+    fn test_v3_event_ids_below_file_name() {
+        let mut bus = Bus::new(4096);
+        let mut recv = bus.add_rx();
+
+        let path: PathBuf = "testfiles/v3/000000000342c4f2".into();
+        parse_file(&path, &mut bus, &RecordFilter::default()).unwrap();
+        drop(bus);
+
+        // Each file is named after the event id following the last event it holds
+        let ids: Vec<_> = recv.iter().map(|r| r.event_id).collect();
+        assert_eq!(ids.iter().max(), Some(&0x342_c4f1));
+        assert!(ids.iter().all(|id| *id > 0x340_0000));
+    }
+
+    #[test]
+    fn test_file_event_id() {
+        assert_eq!(
+            file_event_id(Path::new("/fse/000000000342c4f2")),
+            Some(0x342_c4f2)
+        );
+        assert_eq!(file_event_id(Path::new("test_1.gz")), None);
+        assert_eq!(file_event_id(Path::new("0000000000000000ff")), None);
+    }
+
+    #[test]
+    fn test_skip_by_event_id() {
+        let files: Vec<PathBuf> = [
+            "a/0000000000000100",
+            "a/0000000000000200",
+            "a/0000000000000300",
+            "b/0000000000000150",
+            "other.gz",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        let keep = |bounds: EventIdBounds| {
+            skip_by_event_id(files.clone(), &bounds)
+                .into_iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        // Events 0x180-0x1a0 can only be in the file covering 0x100-0x200 (and the unnamed one)
+        assert_eq!(
+            keep(EventIdBounds {
+                min: Some(0x180),
+                max: Some(0x1a0),
+                ids: None,
+            }),
+            vec!["a/0000000000000200", "other.gz"]
+        );
+
+        // The first file of a dir could start anywhere
+        assert_eq!(
+            keep(EventIdBounds {
+                min: Some(0x120),
+                max: Some(0x130),
+                ids: None,
+            }),
+            vec!["a/0000000000000200", "b/0000000000000150", "other.gz"]
+        );
+
+        assert_eq!(
+            keep(EventIdBounds {
+                ids: Some(Arc::new([0x250].into())),
+                ..EventIdBounds::default()
+            }),
+            vec!["a/0000000000000300", "other.gz"]
+        );
+
+        assert_eq!(keep(EventIdBounds::default()).len(), 5);
+    }
+
+    #[test]
+    fn test_order_by_event_id() {
+        let files: Vec<PathBuf> = [
+            "a/0000000000000300",
+            "b/0000000000000150",
+            "a/0000000000000100",
+            "other.gz",
+            "a/0000000000000200",
+            "b/0000000000000050",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        let ordered: Vec<_> = order_by_event_id(files)
+            .into_iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            ordered,
+            vec![
+                "a/0000000000000100",
+                "a/0000000000000200",
+                "a/0000000000000300",
+                "b/0000000000000050",
+                "b/0000000000000150",
+                "other.gz",
+            ]
+        );
+    }
+
     #[test]
     fn test_v3_with_path_filter() {
         let mut bus = Bus::new(4096);
@@ -95,6 +95,18 @@ pub fn flag_id(want: &str) -> Option<u32> {
     })
 }
 
+/// Iterates over the bits that are set, lowest first
+pub fn iter_bits(mut bits: u32) -> impl Iterator<Item = u32> {
+    std::iter::from_fn(move || {
+        if bits == 0 {
+            return None;
+        }
+        let bit = bits & bits.wrapping_neg();
+        bits &= !bit;
+        Some(bit)
+    })
+}
+
 /// Container for both normal and alternative flag string representations
 #[derive(Clone, Copy, Debug, Default)]
 pub struct FlagStrs {
@@ -234,6 +246,16 @@ mod tests {
         assert_eq!(flag_id("MoDiFiEd"), Some(0x1000_0000));
     }
 
+    #[test]
+    fn test_iter_bits() {
+        assert_eq!(iter_bits(0).count(), 0);
+        assert_eq!(
+            iter_bits(0x1000_0000 | 0x0100_0000 | 0x1).collect::<Vec<_>>(),
+            vec![0x1, 0x0100_0000, 0x1000_0000]
+        );
+        assert_eq!(iter_bits(u32::MAX).count(), 32);
+    }
+
     #[test]
     fn test_flag_id_all_flags() {
         for (name, expected_id) in FLAGS.iter() {
@@ -0,0 +1,184 @@
+//! Activity histograms
+//!
+//! The records are counted per time bucket (the hour or day of their file's timestamp, or the
+//! file itself) and per flag so the activity over the log window can be charted. A record with
+//! several flags counts once for each of them.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use bus::BusReader;
+
+use crate::{
+    flags,
+    opts::{CompressOpts, CsvOpts, Format, HistogramOpts},
+    output::RecordWriter,
+    record::Record,
+    route,
+};
+
+/// What the records are grouped by over time
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum Bucket {
+    /// The hour of the file timestamp (`2024-03-01T12:00:00Z`)
+    #[default]
+    Hour,
+    /// The day of the file timestamp (`2024-03-01`)
+    Day,
+    /// The fsevents file the record came from
+    File,
+}
+
+impl Bucket {
+    /// The bucket the record is in (empty if it doesn't have a timestamp or source file)
+    pub fn key(self, rec: &Record) -> String {
+        match self {
+            Bucket::Hour => rec
+                .file_timestamp
+                .map(|ts| ts.strftime("%Y-%m-%dT%H:00:00Z").to_string())
+                .unwrap_or_default(),
+            Bucket::Day => rec
+                .file_timestamp
+                .map(|ts| ts.strftime("%Y-%m-%d").to_string())
+                .unwrap_or_default(),
+            Bucket::File => rec
+                .source
+                .as_ref()
+                .map(|src| src.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// How many records in a bucket had a flag
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct HistogramRow {
+    pub bucket: String,
+    pub flag: &'static str,
+    pub count: u64,
+}
+
+/// Counts the records per bucket and flag bit
+#[derive(Debug)]
+pub struct Histogram {
+    bucket: Bucket,
+    counts: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+impl Histogram {
+    pub fn new(bucket: Bucket) -> Self {
+        Self {
+            bucket,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Counts the record's flags in its bucket
+    pub fn update(&mut self, rec: &Record) {
+        let counts = self.counts.entry(self.bucket.key(rec)).or_default();
+        for bit in flags::iter_bits(rec.flag) {
+            *counts.entry(bit).or_default() += 1;
+        }
+    }
+
+    /// The rows ordered by bucket and then flag bit
+    pub fn rows(self) -> impl Iterator<Item = HistogramRow> {
+        self.counts.into_iter().flat_map(|(bucket, counts)| {
+            counts.into_iter().map(move |(bit, count)| HistogramRow {
+                bucket: bucket.clone(),
+                flag: flags::parse_bits(bit).norm,
+                count,
+            })
+        })
+    }
+}
+
+/// Counts the records and writes the histogram once they're all read
+pub fn histogram_write(
+    recv: BusReader<Arc<Record>>,
+    opts: HistogramOpts,
+    copts: CompressOpts,
+    csv_opts: CsvOpts,
+) {
+    let Some(path) = opts.histogram else {
+        return;
+    };
+    let writer = match copts.make_output(&path) {
+        Ok(w) => w,
+        Err(err) => {
+            error!(
+                "Couldn't create the histogram output {}: {err}",
+                path.display()
+            );
+            return;
+        }
+    };
+
+    let format = route::infer_format(&path).unwrap_or(Format::Csv);
+    let mut writer = RecordWriter::new(format, writer, &csv_opts, false);
+    let mut histogram = Histogram::new(opts.histogram_bucket);
+    for rec in recv {
+        histogram.update(&rec);
+    }
+
+    for row in histogram.rows() {
+        if let Err(err) = writer.write(&row) {
+            error!("Couldn't write the histogram: {err}");
+        }
+    }
+    if let Err(err) = writer.flush() {
+        error!("Couldn't flush the histogram: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_key() {
+        let rec = Record::default()
+            .with_flags(&["Created"])
+            .with_timestamp("2024-03-01T12:34:56Z".parse().ok())
+            .with_source("logs/0001");
+        assert_eq!(Bucket::Hour.key(&rec), "2024-03-01T12:00:00Z");
+        assert_eq!(Bucket::Day.key(&rec), "2024-03-01");
+        assert_eq!(Bucket::File.key(&rec), "logs/0001");
+
+        let no_ts = Record::default();
+        assert_eq!(Bucket::Hour.key(&no_ts), "");
+        assert_eq!(Bucket::File.key(&no_ts), "");
+    }
+
+    #[test]
+    fn test_histogram_rows() {
+        let mut histogram = Histogram::new(Bucket::Hour);
+        for (flag_names, time) in [
+            (&["Created", "Modified"][..], "2024-03-01T13:05:00Z"),
+            (&["Modified"][..], "2024-03-01T12:59:59Z"),
+            (&["Modified"][..], "2024-03-01T12:00:00Z"),
+            (&["Removed"][..], "2024-03-01T13:30:00Z"),
+        ] {
+            histogram.update(
+                &Record::default()
+                    .with_flags(flag_names)
+                    .with_timestamp(time.parse().ok())
+                    .with_source("a"),
+            );
+        }
+
+        let row = |bucket: &str, flag, count| HistogramRow {
+            bucket: bucket.to_string(),
+            flag,
+            count,
+        };
+        assert_eq!(
+            histogram.rows().collect::<Vec<_>>(),
+            vec![
+                row("2024-03-01T12:00:00Z", "Modified", 2),
+                row("2024-03-01T13:00:00Z", "Created", 1),
+                row("2024-03-01T13:00:00Z", "Removed", 1),
+                row("2024-03-01T13:00:00Z", "Modified", 1),
+            ]
+        );
+    }
+}
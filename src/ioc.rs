@@ -0,0 +1,399 @@
+//! Indicator of compromise (IOC) path matching
+//!
+//! IOC files are csv files with an `id,type,value` header where the type is one of `path`
+//! (a literal full path), `name` (a literal file name), `ext` (a file extension), `glob` or
+//! `regex`. Literals are matched with hash lookups and the patterns with a `RegexSet` so very
+//! large indicator lists stay cheap to check against every record.
+//!
+//! Record paths are relative to the volume root, so the leading `/` of the paths and globs is
+//! dropped and a regex anchored with `^/` is matched as if it were anchored with `^`.
+
+use std::{borrow::Cow, io::Read, path::Path, sync::Arc};
+
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
+use hashbrown::HashMap;
+use regex::{RegexSet, RegexSetBuilder};
+
+use crate::{expr, normalize::PathOpts};
+
+/// The kinds of indicators
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IocKind {
+    Path,
+    #[serde(alias = "basename")]
+    Name,
+    #[serde(alias = "extension")]
+    Ext,
+    Glob,
+    #[serde(alias = "re")]
+    Regex,
+}
+
+#[derive(Debug, Deserialize)]
+struct IocRow {
+    id: String,
+    #[serde(rename = "type")]
+    kind: IocKind,
+    value: String,
+}
+
+/// A compiled set of indicators
+#[derive(Debug, Default)]
+pub struct IocSet {
+    /// How the paths are matched (the literals are stored folded the same way)
+    path_opts: PathOpts,
+    ids: Vec<Arc<str>>,
+    paths: HashMap<String, Vec<usize>>,
+    names: HashMap<String, Vec<usize>>,
+    exts: HashMap<String, Vec<usize>>,
+    path_set: Option<RegexSet>,
+    path_set_ids: Vec<usize>,
+    name_set: Option<RegexSet>,
+    name_set_ids: Vec<usize>,
+}
+
+/// Collects the indicators before compiling the pattern sets
+#[derive(Debug, Default)]
+pub struct IocSetBuilder {
+    set: IocSet,
+    id_map: HashMap<Arc<str>, usize>,
+    path_pats: Vec<String>,
+    name_pats: Vec<String>,
+}
+
+impl IocSetBuilder {
+    /// Creates a builder whose indicators are matched with the path options
+    pub fn new(path_opts: PathOpts) -> Self {
+        Self {
+            set: IocSet {
+                path_opts,
+                ..IocSet::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// Adds a single indicator
+    pub fn add(&mut self, id: &str, kind: IocKind, value: &str) -> Result<()> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(eyre!("The indicator '{id}' has no value"));
+        }
+
+        let idx = match self.id_map.get(id) {
+            Some(idx) => *idx,
+            None => {
+                let id: Arc<str> = Arc::from(id);
+                self.set.ids.push(id.clone());
+                self.id_map.insert(id, self.set.ids.len() - 1);
+                self.set.ids.len() - 1
+            }
+        };
+
+        let opts = self.set.path_opts;
+        match kind {
+            IocKind::Path => push(
+                &mut self.set.paths,
+                fold(&opts, norm_path(value)).into_owned(),
+                idx,
+            ),
+            IocKind::Name => push(&mut self.set.names, fold(&opts, value).into_owned(), idx),
+            IocKind::Ext => push(
+                &mut self.set.exts,
+                fold(&opts, value.trim_start_matches('.')).to_ascii_lowercase(),
+                idx,
+            ),
+            IocKind::Glob => {
+                let glob = norm_path(value);
+                let rex = expr::glob_regex(glob)?.as_str().to_string();
+                if glob.contains('/') {
+                    self.path_pats.push(rex);
+                    self.set.path_set_ids.push(idx);
+                } else {
+                    self.name_pats.push(rex);
+                    self.set.name_set_ids.push(idx);
+                }
+            }
+            IocKind::Regex => {
+                let rex = norm_regex(value);
+                regex::Regex::new(&rex)
+                    .map_err(|e| eyre!("Invalid regex for the indicator '{id}': {e}"))?;
+                self.path_pats.push(rex.into_owned());
+                self.set.path_set_ids.push(idx);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds every indicator from a csv reader
+    pub fn add_reader<R: Read>(&mut self, r: R) -> Result<()> {
+        let mut reader = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .from_reader(r);
+
+        for (line, row) in reader.deserialize::<IocRow>().enumerate() {
+            let row = row.wrap_err_with(|| format!("Invalid indicator on row {}", line + 1))?;
+            self.add(&row.id, row.kind, &row.value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds every indicator from an IOC file
+    pub fn add_file(&mut self, path: &Path) -> Result<()> {
+        let f = std::fs::File::open(path)
+            .wrap_err_with(|| format!("Couldn't open the IOC file {}", path.display()))?;
+        self.add_reader(f)
+            .wrap_err_with(|| format!("Invalid IOC file {}", path.display()))
+    }
+
+    /// Compiles the pattern sets
+    pub fn build(mut self) -> Result<IocSet> {
+        let opts = self.set.path_opts;
+        let compile = |pats: &[String]| {
+            RegexSetBuilder::new(pats.iter().map(|p| opts.normalize(p)))
+                .case_insensitive(opts.ignore_case)
+                .build()
+        };
+
+        if !self.path_pats.is_empty() {
+            self.set.path_set = Some(compile(&self.path_pats)?);
+        }
+        if !self.name_pats.is_empty() {
+            self.set.name_set = Some(compile(&self.name_pats)?);
+        }
+
+        Ok(self.set)
+    }
+}
+
+#[inline]
+fn push(map: &mut HashMap<String, Vec<usize>>, key: String, idx: usize) {
+    let ids = map.entry(key).or_default();
+    if !ids.contains(&idx) {
+        ids.push(idx);
+    }
+}
+
+/// Folds a literal the way the paths are matched (normalized and lowercased if case-insensitive)
+fn fold<'a>(opts: &PathOpts, s: &'a str) -> Cow<'a, str> {
+    let s = opts.normalize(s);
+    if opts.ignore_case {
+        Cow::Owned(s.to_lowercase())
+    } else {
+        s
+    }
+}
+
+/// Record paths are relative to the volume root while indicators usually start with a `/`
+#[inline]
+fn norm_path(path: &str) -> &str {
+    path.trim_start_matches('/')
+}
+
+/// Anchors a regex written for absolute paths (`^/...`) to the relative record paths instead
+fn norm_regex(rex: &str) -> Cow<'_, str> {
+    match rex.strip_prefix("^/").or_else(|| rex.strip_prefix("^\\/")) {
+        Some(rest) => Cow::Owned(format!("^{rest}")),
+        None => Cow::Borrowed(rex),
+    }
+}
+
+impl IocSet {
+    /// Loads and compiles the indicators from every IOC file
+    ///
+    /// The indicators are matched with the same path options as the other path filters
+    pub fn load(files: &[impl AsRef<Path>], path_opts: PathOpts) -> Result<Self> {
+        let mut builder = IocSetBuilder::new(path_opts);
+        for f in files {
+            builder.add_file(f.as_ref())?;
+        }
+
+        let set = builder.build()?;
+        info!("Loaded {} indicators", set.ids.len());
+        Ok(set)
+    }
+
+    /// The ids of every indicator the path matches (in the order they were loaded)
+    ///
+    /// The path must already be normalized with the set's path options
+    pub fn matches(&self, path: &str) -> Vec<Arc<str>> {
+        let path = norm_path(path);
+        let name = path.rsplit('/').next().unwrap_or(path);
+
+        let mut found = vec![];
+        if self.path_opts.ignore_case {
+            let folded = path.to_lowercase();
+            let folded_name = folded.rsplit('/').next().unwrap_or(&folded);
+            if let Some(ids) = self.paths.get(folded.as_str()) {
+                found.extend_from_slice(ids);
+            }
+            if let Some(ids) = self.names.get(folded_name) {
+                found.extend_from_slice(ids);
+            }
+        } else {
+            if let Some(ids) = self.paths.get(path) {
+                found.extend_from_slice(ids);
+            }
+            if let Some(ids) = self.names.get(name) {
+                found.extend_from_slice(ids);
+            }
+        }
+        if let Some((stem, ext)) = name.rsplit_once('.')
+            && !stem.is_empty()
+            && let Some(ids) = self.exts.get(ext.to_ascii_lowercase().as_str())
+        {
+            found.extend_from_slice(ids);
+        }
+        if let Some(set) = &self.path_set {
+            found.extend(set.matches(path).iter().map(|i| self.path_set_ids[i]));
+        }
+        if let Some(set) = &self.name_set {
+            found.extend(set.matches(name).iter().map(|i| self.name_set_ids[i]));
+        }
+
+        found.sort_unstable();
+        found.dedup();
+        found.into_iter().map(|i| self.ids[i].clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalize::NormForm;
+
+    fn make_set(csv: &str) -> IocSet {
+        let mut builder = IocSetBuilder::default();
+        builder.add_reader(csv.as_bytes()).unwrap();
+        builder.build().unwrap()
+    }
+
+    fn ids(set: &IocSet, path: &str) -> Vec<String> {
+        set.matches(path).iter().map(|s| s.to_string()).collect()
+    }
+
+    const IOCS: &str = r#"id,type,value
+# a comment
+plist, path, /Library/LaunchDaemons/com.evil.plist
+dylib, name, libevil.dylib
+cmd, ext, .command
+tmp-sh, glob, /private/tmp/**.sh
+dot-sh, glob, .*.sh
+hidden, regex, ^Users/[^/]+/\.[^/]+/payload$
+multi, name, payload
+multi, ext, COMMAND
+"#;
+
+    #[test]
+    fn test_literal_matches() {
+        let set = make_set(IOCS);
+
+        assert_eq!(
+            ids(&set, "Library/LaunchDaemons/com.evil.plist"),
+            vec!["plist"]
+        );
+        assert_eq!(
+            ids(&set, "/Library/LaunchDaemons/com.evil.plist"),
+            vec!["plist"]
+        );
+        assert_eq!(ids(&set, "usr/local/lib/libevil.dylib"), vec!["dylib"]);
+        assert_eq!(
+            ids(&set, "Users/a/Desktop/run.Command"),
+            vec!["cmd", "multi"]
+        );
+        assert!(
+            ids(&set, "Users/a/.command").is_empty(),
+            "Dotfiles have no extension"
+        );
+        assert!(ids(&set, "Library/LaunchDaemons/com.good.plist").is_empty());
+    }
+
+    #[test]
+    fn test_pattern_matches() {
+        let set = make_set(IOCS);
+
+        assert_eq!(ids(&set, "private/tmp/x/y/run.sh"), vec!["tmp-sh"]);
+        assert_eq!(ids(&set, "Users/a/.hidden.sh"), vec!["dot-sh"]);
+        assert_eq!(ids(&set, "Users/a/.cache/payload"), vec!["hidden", "multi"]);
+        assert!(ids(&set, "Users/a/cache/run.sh").is_empty());
+    }
+
+    #[test]
+    fn test_absolute_regex() {
+        let set = make_set(
+            "id,type,value\n\
+             agent,regex,^/Library/LaunchAgents/.*\\.plist$\n\
+             escaped,regex,^\\/private/tmp/[^/]+$\n",
+        );
+
+        assert_eq!(
+            ids(&set, "Library/LaunchAgents/com.evil.plist"),
+            vec!["agent"]
+        );
+        assert_eq!(
+            ids(&set, "/Library/LaunchAgents/com.evil.plist"),
+            vec!["agent"]
+        );
+        assert!(ids(&set, "Users/a/Library/LaunchAgents/com.evil.plist").is_empty());
+        assert_eq!(ids(&set, "private/tmp/x"), vec!["escaped"]);
+    }
+
+    #[test]
+    fn test_many_indicators() {
+        let mut csv = String::from("id,type,value\n");
+        for i in 0..2000 {
+            csv.push_str(&format!("p{i},path,/Users/a/file{i}.txt\n"));
+            csv.push_str(&format!("g{i},glob,dir{i}/**\n"));
+        }
+        let set = make_set(&csv);
+
+        assert_eq!(ids(&set, "Users/a/file1234.txt"), vec!["p1234"]);
+        assert_eq!(ids(&set, "dir17/x/y"), vec!["g17"]);
+        assert!(ids(&set, "Users/a/file2000.txt").is_empty());
+    }
+
+    #[test]
+    fn test_ignore_case_and_normalize() {
+        let csv = "id,type,value\n\
+                   plist,path,/library/launchdaemons/com.evil.plist\n\
+                   name,name,Jose\u{301}.txt\n\
+                   glob,glob,/private/tmp/**.SH\n\
+                   rex,regex,^users/[^/]+/evil$\n";
+        let opts = PathOpts {
+            ignore_case: true,
+            normalize: Some(NormForm::Nfc),
+        };
+        let mut builder = IocSetBuilder::new(opts);
+        builder.add_reader(csv.as_bytes()).unwrap();
+        let set = builder.build().unwrap();
+        let ids = |path: &str| ids(&set, &opts.normalize(path));
+
+        assert_eq!(ids("Library/LaunchDaemons/com.Evil.plist"), vec!["plist"]);
+        assert_eq!(ids("Users/a/JOS\u{c9}.txt"), vec!["name"]);
+        assert_eq!(ids("Private/tmp/x/run.sh"), vec!["glob"]);
+        assert_eq!(ids("Users/Alice/EVIL"), vec!["rex"]);
+
+        // Without the options the case still has to match
+        assert!(make_set(csv).matches("Users/Alice/EVIL").is_empty());
+    }
+
+    #[test]
+    fn test_invalid_iocs() {
+        let bad = |csv: &str| {
+            let mut builder = IocSetBuilder::default();
+            builder.add_reader(csv.as_bytes()).is_err()
+        };
+
+        assert!(bad("id,type,value\na,sha256,abc\n"));
+        assert!(bad("id,type,value\na,regex,(\n"));
+        assert!(bad("id,type,value\na,path,\n"));
+        assert!(bad("id,type,value\na,path\n"));
+    }
+}
@@ -0,0 +1,369 @@
+//! File lifecycles (created -> modified -> renamed -> removed)
+//!
+//! The records are replayed in event id order and every file is followed by its node id (so
+//! renames stay part of the same lifecycle) or by its path when the records don't have node ids.
+//! Files that were created and then removed within the logs are the interesting ones (staging
+//! areas, droppers) so only those complete lifecycles are written unless all of them are wanted.
+
+use std::{fmt::Write as _, sync::Arc};
+
+use bus::BusReader;
+use hashbrown::HashMap;
+#[cfg(feature = "hex")]
+use serde_hex::{CompactCapPfx, SerHex, SerHexOpt};
+
+use crate::{
+    flags,
+    opts::{CompressOpts, CsvOpts, Format, LifecycleOpts},
+    output::RecordWriter,
+    record::Record,
+    replay, route,
+};
+
+/// The steps of a lifecycle (the order they're applied in when a record has several)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Step {
+    Created,
+    Modified,
+    Renamed,
+    Removed,
+}
+
+impl Step {
+    const ALL: [Step; 4] = [Step::Created, Step::Modified, Step::Renamed, Step::Removed];
+
+    fn name(self) -> &'static str {
+        match self {
+            Step::Created => "created",
+            Step::Modified => "modified",
+            Step::Renamed => "renamed",
+            Step::Removed => "removed",
+        }
+    }
+
+    fn flag(self) -> &'static str {
+        match self {
+            Step::Created => "Created",
+            Step::Modified => "Modified",
+            Step::Renamed => "Renamed",
+            Step::Removed => "Removed",
+        }
+    }
+}
+
+/// How a file is followed
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Key {
+    Node(u64),
+    Path(String),
+}
+
+/// A lifecycle being followed
+#[derive(Debug)]
+struct Tracked {
+    original_path: String,
+    path: String,
+    node_id: Option<u64>,
+    first_event_id: u64,
+    created_event_id: Option<u64>,
+    events: u64,
+    /// The steps with how many times each repeated in a row
+    steps: Vec<(Step, u64)>,
+}
+
+impl Tracked {
+    fn new(rec: &Record) -> Self {
+        Self {
+            original_path: rec.key_path().to_string(),
+            path: rec.key_path().to_string(),
+            node_id: rec.node_id,
+            first_event_id: rec.event_id,
+            created_event_id: None,
+            events: 0,
+            steps: vec![],
+        }
+    }
+
+    fn push(&mut self, step: Step) {
+        match self.steps.last_mut() {
+            Some((last, n)) if *last == step => *n += 1,
+            _ => self.steps.push((step, 1)),
+        }
+    }
+
+    fn finish(self, removed_event_id: Option<u64>) -> Lifecycle {
+        let mut sequence = String::new();
+        for (step, n) in self.steps.iter() {
+            if !sequence.is_empty() {
+                sequence.push_str(" > ");
+            }
+            sequence.push_str(step.name());
+            if *n > 1 {
+                let _ = write!(sequence, " x{n}");
+            }
+        }
+
+        let complete = self.created_event_id.is_some() && removed_event_id.is_some();
+        Lifecycle {
+            path: self.path,
+            original_path: self.original_path,
+            node_id: self.node_id,
+            first_event_id: self.first_event_id,
+            created_event_id: self.created_event_id,
+            removed_event_id,
+            span: self
+                .created_event_id
+                .zip(removed_event_id)
+                .map(|(c, r)| r - c),
+            events: self.events,
+            sequence,
+            complete,
+        }
+    }
+}
+
+/// A written lifecycle
+#[derive(Clone, Debug, Serialize)]
+pub struct Lifecycle {
+    /// The last path of the file
+    pub path: String,
+    /// The first path of the file (differs from `path` if it was renamed)
+    pub original_path: String,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub node_id: Option<u64>,
+    #[cfg_attr(feature = "hex", serde(with = "SerHex::<CompactCapPfx>"))]
+    pub first_event_id: u64,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub created_event_id: Option<u64>,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub removed_event_id: Option<u64>,
+    /// The event ids between the creation and the removal
+    pub span: Option<u64>,
+    /// How many records the lifecycle has
+    pub events: u64,
+    /// The steps in order, repeats are collapsed (`created > modified x3 > removed`)
+    pub sequence: String,
+    /// If the file was both created and removed
+    pub complete: bool,
+}
+
+/// Follows the lifecycle of every file in the records (given in event id order)
+#[derive(Debug)]
+pub struct LifecycleTracker {
+    /// If the lifecycles that weren't created and removed within the logs are wanted too
+    all: bool,
+    masks: [u32; 4],
+    tracked: HashMap<Key, Tracked>,
+}
+
+impl LifecycleTracker {
+    pub fn new(all: bool) -> Self {
+        Self {
+            all,
+            masks: Step::ALL.map(|s| flags::flag_id(s.flag()).expect("Known flag")),
+            tracked: HashMap::new(),
+        }
+    }
+
+    /// Adds the next record, returning the lifecycle it ended (if it removed the file)
+    pub fn push(&mut self, rec: &Record) -> Option<Lifecycle> {
+        let steps: Vec<_> = Step::ALL
+            .iter()
+            .zip(self.masks)
+            .filter(|(_, mask)| rec.flag & mask != 0)
+            .map(|(step, _)| *step)
+            .collect();
+        if steps.is_empty() {
+            return None;
+        }
+
+        let key = match rec.node_id {
+            Some(node) => Key::Node(node),
+            None => Key::Path(rec.key_path().to_string()),
+        };
+
+        // Files first seen after their creation can't have a complete lifecycle
+        if !self.all && !self.tracked.contains_key(&key) && steps[0] != Step::Created {
+            return None;
+        }
+
+        let tracked = self
+            .tracked
+            .entry(key.clone())
+            .or_insert_with(|| Tracked::new(rec));
+        tracked.events += 1;
+        tracked.path = rec.key_path().to_string();
+
+        for step in steps {
+            if step == Step::Created && tracked.created_event_id.is_none() {
+                tracked.created_event_id = Some(rec.event_id);
+            }
+            tracked.push(step);
+
+            if step == Step::Removed {
+                let tracked = self.tracked.remove(&key).expect("Tracked");
+                let lifecycle = tracked.finish(Some(rec.event_id));
+                return (self.all || lifecycle.complete).then_some(lifecycle);
+            }
+        }
+
+        None
+    }
+
+    /// Ends the lifecycles of the files that weren't removed (only returned if all of them are
+    /// wanted), in the order they were first seen
+    pub fn finish(&mut self) -> Vec<Lifecycle> {
+        if !self.all {
+            self.tracked.clear();
+            return vec![];
+        }
+
+        let mut left: Vec<_> = self.tracked.drain().map(|(_, t)| t).collect();
+        left.sort_by_key(|t| t.first_event_id);
+        left.into_iter().map(|t| t.finish(None)).collect()
+    }
+}
+
+/// Writes the lifecycles of the files from a bus receiver
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving record updates
+/// * `opts` - The lifecycle options (with the output path)
+/// * `copts` - The compression options used to open the output
+/// * `csv_opts` - The csv dialect to use
+pub fn lifecycle_write(
+    recv: BusReader<Arc<Record>>,
+    opts: LifecycleOpts,
+    copts: CompressOpts,
+    csv_opts: CsvOpts,
+) {
+    let Some(path) = opts.lifecycles else {
+        return;
+    };
+    let writer = match copts.make_output(&path) {
+        Ok(w) => w,
+        Err(err) => {
+            error!(
+                "Couldn't create the lifecycles output {}: {err}",
+                path.display()
+            );
+            return;
+        }
+    };
+
+    let format = route::infer_format(&path).unwrap_or(Format::Json);
+    let mut writer = RecordWriter::new(format, writer, &csv_opts, false);
+    let mut tracker = LifecycleTracker::new(opts.lifecycles_all);
+    let mut count = 0u64;
+
+    let mut write = |lifecycle: Lifecycle| {
+        count += 1;
+        if let Err(err) = writer.write(&lifecycle) {
+            error!("Couldn't write the lifecycle: {err}");
+        }
+    };
+
+    replay::replay(recv, None, |rec| {
+        if let Some(lifecycle) = tracker.push(rec) {
+            write(lifecycle);
+        }
+    });
+    tracker.finish().into_iter().for_each(&mut write);
+
+    if let Err(err) = writer.flush() {
+        error!("Couldn't flush the lifecycles: {err}");
+    }
+    info!("Wrote {count} lifecycles");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_lifecycles() {
+        let mut t = LifecycleTracker::new(false);
+
+        assert!(
+            t.push(&Record::event("tmp/a", 10, &["Created"]).with_node_id(Some(1)))
+                .is_none()
+        );
+        assert!(
+            t.push(&Record::event("tmp/a", 11, &["Modified"]).with_node_id(Some(1)))
+                .is_none()
+        );
+        assert!(
+            t.push(&Record::event("tmp/a", 12, &["Modified"]).with_node_id(Some(1)))
+                .is_none()
+        );
+        assert!(
+            t.push(&Record::event("tmp/a", 13, &["Renamed"]).with_node_id(Some(1)))
+                .is_none()
+        );
+        assert!(
+            t.push(&Record::event("tmp/b", 14, &["Renamed"]).with_node_id(Some(1)))
+                .is_none()
+        );
+        // Never created within the logs
+        assert!(
+            t.push(&Record::event("tmp/old", 15, &["Removed"]).with_node_id(Some(2)))
+                .is_none()
+        );
+
+        let l = t
+            .push(&Record::event("tmp/b", 20, &["Removed"]).with_node_id(Some(1)))
+            .unwrap();
+        assert_eq!(
+            (l.path.as_str(), l.original_path.as_str()),
+            ("tmp/b", "tmp/a")
+        );
+        assert_eq!(
+            (l.created_event_id, l.removed_event_id, l.span),
+            (Some(10), Some(20), Some(10))
+        );
+        assert_eq!(l.sequence, "created > modified x2 > renamed x2 > removed");
+        assert_eq!(l.events, 6);
+        assert!(l.complete);
+
+        // Coalesced records and path keyed lifecycles
+        let l = t
+            .push(&Record::event(
+                "tmp/c",
+                30,
+                &["Created", "Modified", "Removed"],
+            ))
+            .unwrap();
+        assert_eq!(
+            (l.span, l.sequence.as_str()),
+            (Some(0), "created > modified > removed")
+        );
+
+        // A removed file starts over if it shows up again
+        assert!(
+            t.push(&Record::event("tmp/b", 40, &["Modified"]).with_node_id(Some(1)))
+                .is_none()
+        );
+        assert!(t.finish().is_empty());
+    }
+
+    #[test]
+    fn test_all_lifecycles() {
+        let mut t = LifecycleTracker::new(true);
+
+        let l = t.push(&Record::event("a", 1, &["Removed"])).unwrap();
+        assert!(!l.complete);
+        assert_eq!((l.created_event_id, l.span), (None, None));
+
+        assert!(t.push(&Record::event("c", 3, &["Created"])).is_none());
+        assert!(t.push(&Record::event("b", 2, &["Modified"])).is_none());
+        let left = t.finish();
+        assert_eq!(
+            left.iter().map(|l| l.path.as_str()).collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+        assert!(
+            left.iter()
+                .all(|l| !l.complete && l.removed_event_id.is_none())
+        );
+    }
+}
@@ -9,7 +9,6 @@ extern crate log;
 extern crate serde_derive;
 
 use std::{
-    collections::BTreeMap,
     convert::identity,
     fs::File,
     io::{self, BufWriter, Write},
@@ -29,14 +28,50 @@ use color_eyre::Result;
 use csv::Writer;
 use env_logger::{Target, WriteStyle};
 use log::LevelFilter;
-use opts::{Commands, Generate};
+use opts::{Commands, Format, Generate};
 
 use crate::record::Record;
 
+/// Logs a summary at the level, or prints it to stderr if the logger was quieted (because the
+/// records are written to stdout) so it's never lost
+macro_rules! summary {
+    ($lvl:expr, $($arg:tt)+) => {
+        if log_enabled!($lvl) {
+            log!($lvl, $($arg)+);
+        } else {
+            eprintln!($($arg)+);
+        }
+    };
+}
+
+mod alert;
+mod artifact;
+mod burst;
+mod canonical;
+#[cfg(feature = "watch")]
+mod checkpoint;
+mod derived;
+mod expr;
+mod extsort;
 mod file_parser;
 mod flags;
+mod histogram;
+mod ioc;
+mod lifecycle;
+mod noise;
+mod normalize;
 mod opts;
+mod output;
 mod record;
+mod replay;
+#[cfg(feature = "watch")]
+mod rotate;
+mod route;
+mod sort;
+mod split;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod tree;
 mod uniques;
 mod version;
 
@@ -47,10 +82,11 @@ static GLOBAL: MiMalloc = MiMalloc;
 
 fn main() -> Result<()> {
     match opts::get_opts()?.command {
-        Commands::Dump(d) => dump(d),
+        Commands::Dump(d) => dump(*d),
         Commands::Generate(g) => generate(g),
+        Commands::Tree(t) => tree(*t),
         #[cfg(feature = "watch")]
-        Commands::Watch(w) => watch(w),
+        Commands::Watch(w) => watch(*w),
     }
 }
 
@@ -140,70 +176,6 @@ where
     }
 }
 
-/// Aggregates records by path and writes unique path counts with combined flags
-///
-/// # Arguments
-/// * `recv` - Bus reader receiving record updates
-/// * `writer` - CSV writer for unique path output
-/// * `_` - Unused pretty print flag (kept for API consistency)
-/// * `include_timestamps` - Whether to include timestamps in CSV output
-fn write_uniqs<I>(
-    recv: BusReader<Arc<Record>>,
-    mut writer: Writer<I>,
-    _: bool,
-    include_timestamps: bool,
-) where
-    I: Write,
-{
-    let mut u = BTreeMap::new();
-
-    for rec in recv {
-        u.entry(rec.path.clone())
-            .or_insert_with(uniques::UniqueCounts::default)
-            .update(rec.flag, rec.file_timestamp);
-    }
-
-    if include_timestamps {
-        // Use full serialization with timestamps
-        for (path, v) in u {
-            if let Err(err) = writer.serialize(v.into_unique_out(path)) {
-                error!("Error writing the uniques: {err}");
-            }
-        }
-    } else {
-        // Manually write CSV without timestamps
-        // Write header
-        #[cfg(feature = "alt_flags")]
-        let header = vec!["path", "counts", "flags", "alt_flags"];
-        #[cfg(not(feature = "alt_flags"))]
-        let header = vec!["path", "counts", "flags"];
-
-        if let Err(err) = writer.write_record(&header) {
-            error!("Error writing CSV header: {err}");
-            return;
-        }
-
-        // Write data rows
-        for (path, v) in u {
-            let out = v.into_unique_out_no_timestamps(path);
-            let counts_str = out.counts.to_string();
-            #[cfg(feature = "alt_flags")]
-            let record = vec![
-                out.path.as_str(),
-                counts_str.as_str(),
-                out.flags,
-                out.alt_flags,
-            ];
-            #[cfg(not(feature = "alt_flags"))]
-            let record = vec![out.path.as_str(), counts_str.as_str(), out.flags];
-
-            if let Err(err) = writer.write_record(&record) {
-                error!("Error writing unique record: {err}");
-            }
-        }
-    }
-}
-
 /// Checks if the given path represents stdout (indicated by "-")
 ///
 /// # Arguments
@@ -246,11 +218,18 @@ fn iyaml(rec: Arc<Record>, writer: &mut BufWriter<File>) {
 }
 
 macro_rules! fdump {
-    ( $bus: ident, $scope: ident, $ftype: expr, $path:ident, $proc_f:ident, $c_opt: ident, $creater:expr, ) => {
+    ( $bus: ident, $scope: ident, $ftype: expr, $format: expr, $path:ident, $proc_f:ident, $c_opt: ident, $csv_opt: ident, $split: ident, $creater:expr, ) => {
         if let Some(p) = $path {
             let recv = $bus.add_rx();
 
-            if path_stdout(&p) {
+            if $split.enabled() {
+                $scope.spawn(move |_| {
+                    split::split_write(
+                        recv,
+                        split::SplitWriter::new(p, $format, $c_opt, $csv_opt, $split),
+                    );
+                });
+            } else if path_stdout(&p) {
                 $scope.spawn(move |_| {
                     $proc_f(recv, $creater($c_opt.make_stdout()), false, false);
                 });
@@ -335,7 +314,9 @@ fn new_bus() -> Bus<Arc<Record>> {
 }
 
 fn dump(opts: opts::Dump) -> Result<()> {
-    let std_counts = opts.stdout_counts();
+    let routes = opts.route_opts.routes(opts.filter_opts.path_opts())?;
+    let alerts = opts.alert_opts.output(opts.filter_opts.path_opts())?;
+    let std_counts = opts.stdout_counts() + routes.iter().filter(|r| r.is_stdout()).count();
     env_logger::Builder::new()
         .filter(
             None,
@@ -352,10 +333,19 @@ fn dump(opts: opts::Dump) -> Result<()> {
     color_eyre::install()?;
 
     opts.validate(std_counts)?;
-    let file_paths = opts.real_files();
+
+    let rec_filter = opts
+        .filter_opts
+        .filter()?
+        .with_time_window(opts.since, opts.until);
+    let file_paths = file_parser::skip_by_event_id(opts.real_files(), &rec_filter.event_bounds);
 
     info!("Starting");
 
+    let burst_opts = opts.burst_opts.clone();
+    let lifecycle_opts = opts.lifecycle_opts.clone();
+    let histogram_opts = opts.histogram_opts.clone();
+    let uniques_out = opts.uniques_output();
     let opts::Dump {
         csvs: individual_csvs,
         jsons: individual_jsons,
@@ -363,85 +353,96 @@ fn dump(opts: opts::Dump) -> Result<()> {
         csv: csv_path,
         json: json_path,
         yaml: yaml_path,
-        uniques: uniq_path,
-        unique_timestamps,
         ..
     } = opts;
 
-    let rec_filter = opts.filter_opts.filter()?;
-
     let copts = opts.compress_opts;
+    let csv_opts = opts.csv_opts;
+    let split_opts = opts.split_opts;
+    let sort_opts = opts.sort_opts;
 
     crossbeam::scope(|scope| {
         let mut bus = new_bus();
 
+        // When sorting, the combined outputs read from a second bus that the sorter broadcasts
+        // to once every file has been parsed
+        let mut sorted_bus = sort_opts.sort.map(|_| new_bus());
+        let out_bus = sorted_bus.as_mut().unwrap_or(&mut bus);
+
         fdump!(
-            bus,
+            out_bus,
             scope,
             "csv",
+            Format::Csv,
             csv_path,
             csv_write,
             copts,
-            csv::Writer::from_writer,
+            csv_opts,
+            split_opts,
+            |w| csv_opts.writer(w),
         );
 
-        // Handle uniques output with timestamp flag
-        if let Some(p) = uniq_path {
+        if let Some(out) = uniques_out {
+            let recv = out_bus.add_rx();
+            scope.spawn(move |_| uniques::uniques_write(recv, out, copts, csv_opts));
+        }
+
+        fdump!(
+            out_bus,
+            scope,
+            "json",
+            Format::Json,
+            json_path,
+            json_write,
+            copts,
+            csv_opts,
+            split_opts,
+            identity,
+        );
+        fdump!(
+            out_bus,
+            scope,
+            "yaml",
+            Format::Yaml,
+            yaml_path,
+            yaml_write,
+            copts,
+            csv_opts,
+            split_opts,
+            identity,
+        );
+
+        for route in routes {
+            let recv = out_bus.add_rx();
+            scope.spawn(move |_| route::route_write(recv, route, copts, csv_opts, false));
+        }
+
+        // The alerts, bursts and lifecycles read the records in parse order so they can be
+        // replayed file by file
+        if let Some(alerts) = alerts {
             let recv = bus.add_rx();
+            scope.spawn(move |_| alert::alert_write(recv, alerts, copts, csv_opts, false));
+        }
 
-            if path_stdout(&p) {
-                scope.spawn(move |_| {
-                    write_uniqs(
-                        recv,
-                        csv::Writer::from_writer(copts.make_stdout()),
-                        false,
-                        unique_timestamps,
-                    );
-                });
-            } else {
-                match File::create(&p) {
-                    Err(err) => error!(
-                        "Couldn't create unique csv output file {}: {err}",
-                        p.display()
-                    ),
-                    Ok(f) => {
-                        scope.spawn(move |_| {
-                            if copts.is_gz(&p) {
-                                write_uniqs(
-                                    recv,
-                                    csv::Writer::from_writer(copts.make_gzip(BufWriter::new(f))),
-                                    false,
-                                    unique_timestamps,
-                                );
-                            } else if copts.is_zstd(&p) {
-                                #[cfg(feature = "zstd")]
-                                {
-                                    write_uniqs(
-                                        recv,
-                                        csv::Writer::from_writer(copts.make_zstd(f)),
-                                        false,
-                                        unique_timestamps,
-                                    );
-                                }
+        if burst_opts.bursts.is_some() {
+            let recv = bus.add_rx();
+            scope.spawn(move |_| burst::burst_write(recv, burst_opts, copts, csv_opts));
+        }
 
-                                #[cfg(not(feature = "zstd"))]
-                                unreachable!("zstd feature not enabled");
-                            } else {
-                                write_uniqs(
-                                    recv,
-                                    csv::Writer::from_writer(BufWriter::new(f)),
-                                    false,
-                                    unique_timestamps,
-                                );
-                            };
-                        });
-                    }
-                }
-            }
+        if lifecycle_opts.lifecycles.is_some() {
+            let recv = bus.add_rx();
+            scope.spawn(move |_| lifecycle::lifecycle_write(recv, lifecycle_opts, copts, csv_opts));
         }
 
-        fdump!(bus, scope, "json", json_path, json_write, copts, identity,);
-        fdump!(bus, scope, "yaml", yaml_path, yaml_write, copts, identity,);
+        if histogram_opts.histogram.is_some() {
+            let recv = bus.add_rx();
+            scope.spawn(move |_| histogram::histogram_write(recv, histogram_opts, copts, csv_opts));
+        }
+
+        if let Some(sorted_bus) = sorted_bus {
+            let recv = bus.add_rx();
+            scope.spawn(move |_| sort::sort_write(recv, sort_opts, sorted_bus));
+        }
 
         for f in file_paths {
             let running = Arc::new(AtomicBool::new(true));
@@ -454,7 +455,7 @@ fn dump(opts: opts::Dump) -> Result<()> {
                     running,
                     "csv",
                     f,
-                    Writer::from_writer,
+                    |w| csv_opts.writer(w),
                     icsv,
                 );
 
@@ -492,9 +493,69 @@ fn dump(opts: opts::Dump) -> Result<()> {
     })
     .expect("Couldn't close all the threads");
 
+    rec_filter.log_exclusions();
+
     Ok(())
 }
 
+fn tree(opts: opts::Tree) -> Result<()> {
+    env_logger::Builder::new()
+        .filter(
+            None,
+            if path_stdout(&opts.output) {
+                LevelFilter::Error
+            } else {
+                LevelFilter::Info
+            },
+        )
+        .write_style(WriteStyle::Always)
+        .target(Target::Stderr)
+        .init();
+
+    color_eyre::install()?;
+
+    opts.validate()?;
+
+    let rec_filter = opts
+        .filter_opts
+        .filter()?
+        .with_time_window(opts.since, opts.until);
+    let file_paths = file_parser::skip_by_event_id(opts.real_files(), &rec_filter.event_bounds);
+
+    // Open the output first so a bad path doesn't waste the parsing
+    let copts = opts.compress_opts;
+    let writer = copts.make_output(&opts.output)?;
+
+    info!("Starting");
+
+    let mut dir_tree = crossbeam::scope(|scope| {
+        let mut bus = new_bus();
+        let recv = bus.add_rx();
+        let builder = scope.spawn(move |_| {
+            let mut dir_tree = tree::DirTree::default();
+            replay::replay(recv, None, |rec| dir_tree.push(rec));
+            dir_tree.finish();
+            dir_tree
+        });
+
+        for f in file_paths {
+            match file_parser::parse_file(&f, &mut bus, &rec_filter) {
+                Ok(_) => info!("Finished parsing {}", f.display()),
+                Err(e) => error!("Couldn't parse '{}': {}", f.display(), e),
+            };
+        }
+
+        drop(bus);
+        builder.join().expect("Couldn't build the tree")
+    })
+    .expect("Couldn't close all the threads");
+
+    rec_filter.log_exclusions();
+
+    dir_tree.retain(&opts.status);
+    dir_tree.write(opts.format(), writer, &opts.csv_opts)
+}
+
 fn generate(g: Generate) -> Result<()> {
     let mut cmd = opts::Cli::command();
     let name = cmd.get_name().to_string();
@@ -511,6 +572,8 @@ fn watch(opts: opts::Watch) -> Result<()> {
         DebounceEventResult, FileIdMap, new_debouncer_opt, notify::RecursiveMode,
     };
 
+    use color_eyre::eyre::eyre;
+
     use crate::file_parser::parse_file;
 
     env_logger::Builder::new()
@@ -521,9 +584,27 @@ fn watch(opts: opts::Watch) -> Result<()> {
 
     color_eyre::install()?;
 
+    opts.rotate_opts.validate()?;
     let rec_filter = opts.filter_opts.filter()?;
+    let routes = opts.route_opts.routes(opts.filter_opts.path_opts())?;
+    let alerts = opts.alert_opts.output(opts.filter_opts.path_opts())?;
+    let stdout_counts = routes.iter().filter(|r| r.is_stdout()).count()
+        + usize::from(alerts.as_ref().is_some_and(|a| a.is_stdout()));
+    if opts.rotate_opts.output.is_none() && stdout_counts > 0 {
+        return Err(eyre!(
+            "Watch already writes to stdout so routes and alerts can't (unless --output is given)"
+        ));
+    } else if stdout_counts > 1 {
+        return Err(eyre!("Can't have more than one file printing to stdout!"));
+    }
+
+    let mut checkpoint = opts
+        .state_file
+        .map(|path| checkpoint::Checkpoint::load(path, &opts.watch_dirs))
+        .transpose()?;
 
-    let (send, recv) = crossbeam_channel::bounded(128);
+    // Unbounded so the watcher doesn't drop any files while the backfill is parsed
+    let (send, recv) = crossbeam_channel::unbounded();
 
     let debounce_time = Duration::from_secs(2);
 
@@ -592,26 +673,78 @@ fn watch(opts: opts::Watch) -> Result<()> {
     };
 
     let copts = opts.compress_opts;
+    let csv_opts = opts.csv_opts;
+    let rotate_opts = opts.rotate_opts;
+    let template = match rotate_opts.output {
+        Some(_) => Some(rotate_opts.template()?),
+        None => None,
+    };
 
     crossbeam::scope(|fscope| {
         let mut bus = new_bus();
 
         let rec_recv = bus.add_rx();
         fscope.spawn(move |_| {
+            if let Some(template) = template {
+                let writer = rotate::RotatingWriter::new(
+                    template,
+                    opts.format,
+                    opts.pretty,
+                    copts,
+                    csv_opts,
+                    &rotate_opts,
+                );
+                return rotate::rotate_write(rec_recv, writer);
+            }
+
             let out = copts.make_stdout();
 
             match opts.format {
-                opts::WatchFormat::Csv => {
-                    csv_write(rec_recv, csv::Writer::from_writer(out), false, true)
-                }
-                opts::WatchFormat::Json => json_write(rec_recv, out, opts.pretty, true),
-                opts::WatchFormat::Yaml => yaml_write(rec_recv, out, false, true),
+                Format::Csv => csv_write(rec_recv, csv_opts.writer(out), false, true),
+                Format::Json => json_write(rec_recv, out, opts.pretty, true),
+                Format::Yaml => yaml_write(rec_recv, out, false, true),
             }
         });
 
-        for path in recv {
+        for route in routes {
+            let recv = bus.add_rx();
+            fscope.spawn(move |_| route::route_write(recv, route, copts, csv_opts, true));
+        }
+
+        if let Some(alerts) = alerts {
+            let recv = bus.add_rx();
+            fscope.spawn(move |_| alert::alert_write(recv, alerts, copts, csv_opts, true));
+        }
+
+        // The files written while watch was down are parsed before the new ones (the watcher was
+        // already started so nothing is missed in between)
+        let backfill = checkpoint
+            .as_ref()
+            .map(|c| c.backfill())
+            .unwrap_or_default();
+        if !backfill.is_empty() {
+            info!("Backfilling {} files", backfill.len());
+        }
+
+        for path in backfill.into_iter().chain(recv) {
+            if checkpoint.as_ref().is_some_and(|c| !c.is_new(&path)) {
+                debug!("Skipping {} as it was already parsed", path.display());
+                continue;
+            }
+
+            if let Some(id) = file_parser::file_event_id(&path)
+                && !rec_filter.event_bounds.may_match(None, id)
+            {
+                debug!("Skipping {} due to the event id filters", path.display());
+                continue;
+            }
+
             if let Err(err) = parse_file(&path, &mut bus, &rec_filter) {
                 error!("Error parsing {}: {err}", path.display());
+            } else if let Some(checkpoint) = checkpoint.as_mut()
+                && let Err(err) = checkpoint.mark(&path)
+            {
+                error!("Couldn't update the checkpoint: {err}");
             }
         }
     })
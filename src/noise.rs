@@ -0,0 +1,297 @@
+//! Exclusion filters and noise-suppression profiles
+//!
+//! Exclusions are named rules that drop the records they match. They come from the
+//! `--exclude-path`/`--exclude-flags` options and from noise profiles (either built in or loaded
+//! from a yaml file) and each one counts how many records it dropped so it can be reported.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
+use regex::Regex;
+
+use crate::{
+    expr::{Expr, PathMatch},
+    flags,
+    normalize::PathOpts,
+    record::Record,
+};
+
+/// The built-in macOS noise profile
+const MACOS_DEFAULT: &str = r#"
+name: macos-default
+exclude:
+  - name: spotlight
+    path: '(^|/)\.Spotlight-V100(/|$)'
+  - name: fseventsd
+    path: '(^|/)\.fseventsd(/|$)'
+  - name: var-folders
+    path: '^/?private/var/folders/'
+  - name: caches
+    path: '(^|/)Library/Caches(/|$)'
+  - name: time-machine
+    path: '(^|/)(\.MobileBackups|Backups\.backupdb|\.TimeMachine|com\.apple\.TimeMachine\.localsnapshots)(/|$)'
+  - name: document-revisions
+    path: '(^|/)\.DocumentRevisions-V100(/|$)'
+  - name: unified-logs
+    path: '^/?private/var/db/(uuidtext|diagnostics)/'
+"#;
+
+/// The names of the built-in noise profiles
+pub const BUILTIN_PROFILES: &[&str] = &["macos-default"];
+
+/// A named rule that drops every record it matches
+#[derive(Clone, Debug)]
+pub struct Exclusion {
+    pub name: String,
+    pub expr: Expr,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Exclusion {
+    pub fn new(name: String, expr: Expr) -> Self {
+        Self {
+            name,
+            expr,
+            dropped: Arc::default(),
+        }
+    }
+
+    /// Creates an exclusion from a path pattern; a `glob:` prefix makes it a glob instead of a
+    /// regex (an `re:` prefix is also accepted)
+    pub fn path(pat: &str) -> Result<Self> {
+        let m = match pat.strip_prefix("glob:") {
+            Some(glob) => PathMatch::glob(glob)?,
+            None => {
+                let rex = pat.strip_prefix("re:").unwrap_or(pat);
+                PathMatch::Regex(
+                    Regex::new(rex).map_err(|e| eyre!("Invalid exclude path '{rex}': {e}"))?,
+                )
+            }
+        };
+
+        Ok(Self::new(format!("exclude-path {pat}"), Expr::Path(m)))
+    }
+
+    /// Creates an exclusion dropping any record with the flag
+    pub fn flag(flag: &str) -> Result<Self> {
+        let mask = flags::flag_id(flag).ok_or_else(|| eyre!("Unknown exclude flag id: {flag}"))?;
+        Ok(Self::new(
+            format!("exclude-flags {flag}"),
+            Expr::Flags { mask, all: false },
+        ))
+    }
+
+    /// If the record should be dropped (counting it if so)
+    #[cfg(test)]
+    pub fn excludes(&self, rec: &Record) -> bool {
+        self.excludes_path(rec, &rec.path)
+    }
+
+    /// If the record with the (normalized) path should be dropped (counting it if so)
+    #[inline]
+    pub fn excludes_path(&self, rec: &Record, path: &str) -> bool {
+        if self.expr.eval_path(rec, path) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Recompiles the exclusion's path matches with the path options
+    pub fn with_path_opts(self, opts: PathOpts) -> Result<Self> {
+        Ok(Self {
+            expr: self.expr.with_path_opts(opts)?,
+            ..self
+        })
+    }
+
+    /// How many records this exclusion has dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// A noise profile as written in a profile file
+#[derive(Debug, Deserialize)]
+struct Profile {
+    #[serde(default)]
+    name: Option<String>,
+    exclude: Vec<ProfileRule>,
+}
+
+/// A single profile rule; every given test has to match for the record to be dropped
+#[derive(Debug, Deserialize)]
+struct ProfileRule {
+    name: String,
+    /// A path regex
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    glob: Option<String>,
+    /// Matches if any of these flags are present
+    #[serde(default)]
+    flags: Vec<String>,
+    #[serde(default, rename = "where")]
+    where_expr: Option<String>,
+}
+
+impl ProfileRule {
+    fn into_exclusion(self, profile: &str) -> Result<Exclusion> {
+        let name = format!("{profile}/{}", self.name);
+        let mut terms = vec![];
+
+        if let Some(rex) = &self.path {
+            terms.push(Expr::Path(PathMatch::Regex(
+                Regex::new(rex).map_err(|e| eyre!("Invalid path in rule {name}: {e}"))?,
+            )));
+        }
+        if let Some(glob) = &self.glob {
+            terms.push(Expr::Path(PathMatch::glob(glob)?));
+        }
+        if !self.flags.is_empty() {
+            let mut mask = 0;
+            for flag in self.flags.iter() {
+                mask |= flags::flag_id(flag)
+                    .ok_or_else(|| eyre!("Unknown flag '{flag}' in rule {name}"))?;
+            }
+            terms.push(Expr::Flags { mask, all: false });
+        }
+        if let Some(expr) = &self.where_expr {
+            terms.push(Expr::parse(expr).wrap_err_with(|| format!("Invalid rule {name}"))?);
+        }
+
+        if terms.is_empty() {
+            return Err(eyre!("The rule {name} doesn't have anything to match"));
+        }
+
+        Ok(Exclusion::new(name, Expr::and(terms)))
+    }
+}
+
+fn parse_profile(yaml: &str, default_name: &str) -> Result<Vec<Exclusion>> {
+    let profile: Profile = serde_yaml::from_str(yaml)?;
+    let name = profile.name.as_deref().unwrap_or(default_name).to_string();
+
+    profile
+        .exclude
+        .into_iter()
+        .map(|r| r.into_exclusion(&name))
+        .collect()
+}
+
+/// Loads a noise profile by its built-in name or from a yaml file
+pub fn load_profile(profile: &str) -> Result<Vec<Exclusion>> {
+    match profile {
+        "macos-default" => parse_profile(MACOS_DEFAULT, profile),
+        path => {
+            let path = Path::new(path);
+            let yaml = fs::read_to_string(path).wrap_err_with(|| {
+                format!(
+                    "'{}' isn't a built-in noise profile ({}) or a readable file",
+                    path.display(),
+                    BUILTIN_PROFILES.join(", ")
+                )
+            })?;
+
+            let default_name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.display().to_string());
+            parse_profile(&yaml, &default_name)
+                .wrap_err_with(|| format!("Invalid noise profile {}", path.display()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_profile() {
+        let exclusions = load_profile("macos-default").unwrap();
+        let dropped = |path: &str| exclusions.iter().any(|e| e.excludes(&Record::at(path)));
+
+        assert!(dropped(".Spotlight-V100/Store-V2/abc/store.db"));
+        assert!(dropped("private/var/folders/xx/T/tmp.1"));
+        assert!(dropped(
+            "Users/bob/Library/Caches/com.apple.Safari/Cache.db"
+        ));
+        assert!(dropped(".fseventsd/000000000342c4f2"));
+        assert!(dropped("Volumes/Backup/Backups.backupdb/mac/2024"));
+        assert!(!dropped("Users/bob/Documents/report.pdf"));
+        assert!(!dropped("Users/bob/Library/LaunchAgents/evil.plist"));
+    }
+
+    #[test]
+    fn test_exclusion_counts() {
+        let ex = Exclusion::path("glob:*.tmp").unwrap();
+        assert!(ex.excludes(&Record::at("a/b.tmp")));
+        assert!(ex.excludes(&Record::at("c.tmp")));
+        assert!(!ex.excludes(&Record::at("c.txt")));
+
+        // Clones share the count
+        let other = ex.clone();
+        assert!(other.excludes(&Record::at("d.tmp")));
+        assert_eq!(ex.dropped(), 3);
+        assert_eq!(ex.name, "exclude-path glob:*.tmp");
+    }
+
+    #[test]
+    fn test_exclusion_path_and_flags() {
+        assert!(
+            Exclusion::path("re:^tmp/")
+                .unwrap()
+                .excludes(&Record::at("tmp/a"))
+        );
+        assert!(Exclusion::path("(").is_err());
+
+        let ex = Exclusion::flag("ItemCloned").unwrap();
+        assert!(ex.excludes(&Record::at("a").with_flag(0x0040_0000)));
+        assert!(!ex.excludes(&Record::at("a").with_flag(0x0100_0000)));
+        assert!(Exclusion::flag("Bogus").is_err());
+    }
+
+    #[test]
+    fn test_profile_rules_combine() {
+        let exclusions = parse_profile(
+            r#"
+exclude:
+  - name: cloned-tmp
+    glob: '**/tmp/**'
+    flags: [ItemCloned]
+  - name: early
+    where: event_id < 10
+"#,
+            "mine",
+        )
+        .unwrap();
+
+        assert_eq!(exclusions[0].name, "mine/cloned-tmp");
+        assert!(exclusions[0].excludes(&Record::at("a/tmp/b").with_flag(0x0040_0000)));
+        assert!(!exclusions[0].excludes(&Record::at("a/tmp/b").with_flag(0x0100_0000)));
+        assert!(!exclusions[0].excludes(&Record::at("a/b").with_flag(0x0040_0000)));
+
+        let mut rec = Record::at("a");
+        rec.event_id = 5;
+        assert!(exclusions[1].excludes(&rec));
+    }
+
+    #[test]
+    fn test_profile_invalid() {
+        assert!(parse_profile("exclude:\n  - name: empty\n", "p").is_err());
+        assert!(parse_profile("exclude:\n  - name: f\n    flags: [Nope]\n", "p").is_err());
+        assert!(load_profile("/does/not/exist.yaml").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! Case-insensitive and Unicode normalized path matching
+//!
+//! HFS+ stored the paths in (a variant of) NFD while APFS keeps whatever form it was given and is
+//! usually case-insensitive, so the same file can show up with differently encoded paths. Both
+//! the patterns and the record paths are normalized to the same form before matching.
+
+use std::borrow::Cow;
+
+use color_eyre::Result;
+use regex::{Regex, RegexBuilder};
+use unicode_normalization::{IsNormalized, UnicodeNormalization, is_nfc_quick, is_nfd_quick};
+
+use crate::expr::PathMatch;
+
+/// The Unicode normalization forms
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum NormForm {
+    /// Composed (`é` is a single code point)
+    Nfc,
+    /// Decomposed (`é` is an `e` followed by a combining accent)
+    Nfd,
+}
+
+/// How paths should be matched
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PathOpts {
+    pub ignore_case: bool,
+    pub normalize: Option<NormForm>,
+}
+
+impl PathOpts {
+    /// If the paths are matched as they are
+    #[inline]
+    pub fn is_exact(&self) -> bool {
+        !self.ignore_case && self.normalize.is_none()
+    }
+
+    /// Normalizes the string (only allocating if it wasn't already normalized)
+    #[inline]
+    pub fn normalize<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        match self.normalize {
+            None => Cow::Borrowed(s),
+            Some(NormForm::Nfc) => match is_nfc_quick(s.chars()) {
+                IsNormalized::Yes => Cow::Borrowed(s),
+                _ => Cow::Owned(s.nfc().collect()),
+            },
+            Some(NormForm::Nfd) => match is_nfd_quick(s.chars()) {
+                IsNormalized::Yes => Cow::Borrowed(s),
+                _ => Cow::Owned(s.nfd().collect()),
+            },
+        }
+    }
+
+    /// Recompiles a path match so it matches paths normalized with these options
+    pub fn apply(&self, m: PathMatch) -> Result<PathMatch> {
+        if self.is_exact() {
+            return Ok(m);
+        }
+
+        let literal = |s: String, pre: &str, post: &str| -> Result<PathMatch> {
+            Ok(PathMatch::Regex(
+                self.regex(&format!("{pre}{}{post}", regex::escape(&s)))?,
+            ))
+        };
+
+        Ok(match m {
+            PathMatch::Regex(rex) => PathMatch::Regex(self.regex(rex.as_str())?),
+            PathMatch::Glob { rex, name_only } => PathMatch::Glob {
+                rex: self.regex(rex.as_str())?,
+                name_only,
+            },
+            // Case-insensitive literals are turned into regexes since lowercasing every path
+            // would cost more
+            PathMatch::Prefix(s) if self.ignore_case => literal(s, "^", "")?,
+            PathMatch::Suffix(s) if self.ignore_case => literal(s, "", "$")?,
+            PathMatch::Contains(s) if self.ignore_case => literal(s, "", "")?,
+            PathMatch::Eq(s) if self.ignore_case => literal(s, "^", "$")?,
+            PathMatch::Prefix(s) => PathMatch::Prefix(self.normalize(&s).into_owned()),
+            PathMatch::Suffix(s) => PathMatch::Suffix(self.normalize(&s).into_owned()),
+            PathMatch::Contains(s) => PathMatch::Contains(self.normalize(&s).into_owned()),
+            PathMatch::Eq(s) => PathMatch::Eq(self.normalize(&s).into_owned()),
+        })
+    }
+
+    fn regex(&self, rex: &str) -> Result<Regex> {
+        Ok(RegexBuilder::new(&self.normalize(rex))
+            .case_insensitive(self.ignore_case)
+            .build()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NFC: &str = "Users/Jos\u{e9}/Documents";
+    const NFD: &str = "Users/Jose\u{301}/Documents";
+
+    #[test]
+    fn test_normalize() {
+        let nfc = PathOpts {
+            normalize: Some(NormForm::Nfc),
+            ..PathOpts::default()
+        };
+        let nfd = PathOpts {
+            normalize: Some(NormForm::Nfd),
+            ..PathOpts::default()
+        };
+
+        assert_eq!(nfc.normalize(NFD), NFC);
+        assert!(matches!(nfc.normalize(NFC), Cow::Borrowed(_)));
+        assert_eq!(nfd.normalize(NFC), NFD);
+        assert!(matches!(nfd.normalize("ascii/only"), Cow::Borrowed(_)));
+        assert_eq!(PathOpts::default().normalize(NFD), NFD);
+    }
+
+    #[test]
+    fn test_apply() {
+        let opts = PathOpts {
+            ignore_case: true,
+            normalize: Some(NormForm::Nfc),
+        };
+        let path = opts.normalize(NFD);
+
+        for m in [
+            PathMatch::Prefix("users/JOSÉ".into()),
+            PathMatch::Suffix("josé/documents".into()),
+            PathMatch::Contains("DOCUMENTS".into()),
+            PathMatch::Eq(NFD.to_lowercase()),
+            PathMatch::Regex(Regex::new("^users/jose\u{301}/").unwrap()),
+            PathMatch::glob("**/josé/*").unwrap(),
+        ] {
+            let desc = format!("{m:?}");
+            assert!(opts.apply(m).unwrap().is_match(&path), "{desc}");
+        }
+
+        // Normalizing alone stays case sensitive
+        let opts = PathOpts {
+            normalize: Some(NormForm::Nfd),
+            ..PathOpts::default()
+        };
+        assert!(
+            opts.apply(PathMatch::Contains("José".into()))
+                .unwrap()
+                .is_match(NFD)
+        );
+        assert!(
+            !opts
+                .apply(PathMatch::Contains("josé".into()))
+                .unwrap()
+                .is_match(NFD)
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
+    collections::BTreeSet,
     ffi::OsStr,
-    io::{BufWriter, Write},
+    fs::File,
+    io::{self, BufWriter, Write},
     path::PathBuf,
     time::SystemTime,
 };
@@ -8,10 +10,26 @@ use std::{
 use clap::{Args, Parser, Subcommand, value_parser};
 use clap_complete::Shell;
 use color_eyre::{Result, eyre::eyre};
-use jiff::{Span, Zoned};
+use jiff::{Span, Timestamp, Zoned};
 use std::path::Path;
 
-use crate::record::RecordFilter;
+#[cfg(feature = "watch")]
+use crate::rotate::Rotation;
+use crate::{
+    alert::{AlertOutput, Detector},
+    artifact::Classifier,
+    canonical::Canonicalizer,
+    expr, file_parser,
+    histogram::Bucket,
+    ioc::IocSet,
+    noise::{self, Exclusion},
+    normalize::{NormForm, PathOpts},
+    output::{FinishOnDrop, FinishWrite},
+    record::{PathEncoding, RecordFilter},
+    route::{self, Route, RouteSpec},
+    tree::NodeStatus,
+    uniques::{Rollup, UniqueDetail, UniquesOutput},
+};
 
 /// Utility to dump the fsevent files on OSX
 #[derive(Debug, Parser)]
@@ -24,11 +42,14 @@ pub struct Cli {
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Dump fsevents file into the wanted output files/format
-    Dump(Dump),
+    Dump(Box<Dump>),
 
     /// Watch for new fse files, parse them, and write them to the desired output
     #[cfg(feature = "watch")]
-    Watch(Watch),
+    Watch(Box<Watch>),
+
+    /// Replay the records into the directory tree they leave behind and write it out
+    Tree(Box<Tree>),
 
     /// Outputs shell completions for the desired shell
     #[clap(aliases = &["gen"])]
@@ -47,7 +68,7 @@ pub struct Generate {
 pub struct Watch {
     /// The format the parsed files should be output to
     #[arg(short = 'o', long, default_value = "json")]
-    pub format: WatchFormat,
+    pub format: Format,
 
     /// If the outupt should be "pretty" formatted (multi-line)
     #[arg(short = 'P', long)]
@@ -61,18 +82,180 @@ pub struct Watch {
     #[arg(long)]
     pub poll: bool,
 
+    /// Keep a checkpoint of the files parsed in this file so a restarted watch backfills the
+    /// files written while it was down (without parsing any file twice)
+    #[arg(long = "state-file")]
+    pub state_file: Option<PathBuf>,
+
+    /// The rotating output options
+    #[clap(flatten)]
+    pub rotate_opts: RotateOpts,
+
     /// The compression options
     #[clap(flatten)]
     pub compress_opts: CompressOpts,
 
+    /// The csv dialect options
+    #[clap(flatten)]
+    pub csv_opts: CsvOpts,
+
+    /// The output route options
+    #[clap(flatten)]
+    pub route_opts: RouteOpts,
+
+    /// The detection rule options
+    #[clap(flatten)]
+    pub alert_opts: AlertOpts,
+
     /// The filter options
     #[clap(flatten)]
     pub filter_opts: FilterOpts,
 }
 
-#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+/// Options to write watch's records to rotating files instead of stdout
+#[cfg(feature = "watch")]
+#[derive(Clone, Debug, Default, Args)]
+pub struct RotateOpts {
+    /// Write the records to files named from this template instead of stdout
+    ///
+    /// `strftime` specifiers are expanded with the time (in UTC) the file is started, like
+    /// `events-%Y%m%d-%H.json.gz`. A number is inserted before the extension if the name is
+    /// already taken (`events.0001.json.gz`) so earlier files are never overwritten. Compression
+    /// works the same as the dump outputs and each file is compressed on its own
+    #[arg(long = "output")]
+    pub output: Option<PathBuf>,
+
+    /// Start a new file every hour or day (in UTC)
+    #[arg(long = "rotate", value_enum, requires = "output")]
+    pub rotate: Option<Rotation>,
+
+    /// Start a new file once the current one has this many bytes, counted before they're
+    /// compressed (so a compressed file ends up smaller)
+    ///
+    /// Accepts `K`, `M` and `G` suffixes (powers of 1024)
+    #[arg(long = "rotate-size", value_parser = parse_size, requires = "output")]
+    pub rotate_size: Option<u64>,
+
+    /// Only keep this many of the newest files (including the current one), removing the older
+    /// ones this run wrote
+    #[arg(long = "keep", requires = "output")]
+    pub keep: Option<usize>,
+}
+
 #[cfg(feature = "watch")]
-pub enum WatchFormat {
+impl RotateOpts {
+    pub fn validate(&self) -> Result<()> {
+        if self.rotate_size == Some(0) || self.keep == Some(0) {
+            return Err(eyre!(
+                "The rotate size and number of files to keep must be greater than 0"
+            ));
+        }
+
+        if let Some(output) = &self.output {
+            if output.as_os_str() == "-" {
+                return Err(eyre!(
+                    "Watch already writes to stdout when no output is given"
+                ));
+            }
+            crate::rotate::expand_template(&self.template()?, Timestamp::now())?;
+        }
+
+        Ok(())
+    }
+
+    /// The output template as a string (the `strftime` specifiers need it to be UTF-8)
+    pub fn template(&self) -> Result<String> {
+        let Some(output) = &self.output else {
+            return Err(eyre!("No output was given"));
+        };
+        output
+            .to_str()
+            .map(str::to_string)
+            .ok_or_else(|| eyre!("The output template must be valid UTF-8"))
+    }
+}
+
+/// Replays the records into the directory tree they leave behind
+#[derive(Debug, Args)]
+pub struct Tree {
+    /// Where the tree should be written (`-` for stdout)
+    ///
+    /// If the path ends in `.gz` or `.gzip` it will be gzip compressed.
+    /// If it ends in `.zst` or `.zstd` it will be zstd compressed (requires zstd feature).
+    #[arg(short, long, default_value = "-")]
+    pub output: PathBuf,
+
+    /// The format of the tree, defaults to the one matching the output's extension (`.json` or
+    /// `.csv`) and an indented text tree otherwise
+    #[arg(long, value_enum)]
+    pub format: Option<TreeFormat>,
+
+    /// Only write the nodes with these statuses (and the directories leading to them)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub status: Vec<NodeStatus>,
+
+    /// How many days we should pull (based off the file mod time)
+    ///
+    /// Not allowed with `--since`/`--until`, which replace it
+    #[arg(
+        short = 'd',
+        long = "days",
+        default_value = "90",
+        conflicts_with_all = ["since", "until"]
+    )]
+    pub pull_days: u32,
+
+    /// Only pull the files (and records) last modified at or after this time
+    ///
+    /// Either an RFC 3339 timestamp, a date/datetime (UTC), or a span before now like `2w`,
+    /// `6 months` or `P1Y`
+    #[arg(long, value_parser = parse_when)]
+    pub since: Option<Timestamp>,
+
+    /// Only pull the files (and records) last modified at or before this time (same formats as
+    /// `--since`)
+    #[arg(long, value_parser = parse_when)]
+    pub until: Option<Timestamp>,
+
+    /// The fs event files that should be parsed. If any arg is a directory then any file within
+    /// that has a filename consisting solely of hex chars will be considered a file to parse
+    #[arg(default_value = "/System/Volumes/Data/.fseventsd/")]
+    pub files: Vec<PathBuf>,
+
+    /// The compression options
+    #[clap(flatten)]
+    pub compress_opts: CompressOpts,
+
+    /// The csv dialect options
+    #[clap(flatten)]
+    pub csv_opts: CsvOpts,
+
+    /// The filter options
+    #[clap(flatten)]
+    pub filter_opts: FilterOpts,
+}
+
+/// The formats the uniques can be written as
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum UniquesFormat {
+    Csv,
+    Json,
+    Yaml,
+    Sqlite,
+}
+
+/// The formats the directory tree can be written as
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum TreeFormat {
+    Csv,
+    Json,
+    Text,
+}
+
+/// The formats records can be written as
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
     Csv,
     Json,
     Yaml,
@@ -128,7 +311,7 @@ pub struct Dump {
     #[arg(short, long)]
     pub yaml: Option<PathBuf>,
 
-    /// If we should dump the unique paths/operations found into a csv
+    /// If we should dump the unique paths/operations found into a file (csv by default)
     ///
     /// We'll combine all of the operations for each path so there is one entry per path
     ///
@@ -137,14 +320,80 @@ pub struct Dump {
     #[arg(short, long)]
     pub uniques: Option<PathBuf>,
 
+    /// The format of the uniques, defaults to the one matching the extension (`.json`, `.yaml`
+    /// or `.sqlite`/`.db`) and csv otherwise
+    ///
+    /// sqlite needs the sqlite feature and can't be compressed or printed to stdout
+    #[arg(long = "uniques-format", value_enum, requires = "uniques")]
+    pub uniques_format: Option<UniquesFormat>,
+
     /// Include earliest/latest timestamps in unique CSV output
     #[arg(long = "unique-timestamps")]
     pub unique_timestamps: bool,
 
+    /// Add these detail columns to the uniques (comma separated)
+    ///
+    /// `flag-counts` has how many records had each flag, `event-ids` the first and last event
+    /// id, `node-ids` the distinct node ids and `source-files` how many files the path was in
+    #[arg(
+        long = "unique-details",
+        value_enum,
+        value_delimiter = ',',
+        requires = "uniques"
+    )]
+    pub unique_details: Vec<UniqueDetail>,
+
+    /// Roll the uniques up to the directories this many components deep (`Users/me` is 2)
+    ///
+    /// Every row has the combined counts and flags of the paths below it along with how many
+    /// distinct paths that is. Paths that aren't as deep are kept as they are
+    #[arg(
+        long = "uniques-depth",
+        requires = "uniques",
+        conflicts_with = "uniques_by_dir",
+        value_parser = value_parser!(u32).range(1..)
+    )]
+    pub uniques_depth: Option<u32>,
+
+    /// Roll the uniques up to the directory each path is in
+    #[arg(long = "uniques-by-dir", requires = "uniques")]
+    pub uniques_by_dir: bool,
+
+    /// Keep the uniques under about this much memory (e.g. 512M), spilling them to sorted temp
+    /// files in the `--sort-tmp` dir and merging those at the end
+    #[arg(long = "uniques-memory", value_parser = parse_size, requires = "uniques")]
+    pub uniques_memory: Option<u64>,
+
+    /// Aggregate the uniques per time bucket as well as per path, adding a `bucket` column
+    ///
+    /// `hour` and `day` use the timestamp of the file the record was in (records without one are
+    /// in an empty bucket) and `file` the fsevents file itself
+    #[arg(long = "uniques-bucket", value_enum, requires = "uniques")]
+    pub uniques_bucket: Option<Bucket>,
+
     /// How many days we should pull (based off the file mod time)
-    #[arg(short = 'd', long = "days", default_value = "90")]
+    ///
+    /// Not allowed with `--since`/`--until`, which replace it
+    #[arg(
+        short = 'd',
+        long = "days",
+        default_value = "90",
+        conflicts_with_all = ["since", "until"]
+    )]
     pub pull_days: u32,
 
+    /// Only pull the files (and records) last modified at or after this time
+    ///
+    /// Either an RFC 3339 timestamp, a date/datetime (UTC), or a span before now like `2w`,
+    /// `6 months` or `P1Y`
+    #[arg(long, value_parser = parse_when)]
+    pub since: Option<Timestamp>,
+
+    /// Only pull the files (and records) last modified at or before this time (same formats as
+    /// `--since`)
+    #[arg(long, value_parser = parse_when)]
+    pub until: Option<Timestamp>,
+
     /// The fs event files that should be parsed. If any arg is a directory then any file within
     /// that has a filename consisting solely of hex chars will be considered a file to parse
     #[arg(default_value = "/System/Volumes/Data/.fseventsd/")]
@@ -154,11 +403,308 @@ pub struct Dump {
     #[clap(flatten)]
     pub compress_opts: CompressOpts,
 
+    /// The csv dialect options
+    #[clap(flatten)]
+    pub csv_opts: CsvOpts,
+
+    /// The output splitting options
+    #[clap(flatten)]
+    pub split_opts: SplitOpts,
+
+    /// The sorting options
+    #[clap(flatten)]
+    pub sort_opts: SortOpts,
+
+    /// The output route options
+    #[clap(flatten)]
+    pub route_opts: RouteOpts,
+
+    /// The detection rule options
+    #[clap(flatten)]
+    pub alert_opts: AlertOpts,
+
+    /// The burst analysis options
+    #[clap(flatten)]
+    pub burst_opts: BurstOpts,
+
+    /// The file lifecycle options
+    #[clap(flatten)]
+    pub lifecycle_opts: LifecycleOpts,
+
+    /// The activity histogram options
+    #[clap(flatten)]
+    pub histogram_opts: HistogramOpts,
+
     /// The filter options
     #[clap(flatten)]
     pub filter_opts: FilterOpts,
 }
 
+/// Options to split the combined outputs into numbered chunks
+#[derive(Clone, Copy, Debug, Default, Args)]
+pub struct SplitOpts {
+    /// Start a new chunk of the combined outputs after this many records
+    ///
+    /// Each chunk is written next to the requested output with its number inserted before the
+    /// extension (`events.json.gz` -> `events.0001.json.gz`) and an index listing the event id
+    /// range of every chunk is written to `<output>.index.json`
+    #[arg(long = "split-records")]
+    pub split_records: Option<u64>,
+
+    /// Start a new chunk of the combined outputs after this many (uncompressed) bytes, so a
+    /// chunk only goes past it by its last record
+    ///
+    /// Accepts `K`, `M` and `G` suffixes (powers of 1024)
+    #[arg(long = "split-bytes", value_parser = parse_size)]
+    pub split_bytes: Option<u64>,
+
+    /// Start a new chunk of the combined outputs for every N input files
+    #[arg(long = "split-files")]
+    pub split_files: Option<usize>,
+}
+
+impl SplitOpts {
+    /// If any of the split options are set
+    pub fn enabled(&self) -> bool {
+        self.split_records.is_some() || self.split_bytes.is_some() || self.split_files.is_some()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.split_records == Some(0)
+            || self.split_bytes == Some(0)
+            || self.split_files == Some(0)
+        {
+            return Err(eyre!("The split sizes must be greater than 0"));
+        }
+
+        Ok(())
+    }
+}
+
+/// The keys the combined output can be sorted by
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum SortKey {
+    EventId,
+    Path,
+    NodeId,
+}
+
+/// Options to globally sort the combined outputs
+#[derive(Clone, Debug, Args)]
+pub struct SortOpts {
+    /// Sort the combined outputs by this key across all of the input files
+    ///
+    /// The records are sorted with a bounded amount of memory; once `--sort-buffer` records have
+    /// been collected they're spilled to a temp file and all of the files are merged at the end
+    #[arg(long = "sort")]
+    pub sort: Option<SortKey>,
+
+    /// Drop duplicate records (the same event id, path and flags) while sorting, as produced by
+    /// overlapping collections
+    ///
+    /// Not allowed with `--sort node-id` since copies of an event can have different node ids
+    /// (or none, in v1 files) and so aren't sorted next to each other
+    #[arg(long = "dedupe", requires = "sort")]
+    pub dedupe: bool,
+
+    /// How many records to keep in memory while sorting before spilling them to a temp file
+    #[arg(long = "sort-buffer", default_value = "500000")]
+    pub sort_buffer: usize,
+
+    /// The dir to write the temporary sort (and uniques) files to (defaults to the system temp dir)
+    #[arg(long = "sort-tmp")]
+    pub sort_tmp: Option<PathBuf>,
+}
+
+impl SortOpts {
+    pub fn validate(&self) -> Result<()> {
+        if self.dedupe && self.sort == Some(SortKey::NodeId) {
+            return Err(eyre!(
+                "Can't dedupe when sorting by node id; the copies of an event aren't sorted next to each other"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for SortOpts {
+    fn default() -> Self {
+        Self {
+            sort: None,
+            dedupe: false,
+            sort_buffer: 500_000,
+            sort_tmp: None,
+        }
+    }
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix (powers of 1024)
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+
+    num.trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| eyre!("Invalid size '{s}'; expected a number with an optional K/M/G suffix"))
+}
+
+/// Options for the alerts raised by the detection rules
+#[derive(Clone, Debug, Default, Args)]
+pub struct AlertOpts {
+    /// Check the records against the detection rules and write the alerts they raise to a file
+    ///
+    /// The format is inferred from the extension (json if it can't be) and compression works the
+    /// same as the combined outputs. The rules see the records that pass the filter options
+    #[arg(long = "alerts")]
+    pub alerts: Option<PathBuf>,
+
+    /// The detection rules: a built-in rule set (`macos-default`) or a yaml rules file
+    ///
+    /// Can be given multiple times; `macos-default` is used if no rules are given
+    #[arg(long = "rules", requires = "alerts")]
+    pub rules: Vec<String>,
+}
+
+impl AlertOpts {
+    /// Loads the rules if alerts were requested
+    pub fn output(&self, path_opts: PathOpts) -> Result<Option<AlertOutput>> {
+        let Some(path) = &self.alerts else {
+            return Ok(None);
+        };
+
+        Ok(Some(AlertOutput {
+            path: path.clone(),
+            format: route::infer_format(path).unwrap_or(Format::Json),
+            detector: Detector::load(&self.rules, path_opts)?,
+        }))
+    }
+}
+
+/// Options for the burst analysis
+#[derive(Clone, Debug, Default, Args)]
+pub struct BurstOpts {
+    /// Find the bursts of renamed, modified and removed files (like ransomware or a wiper) and
+    /// write them to a file
+    ///
+    /// The format is inferred from the extension (json if it can't be) and compression works the
+    /// same as the combined outputs
+    #[arg(long = "bursts")]
+    pub bursts: Option<PathBuf>,
+
+    /// The event id span of the window sliding over the records
+    #[arg(long = "burst-window", default_value_t = 1000, requires = "bursts")]
+    pub burst_window: u64,
+
+    /// How many renamed, modified or removed records the window needs to hold for a burst
+    #[arg(long = "burst-min-events", default_value_t = 200, requires = "bursts")]
+    pub burst_min_events: usize,
+
+    /// The share of renames changing the extension at which a burst is suspected ransomware
+    #[arg(long = "burst-ext-ratio", default_value_t = 0.5, requires = "bursts")]
+    pub burst_ext_ratio: f64,
+}
+
+/// Options for the file lifecycles
+#[derive(Clone, Debug, Default, Args)]
+pub struct LifecycleOpts {
+    /// Follow every file through its created, modified, renamed and removed events and write the
+    /// ones that were created and then removed to a file
+    ///
+    /// Files are followed by their node id (so renames are part of the lifecycle) or by their
+    /// path for records without node ids. The format is inferred from the extension (json if it
+    /// can't be) and compression works the same as the combined outputs
+    #[arg(long = "lifecycles")]
+    pub lifecycles: Option<PathBuf>,
+
+    /// Write every lifecycle, not just the ones that were created and removed
+    #[arg(long = "lifecycles-all", requires = "lifecycles")]
+    pub lifecycles_all: bool,
+}
+
+/// Options for the activity histogram
+#[derive(Clone, Debug, Default, Args)]
+pub struct HistogramOpts {
+    /// Count the records per time bucket and flag and write them to a file to chart the activity
+    /// over the log window
+    ///
+    /// The format is inferred from the extension (csv if it can't be) and compression works the
+    /// same as the combined outputs
+    #[arg(long = "histogram")]
+    pub histogram: Option<PathBuf>,
+
+    /// What the histogram buckets are (`hour` and `day` use the timestamp of the file the record
+    /// was in)
+    #[arg(
+        long = "histogram-bucket",
+        value_enum,
+        default_value_t = Bucket::Hour,
+        requires = "histogram"
+    )]
+    pub histogram_bucket: Bucket,
+}
+
+/// Options for extra outputs that each have their own filter
+#[derive(Clone, Debug, Default, Args)]
+pub struct RouteOpts {
+    /// Write the records matching a filter to an extra output:
+    /// `<name>[@<format>]:<path>[:<filter>]`
+    ///
+    /// The filter is an expression in the same language as `--where` (like
+    /// `flags has Removed and path ~ "\.pdf$"`) and is applied on top of the global filter
+    /// options. The format is inferred from the path's extension unless it's given (as for `-`,
+    /// stdout) and compression works the same as the combined outputs. Can be given multiple times; every route is fed by the same parse
+    /// pass
+    #[arg(long = "route", value_parser = RouteSpec::parse)]
+    pub routes: Vec<RouteSpec>,
+
+    /// A yaml file with a list of routes to write
+    ///
+    /// Each route has a `name` and `path` and optionally a `format`, `pretty`, `where`
+    /// expression, `path_filter`, `any_flags` and `all_flags`
+    #[arg(long = "routes-file")]
+    pub routes_file: Option<PathBuf>,
+}
+
+impl RouteOpts {
+    /// If any routes were requested
+    pub fn enabled(&self) -> bool {
+        !self.routes.is_empty() || self.routes_file.is_some()
+    }
+
+    /// Loads and compiles every route from the command line and the routes file
+    ///
+    /// The routes' paths are matched with the same options as the main filter
+    pub fn routes(&self, path_opts: PathOpts) -> Result<Vec<Route>> {
+        let mut specs = self.routes.clone();
+        if let Some(p) = &self.routes_file {
+            specs.extend(route::load_routes(p)?);
+        }
+
+        let mut routes: Vec<Route> = Vec::with_capacity(specs.len());
+        for spec in specs {
+            if routes.iter().any(|r| r.name == spec.name) {
+                return Err(eyre!(
+                    "The route name '{}' is used more than once",
+                    spec.name
+                ));
+            }
+            let mut route = spec.build()?;
+            route.filter = route.filter.with_path_opts(path_opts, false)?;
+            routes.push(route);
+        }
+
+        Ok(routes)
+    }
+}
+
 #[derive(Debug, Args)]
 pub struct FilterOpts {
     /// Only show entries that have a path matching this regex
@@ -166,20 +712,211 @@ pub struct FilterOpts {
     pub filter_paths: Option<String>,
 
     /// Only show entries if any of the flags are present in the operation
-    #[arg(short = 'f', long = "any-flags", conflicts_with = "all_flags")]
+    #[arg(short = 'f', long = "any-flags")]
     pub any_flags: Vec<String>,
 
     /// Only show entries if all of the flags are present in the operation
-    #[arg(long = "all-flags", conflicts_with = "any_flags")]
+    #[arg(long = "all-flags")]
     pub all_flags: Vec<String>,
+
+    /// Only show entries matching this filter expression
+    ///
+    /// Supports `and`, `or`, `not` and parentheses over `path ~ "<regex>"`,
+    /// `path glob|prefix|suffix|contains|==|!= "<str>"`, `flags has|any|all <flag>,...`,
+    /// `event_id|node_id <op> <num>`, `event_id|node_id in <num>..<num>` and
+    /// `file_timestamp <op> "<time>"` (`<op>` is one of `== != < <= > >=`).
+    /// e.g. `flags has Created and not path ~ "^private/var/folders" and event_id > 0x1000`
+    #[arg(short = 'w', long = "where")]
+    pub where_expr: Option<String>,
+
+    /// Drop entries that have a path matching this regex (prefix with `glob:` to use a glob)
+    #[arg(long = "exclude-path")]
+    pub exclude_paths: Vec<String>,
+
+    /// Drop entries if any of these flags are present in the operation
+    #[arg(long = "exclude-flags")]
+    pub exclude_flags: Vec<String>,
+
+    /// Drop the noise described by a built-in profile (`macos-default`) or a yaml profile file
+    ///
+    /// A profile file has an optional `name` and an `exclude` list of rules; each rule has a
+    /// `name` and any of `path` (regex), `glob`, `flags` (any of) and `where` which all have to
+    /// match for a record to be dropped
+    #[arg(long = "noise-profile")]
+    pub noise_profiles: Vec<String>,
+
+    /// Only show entries with a path matching an indicator from this IOC file (can be repeated)
+    ///
+    /// IOC files are csv files with an `id,type,value` header; the type is one of `path`,
+    /// `name`, `ext`, `glob` or `regex`. Matching entries get an `ioc_ids` column with the ids
+    /// of every indicator they matched. Paths are relative to the volume root, so a leading `/`
+    /// (or a regex's `^/`) matches at the root
+    #[arg(long = "ioc-file")]
+    pub ioc_files: Vec<PathBuf>,
+
+    /// Keep the entries that don't match any indicator (they're still tagged)
+    #[arg(long = "ioc-keep-all", requires = "ioc_files")]
+    pub ioc_keep_all: bool,
+
+    /// Only show entries with an event id of at least this (decimal or `0x` prefixed hex)
+    ///
+    /// Files named after event ids that can't hold a wanted event are skipped entirely
+    #[arg(long = "min-event-id", value_parser = parse_id)]
+    pub min_event_id: Option<u64>,
+
+    /// Only show entries with an event id of at most this (decimal or `0x` prefixed hex)
+    #[arg(long = "max-event-id", value_parser = parse_id)]
+    pub max_event_id: Option<u64>,
+
+    /// Only show entries with an event id listed in this file (whitespace or comma separated,
+    /// decimal or `0x` prefixed hex, `#` starts a comment)
+    #[arg(long = "event-ids")]
+    pub event_ids: Option<PathBuf>,
+
+    /// Only show entries with a node id listed in this file (same format as `--event-ids`)
+    #[arg(long = "node-ids")]
+    pub node_ids: Option<PathBuf>,
+
+    /// Match paths case-insensitively (like APFS usually does)
+    ///
+    /// Applies to every path filter, the exclusions, the routes and the IOC indicators
+    #[arg(short = 'i', long = "ignore-case")]
+    pub ignore_case: bool,
+
+    /// Unicode normalize the paths and path filters to this form before matching
+    ///
+    /// HFS+ stored the paths decomposed (NFD) while most tools produce composed (NFC) paths
+    #[arg(long = "normalize", value_enum)]
+    pub normalize: Option<NormForm>,
+
+    /// Write the normalized paths to the outputs instead of the paths as stored
+    #[arg(long = "emit-normalized", requires = "normalize")]
+    pub emit_normalized: bool,
+
+    /// How paths that aren't valid UTF-8 are written
+    ///
+    /// The `path_lossy` column marks those paths. `escape` writes their invalid bytes as `\xNN`
+    /// (and `\` as `\\`) while `base64` adds a `path_base64` column with their raw bytes
+    #[arg(long = "path-encoding", value_enum, default_value_t)]
+    pub path_encoding: PathEncoding,
+
+    /// Add the fields derived from the path to the records: `dir`, `name`, `ext`, `depth`,
+    /// `home_user` (from `/Users/<name>` or `/private/var/root`) and `volume` (from
+    /// `/Volumes/<name>`)
+    ///
+    /// The filter expressions can use these fields without this option
+    #[arg(long = "derived-fields")]
+    pub derived_fields: bool,
+
+    /// Resolve the firmlinks (`/System/Volumes/Data/Users` is `/Users`) and the `/var`, `/tmp`
+    /// and `/etc` symlinks into a `canonical_path` that the filters and uniques use
+    #[arg(long = "canonicalize")]
+    pub canonicalize: bool,
+
+    /// Yaml files of extra `from: to` path prefix mappings to canonicalize with (implies
+    /// `--canonicalize`); they're applied before the built-in ones
+    #[arg(long = "path-map")]
+    pub path_maps: Vec<PathBuf>,
+
+    /// Label the records with the artifact they touch (browser history, launch agents, TCC.db,
+    /// ssh keys, ...) in `artifact_category` and `artifact_name` columns
+    #[arg(long = "classify")]
+    pub classify: bool,
+
+    /// Yaml files of extra artifact rules (implies `--classify`); they're checked before the
+    /// built-in ones
+    #[arg(long = "artifact-rules")]
+    pub artifact_rules: Vec<PathBuf>,
+
+    /// Only include the records of artifacts in the category, e.g. `persistence` (implies
+    /// `--classify`)
+    #[arg(long = "category")]
+    pub categories: Vec<String>,
 }
 
 impl FilterOpts {
+    /// How the paths should be matched
+    pub fn path_opts(&self) -> PathOpts {
+        PathOpts {
+            ignore_case: self.ignore_case,
+            normalize: self.normalize,
+        }
+    }
+
     pub fn filter(&self) -> Result<RecordFilter> {
-        RecordFilter::new(&self.filter_paths, &self.any_flags, &self.all_flags)
+        let mut exclusions = vec![];
+        for pat in self.exclude_paths.iter() {
+            exclusions.push(Exclusion::path(pat)?);
+        }
+        for flag in self.exclude_flags.iter() {
+            exclusions.push(Exclusion::flag(flag)?);
+        }
+        for profile in self.noise_profiles.iter() {
+            exclusions.extend(noise::load_profile(profile)?);
+        }
+
+        let mut filter = RecordFilter::new(&self.filter_paths, &self.any_flags, &self.all_flags)?
+            .with_path_opts(self.path_opts(), self.emit_normalized)?
+            .with_path_encoding(self.path_encoding)
+            .with_derived_fields(self.derived_fields)
+            .with_where(self.where_expr.as_deref())?
+            .with_ids(
+                self.min_event_id,
+                self.max_event_id,
+                self.event_ids.as_deref().map(load_ids).transpose()?,
+                self.node_ids.as_deref().map(load_ids).transpose()?,
+            )
+            .with_exclusions(exclusions)?;
+
+        if self.canonicalize || !self.path_maps.is_empty() {
+            filter = filter.with_canonicalizer(Canonicalizer::load(&self.path_maps)?);
+        }
+        if self.classify || !self.artifact_rules.is_empty() || !self.categories.is_empty() {
+            filter =
+                filter.with_artifacts(Classifier::load(&self.artifact_rules)?, &self.categories)?;
+        }
+        if !self.ioc_files.is_empty() {
+            filter = filter.with_iocs(
+                IocSet::load(&self.ioc_files, self.path_opts())?,
+                self.ioc_keep_all,
+            );
+        }
+
+        Ok(filter)
     }
 }
 
+/// Parses an id given as decimal or `0x` prefixed hex
+pub fn parse_id(s: &str) -> Result<u64> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| eyre!("Invalid id '{s}'; expected a decimal or 0x prefixed hex number"))
+}
+
+/// Loads a list of ids from a file
+pub fn load_ids(path: &Path) -> Result<BTreeSet<u64>> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| eyre!("Couldn't read the id file {}: {e}", path.display()))?;
+
+    let mut ids = BTreeSet::new();
+    for (num, line) in data.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        for id in line.split(|c: char| c == ',' || c.is_whitespace()) {
+            if !id.is_empty() {
+                ids.insert(
+                    parse_id(id)
+                        .map_err(|e| eyre!("{e} on line {} of {}", num + 1, path.display()))?,
+                );
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
 #[derive(Clone, Copy, Debug, Args)]
 pub struct CompressOpts {
     /// The level we should compress the gzip output as; 0-9
@@ -264,12 +1001,55 @@ impl CompressOpts {
 
     #[cfg(feature = "zstd")]
     pub fn make_zstd<'a, W>(&self, w: W) -> zstd::stream::AutoFinishEncoder<'a, W>
+    where
+        W: Write,
+    {
+        self.make_zstd_encoder(w).auto_finish()
+    }
+
+    /// The zstd encoder, which has to be finished by hand
+    #[cfg(feature = "zstd")]
+    fn make_zstd_encoder<'a, W>(&self, w: W) -> zstd::stream::write::Encoder<'a, W>
     where
         W: Write,
     {
         let mut z = zstd::stream::write::Encoder::new(w, self.zlvl()).unwrap();
         z.multithread(self.zthreads as u32).unwrap();
-        z.auto_finish()
+        z
+    }
+
+    /// Creates the file at `path` wrapped in whichever compression is wanted for it
+    pub fn make_file(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+        Ok(Box::new(FinishOnDrop(self.make_finish_file(path)?)))
+    }
+
+    /// Creates the file at `path` like `make_file`, but it has to be finished by hand (so the
+    /// errors finishing it can be handled)
+    pub fn make_finish_file(&self, path: &Path) -> io::Result<Box<dyn FinishWrite>> {
+        let f = File::create(path)?;
+
+        Ok(if self.is_gz(path) {
+            Box::new(self.make_gzip(BufWriter::new(f)))
+        } else if self.is_zstd(path) {
+            #[cfg(feature = "zstd")]
+            {
+                Box::new(self.make_zstd_encoder(BufWriter::new(f)))
+            }
+
+            #[cfg(not(feature = "zstd"))]
+            unreachable!("zstd feature not enabled");
+        } else {
+            Box::new(BufWriter::new(f))
+        })
+    }
+
+    /// Creates the output at `path`, which is stdout if it's "-"
+    pub fn make_output(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+        if path.as_os_str() == "-" {
+            Ok(Box::new(self.make_stdout()))
+        } else {
+            self.make_file(path)
+        }
     }
 
     pub fn make_stdout(&self) -> BufWriter<Box<dyn Write>> {
@@ -299,6 +1079,102 @@ impl CompressOpts {
     }
 }
 
+/// The UTF-8 byte order mark; Excel needs it to detect the encoding of a csv
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+#[derive(Clone, Copy, Debug, Args)]
+pub struct CsvOpts {
+    /// The field delimiter for csv output; a single ascii char or `tab`
+    #[arg(long = "csv-delimiter", default_value = ",", value_parser = parse_delimiter)]
+    pub delimiter: u8,
+
+    /// When the fields in the csv output should be quoted
+    #[arg(long = "csv-quote", default_value = "necessary")]
+    pub quote: CsvQuote,
+
+    /// The line terminator for csv output
+    #[arg(long = "csv-terminator", default_value = "lf")]
+    pub terminator: CsvTerminator,
+
+    /// Don't write a header row to the csv output
+    #[arg(long = "csv-no-header")]
+    pub no_header: bool,
+
+    /// Start the csv output with a UTF-8 byte order mark (for Excel)
+    #[arg(long = "csv-bom")]
+    pub bom: bool,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum CsvQuote {
+    Always,
+    Necessary,
+    NonNumeric,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum CsvTerminator {
+    Lf,
+    Crlf,
+}
+
+fn parse_delimiter(s: &str) -> Result<u8> {
+    match s {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(eyre!(
+            "The csv delimiter must be a single ascii char or `tab`"
+        )),
+    }
+}
+
+impl Default for CsvOpts {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: CsvQuote::Necessary,
+            terminator: CsvTerminator::Lf,
+            no_header: false,
+            bom: false,
+        }
+    }
+}
+
+impl CsvOpts {
+    /// If the header row should be written
+    pub fn has_headers(&self) -> bool {
+        !self.no_header
+    }
+
+    /// Creates a csv writer using the wanted dialect, writing the BOM first if needed
+    pub fn writer<W>(&self, mut w: W) -> csv::Writer<W>
+    where
+        W: Write,
+    {
+        if self.bom
+            && let Err(err) = w.write_all(UTF8_BOM)
+        {
+            error!("Couldn't write the csv BOM: {err}");
+        }
+
+        csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .quote_style(match self.quote {
+                CsvQuote::Always => csv::QuoteStyle::Always,
+                CsvQuote::Necessary => csv::QuoteStyle::Necessary,
+                CsvQuote::NonNumeric => csv::QuoteStyle::NonNumeric,
+                CsvQuote::Never => csv::QuoteStyle::Never,
+            })
+            .terminator(match self.terminator {
+                CsvTerminator::Lf => csv::Terminator::Any(b'\n'),
+                CsvTerminator::Crlf => csv::Terminator::CRLF,
+            })
+            .has_headers(self.has_headers())
+            .from_writer(w)
+    }
+}
+
 fn stdout_path(path: &Option<PathBuf>) -> bool {
     if let Some(p) = path {
         p.as_os_str() == "-"
@@ -319,6 +1195,18 @@ impl Dump {
         if stdout_path(&self.uniques) {
             counts += 1
         };
+        if stdout_path(&self.alert_opts.alerts) {
+            counts += 1
+        };
+        if stdout_path(&self.burst_opts.bursts) {
+            counts += 1
+        };
+        if stdout_path(&self.lifecycle_opts.lifecycles) {
+            counts += 1
+        };
+        if stdout_path(&self.histogram_opts.histogram) {
+            counts += 1
+        };
         counts
     }
 
@@ -329,11 +1217,46 @@ impl Dump {
             return Err(eyre!("Can't have more than one file printing to stdout!",));
         }
 
+        if let (Some(since), Some(until)) = (self.since, self.until)
+            && since > until
+        {
+            return Err(eyre!("--since ({since}) is after --until ({until})"));
+        }
+
+        if let Some(out) = self.uniques_output()
+            && out.format == UniquesFormat::Sqlite
+        {
+            if !cfg!(feature = "sqlite") {
+                return Err(eyre!(
+                    "Writing the uniques to sqlite needs the sqlite feature"
+                ));
+            }
+            if out.path.as_os_str() == "-" {
+                return Err(eyre!("Can't print the sqlite uniques to stdout!"));
+            }
+            if self.compress_opts.is_gz(&out.path) || self.compress_opts.is_zstd(&out.path) {
+                return Err(eyre!("Can't compress the sqlite uniques"));
+            }
+        }
+
+        self.split_opts.validate()?;
+        self.sort_opts.validate()?;
+        if self.split_opts.enabled()
+            && (stdout_path(&self.csv) || stdout_path(&self.json) || stdout_path(&self.yaml))
+        {
+            return Err(eyre!("Can't split the output that is printed to stdout!",));
+        }
+
         if !(self.csvs
             || self.jsons
             || self.csv.is_some()
             || self.json.is_some()
-            || self.uniques.is_some())
+            || self.uniques.is_some()
+            || self.route_opts.enabled()
+            || self.alert_opts.alerts.is_some()
+            || self.burst_opts.bursts.is_some()
+            || self.lifecycle_opts.lifecycles.is_some()
+            || self.histogram_opts.histogram.is_some())
         {
             return Err(eyre!("You must specify at least one output type!",));
         }
@@ -346,99 +1269,221 @@ impl Dump {
         str.to_string_lossy().chars().all(|c| c.is_ascii_hexdigit())
     }
 
-    fn cutoff_time(&self) -> Option<SystemTime> {
-        if self.pull_days > 0 {
-            Some(
-                Zoned::now()
-                    .checked_sub(Span::new().days(self.pull_days))
-                    .expect("invalid date offset")
-                    .start_of_day()
-                    .expect("invalid date offset")
-                    .into(),
-            )
-        } else {
-            None
+    /// If a file's mod time is in the time window; files without a mod time are always wanted
+    fn in_time_window(
+        meta: &std::fs::Metadata,
+        (since, until): (Option<SystemTime>, Option<SystemTime>),
+    ) -> bool {
+        match meta.modified().or_else(|_| meta.created()) {
+            Ok(mod_time) => {
+                since.is_none_or(|since| mod_time >= since)
+                    && until.is_none_or(|until| mod_time <= until)
+            }
+            // Weird metadata issue assume we want it
+            Err(_) => true,
         }
     }
 
     pub fn real_files(&self) -> Vec<PathBuf> {
-        let cutoff = self.cutoff_time();
-
-        let mut files = Vec::with_capacity(128);
-
-        self.files.iter().for_each(|path| {
-            match path.metadata() {
-                Err(err) => error!("Error processing '{}': {err}", path.display()),
-                Ok(info) => {
-                    if info.is_dir() {
-                        walkdir::WalkDir::new(path)
-                            .max_depth(1)
-                            .follow_links(true)
-                            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
-                            .into_iter()
-                            .for_each(|e| match e {
-                                Ok(e) => {
-                                    // Do the filename check first since it's fast and doesn't do
-                                    // any metadata reads
-                                    if Dump::want_filename(e.file_name()) {
-                                        let want_file = if let Ok(m) = e.metadata() {
-                                            if !m.is_dir() {
-                                                // See if we care about filtering by time
-                                                if let Some(cut_time) = cutoff {
-                                                    // Get the file mod/create time
-                                                    if let Ok(mod_time) =
-                                                        m.modified().or_else(|_| m.created())
-                                                    {
-                                                        // Only process files that have a mod time greater than our
-                                                        // cutoff time
-                                                        if mod_time > cut_time {
-                                                            true
-                                                        } else {
-                                                            debug!(
-                                                                "Skipping {} due to time cutoff",
-                                                                e.path().display()
-                                                            );
-                                                            false
-                                                        }
-                                                    } else {
-                                                        // Weird metadata issue assume we want it
-                                                        true
-                                                    }
-                                                } else {
-                                                    // No time filters always include
-                                                    true
-                                                }
-                                            } else {
-                                                // Skip dirs
-                                                false
-                                            }
-                                        } else {
-                                            // Couldn't get metadata so not sure what this is, try
-                                            // getting it anyway (very unlikely to get here)
-                                            true
-                                        };
+        find_files(&self.files, self.pull_days, self.since, self.until)
+    }
 
-                                        if want_file {
-                                            debug!("Found the fs events file {:?}", e.path());
-                                            files.push(e.into_path());
+    /// Where and how the uniques are written (if they are)
+    pub fn uniques_output(&self) -> Option<UniquesOutput> {
+        let path = self.uniques.clone()?;
+        let format = self.uniques_format.unwrap_or_else(|| {
+            let ext = |exts: &[&str]| {
+                path.extension()
+                    .is_some_and(|e| exts.iter().any(|x| e.eq_ignore_ascii_case(x)))
+            };
+            match route::infer_format(&path) {
+                Some(Format::Json) => UniquesFormat::Json,
+                Some(Format::Yaml) => UniquesFormat::Yaml,
+                _ if ext(&["sqlite", "sqlite3", "db"]) => UniquesFormat::Sqlite,
+                _ => UniquesFormat::Csv,
+            }
+        });
+
+        Some(UniquesOutput {
+            path,
+            format,
+            timestamps: self.unique_timestamps,
+            rollup: self.uniques_rollup(),
+            details: self.unique_details.clone(),
+            bucket: self.uniques_bucket,
+            memory: self.uniques_memory,
+            tmp_dir: self
+                .sort_opts
+                .sort_tmp
+                .clone()
+                .unwrap_or_else(std::env::temp_dir),
+        })
+    }
+
+    /// How the uniques are rolled up into directories (if they are)
+    pub fn uniques_rollup(&self) -> Option<Rollup> {
+        match self.uniques_depth {
+            Some(depth) => Some(Rollup::Depth(depth as usize)),
+            None => self.uniques_by_dir.then_some(Rollup::Parent),
+        }
+    }
+}
+
+fn cutoff_time(pull_days: u32) -> Option<SystemTime> {
+    if pull_days > 0 {
+        Some(
+            Zoned::now()
+                .checked_sub(Span::new().days(pull_days))
+                .expect("invalid date offset")
+                .start_of_day()
+                .expect("invalid date offset")
+                .into(),
+        )
+    } else {
+        None
+    }
+}
+
+/// The (inclusive) window the file mod times have to be in
+///
+/// `--since`/`--until` take precedence over `--days`
+fn time_window(
+    pull_days: u32,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+) -> (Option<SystemTime>, Option<SystemTime>) {
+    if since.is_some() || until.is_some() {
+        (since.map(Into::into), until.map(Into::into))
+    } else {
+        (cutoff_time(pull_days), None)
+    }
+}
+
+/// Finds the fs event files to parse in the given files and directories
+///
+/// Files found in a directory have to be in the time window (`--days` or `--since`/`--until`)
+/// while explicitly given files are only limited by `--since`/`--until`
+fn find_files(
+    paths: &[PathBuf],
+    pull_days: u32,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+) -> Vec<PathBuf> {
+    let window = time_window(pull_days, since, until);
+    let explicit_window = (since.map(Into::into), until.map(Into::into));
+    let mut skipped = 0usize;
+
+    let mut files = Vec::with_capacity(128);
+
+    paths.iter().for_each(|path| {
+        match path.metadata() {
+            Err(err) => error!("Error processing '{}': {err}", path.display()),
+            Ok(info) => {
+                if info.is_dir() {
+                    walkdir::WalkDir::new(path)
+                        .max_depth(1)
+                        .follow_links(true)
+                        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+                        .into_iter()
+                        .for_each(|e| match e {
+                            Ok(e) => {
+                                // Do the filename check first since it's fast and doesn't do
+                                // any metadata reads
+                                if Dump::want_filename(e.file_name()) {
+                                    let want_file = if let Ok(m) = e.metadata() {
+                                        if m.is_dir() {
+                                            // Skip dirs
+                                            false
+                                        } else if Dump::in_time_window(&m, window) {
+                                            true
+                                        } else {
+                                            debug!(
+                                                "Skipping {} due to the time window",
+                                                e.path().display()
+                                            );
+                                            skipped += 1;
+                                            false
                                         }
+                                    } else {
+                                        // Couldn't get metadata so not sure what this is, try
+                                        // getting it anyway (very unlikely to get here)
+                                        true
+                                    };
+
+                                    if want_file {
+                                        debug!("Found the fs events file {:?}", e.path());
+                                        files.push(e.into_path());
                                     }
                                 }
-
-                                Err(err) => {
-                                    error!("Error iterating the files: {err}");
-                                }
-                            });
-                    } else if info.is_file() {
+                            }
+
+                            Err(err) => {
+                                error!("Error iterating the files: {err}");
+                            }
+                        });
+                } else if info.is_file() {
+                    // Explicitly given files are only limited by an explicit time window
+                    if Dump::in_time_window(&info, explicit_window) {
                         files.push(path.clone())
                     } else {
-                        error!("Unknown file type for '{}': {info:?}", path.display())
+                        debug!("Skipping {} due to the time window", path.display());
+                        skipped += 1;
                     }
+                } else {
+                    error!("Unknown file type for '{}': {info:?}", path.display())
                 }
             }
-        });
+        }
+    });
 
-        files
+    if skipped > 0 {
+        summary!(
+            log::Level::Warn,
+            "The time window excluded {skipped} of {} files",
+            skipped + files.len()
+        );
+    }
+
+    // The replays need the files of a dir in event id order whatever order they were given in
+    file_parser::order_by_event_id(files)
+}
+
+impl Tree {
+    pub fn validate(&self) -> Result<()> {
+        if let (Some(since), Some(until)) = (self.since, self.until)
+            && since > until
+        {
+            return Err(eyre!("--since ({since}) is after --until ({until})"));
+        }
+
+        Ok(())
+    }
+
+    /// The format the tree is written as
+    pub fn format(&self) -> TreeFormat {
+        self.format
+            .unwrap_or(match route::infer_format(&self.output) {
+                Some(Format::Csv) => TreeFormat::Csv,
+                Some(Format::Json) => TreeFormat::Json,
+                _ => TreeFormat::Text,
+            })
+    }
+
+    pub fn real_files(&self) -> Vec<PathBuf> {
+        find_files(&self.files, self.pull_days, self.since, self.until)
+    }
+}
+
+/// Parses a point in time given as a timestamp, date, or span before now
+///
+/// This runs while the args are parsed (before the error hook is installed) so valid values
+/// must not build an error report along the way
+pub fn parse_when(s: &str) -> Result<Timestamp> {
+    match s.trim().parse::<Span>() {
+        Ok(span) => Ok(Zoned::now().checked_sub(span.abs())?.timestamp()),
+        Err(_) => expr::parse_time(s).map_err(|_| {
+            eyre!("Invalid time '{s}'; expected an RFC 3339 timestamp, a date or a span like 2w")
+        }),
     }
 }
 
@@ -448,7 +1493,10 @@ pub fn get_opts() -> Result<Cli> {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use super::*;
+    use crate::record::Record;
 
     #[test]
     fn test_compress_opts_glvl() {
@@ -607,7 +1655,15 @@ mod tests {
             yaml: None,
             uniques: None,
             unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
             pull_days: 90,
+            since: None,
+            until: None,
             files: vec![],
             compress_opts: CompressOpts {
                 glevel: 7,
@@ -619,10 +1675,38 @@ mod tests {
                 #[cfg(feature = "zstd")]
                 zstd: false,
             },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts::default(),
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts::default(),
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
             filter_opts: FilterOpts {
                 filter_paths: None,
                 any_flags: vec![],
                 all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
             },
         };
 
@@ -640,7 +1724,15 @@ mod tests {
             yaml: None,
             uniques: None,
             unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
             pull_days: 90,
+            since: None,
+            until: None,
             files: vec![],
             compress_opts: CompressOpts {
                 glevel: 7,
@@ -652,10 +1744,38 @@ mod tests {
                 #[cfg(feature = "zstd")]
                 zstd: false,
             },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts::default(),
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts::default(),
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
             filter_opts: FilterOpts {
                 filter_paths: None,
                 any_flags: vec![],
                 all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
             },
         };
 
@@ -673,7 +1793,15 @@ mod tests {
             yaml: None,
             uniques: None,
             unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
             pull_days: 90,
+            since: None,
+            until: None,
             files: vec![],
             compress_opts: CompressOpts {
                 glevel: 7,
@@ -685,10 +1813,38 @@ mod tests {
                 #[cfg(feature = "zstd")]
                 zstd: false,
             },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts::default(),
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts::default(),
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
             filter_opts: FilterOpts {
                 filter_paths: None,
                 any_flags: vec![],
                 all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
             },
         };
 
@@ -710,7 +1866,15 @@ mod tests {
             yaml: None,
             uniques: None,
             unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
             pull_days: 90,
+            since: None,
+            until: None,
             files: vec![],
             compress_opts: CompressOpts {
                 glevel: 7,
@@ -722,10 +1886,38 @@ mod tests {
                 #[cfg(feature = "zstd")]
                 zstd: false,
             },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts::default(),
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts::default(),
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
             filter_opts: FilterOpts {
                 filter_paths: None,
                 any_flags: vec![],
                 all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
             },
         };
 
@@ -744,7 +1936,15 @@ mod tests {
             yaml: None,
             uniques: None,
             unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
             pull_days: 90,
+            since: None,
+            until: None,
             files: vec![],
             compress_opts: CompressOpts {
                 glevel: 5,
@@ -756,10 +1956,38 @@ mod tests {
                 #[cfg(feature = "zstd")]
                 zstd: false,
             },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts::default(),
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts::default(),
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
             filter_opts: FilterOpts {
                 filter_paths: None,
                 any_flags: vec![],
                 all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
             },
         };
 
@@ -796,7 +2024,15 @@ mod tests {
             yaml: None,
             uniques: None,
             unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
             pull_days: 0, // No time filter
+            since: None,
+            until: None,
             files: vec![],
             compress_opts: CompressOpts {
                 glevel: 7,
@@ -808,14 +2044,42 @@ mod tests {
                 #[cfg(feature = "zstd")]
                 zstd: false,
             },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts::default(),
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts::default(),
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
             filter_opts: FilterOpts {
                 filter_paths: None,
                 any_flags: vec![],
                 all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
             },
         };
 
-        assert!(dump.cutoff_time().is_none());
+        assert!(cutoff_time(dump.pull_days).is_none());
     }
 
     #[test]
@@ -829,7 +2093,15 @@ mod tests {
             yaml: None,
             uniques: None,
             unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
             pull_days: 30,
+            since: None,
+            until: None,
             files: vec![],
             compress_opts: CompressOpts {
                 glevel: 7,
@@ -841,14 +2113,42 @@ mod tests {
                 #[cfg(feature = "zstd")]
                 zstd: false,
             },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts::default(),
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts::default(),
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
             filter_opts: FilterOpts {
                 filter_paths: None,
                 any_flags: vec![],
                 all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
             },
         };
 
-        let cutoff = dump.cutoff_time();
+        let cutoff = cutoff_time(dump.pull_days);
         assert!(cutoff.is_some());
     }
 
@@ -858,12 +2158,37 @@ mod tests {
             filter_paths: Some(r"^/test/".to_string()),
             any_flags: vec!["Modified".to_string()],
             all_flags: vec![],
+            where_expr: None,
+            exclude_paths: vec![],
+            exclude_flags: vec![],
+            noise_profiles: vec![],
+            ioc_files: vec![],
+            ioc_keep_all: false,
+            min_event_id: None,
+            max_event_id: None,
+            event_ids: None,
+            node_ids: None,
+            ignore_case: false,
+            normalize: None,
+            emit_normalized: false,
+            path_encoding: PathEncoding::Lossy,
+            derived_fields: false,
+            canonicalize: false,
+            path_maps: vec![],
+            classify: false,
+            artifact_rules: vec![],
+            categories: vec![],
         };
 
         let filter = filter_opts.filter().unwrap();
-        assert!(filter.path_rex.is_some());
-        assert!(filter.any_flag > 0);
-        assert_eq!(filter.all_flag, 0);
+        let rec = |path: &str, flag: u32| crate::record::Record {
+            path: path.to_string(),
+            flag,
+            ..Default::default()
+        };
+        assert!(filter.want(&rec("/test/a", 0x1000_0000)));
+        assert!(!filter.want(&rec("/other/a", 0x1000_0000)));
+        assert!(!filter.want(&rec("/test/a", 0x0100_0000)));
     }
 
     #[test]
@@ -872,12 +2197,30 @@ mod tests {
             filter_paths: None,
             any_flags: vec![],
             all_flags: vec![],
+            where_expr: None,
+            exclude_paths: vec![],
+            exclude_flags: vec![],
+            noise_profiles: vec![],
+            ioc_files: vec![],
+            ioc_keep_all: false,
+            min_event_id: None,
+            max_event_id: None,
+            event_ids: None,
+            node_ids: None,
+            ignore_case: false,
+            normalize: None,
+            emit_normalized: false,
+            path_encoding: PathEncoding::Lossy,
+            derived_fields: false,
+            canonicalize: false,
+            path_maps: vec![],
+            classify: false,
+            artifact_rules: vec![],
+            categories: vec![],
         };
 
         let filter = filter_opts.filter().unwrap();
-        assert!(filter.path_rex.is_none());
-        assert_eq!(filter.any_flag, 0);
-        assert_eq!(filter.all_flag, 0);
+        assert!(matches!(filter.expr, crate::expr::Expr::True));
     }
 
     #[test]
@@ -887,6 +2230,546 @@ mod tests {
         assert!(!stdout_path(&None));
     }
 
+    #[test]
+    fn test_csv_opts_default_dialect() {
+        let mut w = CsvOpts::default().writer(vec![]);
+        w.write_record(["a", "b c", "d,e"]).unwrap();
+
+        assert_eq!(w.into_inner().unwrap(), b"a,b c,\"d,e\"\n");
+    }
+
+    #[test]
+    fn test_csv_opts_excel_dialect() {
+        let opts = CsvOpts {
+            delimiter: b';',
+            terminator: CsvTerminator::Crlf,
+            bom: true,
+            ..CsvOpts::default()
+        };
+
+        let mut w = opts.writer(vec![]);
+        w.write_record(["a", "b;c"]).unwrap();
+
+        assert_eq!(w.into_inner().unwrap(), b"\xEF\xBB\xBFa;\"b;c\"\r\n");
+    }
+
+    #[test]
+    fn test_csv_opts_tsv_no_quotes_no_header() {
+        let opts = CsvOpts {
+            delimiter: parse_delimiter("tab").unwrap(),
+            quote: CsvQuote::Never,
+            no_header: true,
+            ..CsvOpts::default()
+        };
+
+        #[derive(Serialize)]
+        struct Row {
+            path: &'static str,
+            counts: u64,
+        }
+
+        let mut w = opts.writer(vec![]);
+        w.serialize(Row {
+            path: "/a \"b\"",
+            counts: 3,
+        })
+        .unwrap();
+
+        assert_eq!(w.into_inner().unwrap(), b"/a \"b\"\t3\n");
+    }
+
+    #[test]
+    fn test_cli_debug_assert() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size("4k").unwrap(), 4096);
+        assert_eq!(parse_size("2M").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_size("1 G").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12x").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+
+    #[test]
+    fn test_dump_validate_split_stdout() {
+        let dump = Dump {
+            csvs: false,
+            jsons: false,
+            yamls: false,
+            csv: None,
+            json: Some(PathBuf::from("-")),
+            yaml: None,
+            uniques: None,
+            unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
+            pull_days: 90,
+            since: None,
+            until: None,
+            files: vec![],
+            compress_opts: CompressOpts {
+                glevel: 7,
+                #[cfg(feature = "zstd")]
+                zlevel: 10,
+                #[cfg(feature = "zstd")]
+                zthreads: 2,
+                gzip: false,
+                #[cfg(feature = "zstd")]
+                zstd: false,
+            },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts {
+                split_records: Some(10),
+                ..SplitOpts::default()
+            },
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts::default(),
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
+            filter_opts: FilterOpts {
+                filter_paths: None,
+                any_flags: vec![],
+                all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
+            },
+        };
+
+        let count = dump.stdout_counts();
+        assert!(dump.validate(count).is_err(), "Can't split stdout");
+    }
+
+    fn parse_dump(args: &[&str]) -> Result<Dump> {
+        let cli = Cli::try_parse_from(["fse_dump", "dump", "-j", "out.json"].iter().chain(args))?;
+        match cli.command {
+            Commands::Dump(d) => Ok(*d),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_uniques_rollup() {
+        let dump = parse_dump(&["-u", "u.csv"]).unwrap();
+        assert_eq!(dump.uniques_rollup(), None);
+        let dump = parse_dump(&["-u", "u.csv", "--uniques-depth", "3"]).unwrap();
+        assert_eq!(dump.uniques_rollup(), Some(Rollup::Depth(3)));
+        let dump = parse_dump(&["-u", "u.csv", "--uniques-by-dir"]).unwrap();
+        assert_eq!(dump.uniques_rollup(), Some(Rollup::Parent));
+
+        assert!(parse_dump(&["--uniques-by-dir"]).is_err());
+        assert!(parse_dump(&["--unique-details", "all"]).is_err());
+
+        let dump =
+            parse_dump(&["-u", "u.csv", "--unique-details", "flag-counts,node-ids"]).unwrap();
+        assert_eq!(
+            dump.unique_details,
+            vec![UniqueDetail::FlagCounts, UniqueDetail::NodeIds]
+        );
+        assert!(parse_dump(&["-u", "u.csv", "--uniques-depth", "0"]).is_err());
+        assert!(parse_dump(&["-u", "u.csv", "--uniques-depth", "2", "--uniques-by-dir"]).is_err());
+    }
+
+    #[test]
+    fn test_uniques_format() {
+        let format = |args: &[&str]| parse_dump(args).unwrap().uniques_output().unwrap().format;
+
+        assert_eq!(format(&["-u", "u.csv.gz"]), UniquesFormat::Csv);
+        assert_eq!(format(&["-u", "u.txt"]), UniquesFormat::Csv);
+        assert_eq!(format(&["-u", "u.json"]), UniquesFormat::Json);
+        assert_eq!(format(&["-u", "u.yml.zst"]), UniquesFormat::Yaml);
+        assert_eq!(format(&["-u", "u.sqlite"]), UniquesFormat::Sqlite);
+        assert_eq!(
+            format(&["-u", "u.db", "--uniques-format", "json"]),
+            UniquesFormat::Json
+        );
+        assert!(parse_dump(&[]).unwrap().uniques_output().is_none());
+
+        let dump = parse_dump(&["-u", "-", "--uniques-format", "sqlite"]).unwrap();
+        assert!(dump.validate(0).is_err());
+        let dump = parse_dump(&["-u", "u.db", "--gzip"]).unwrap();
+        assert!(dump.validate(0).is_err());
+        #[cfg(feature = "sqlite")]
+        assert!(parse_dump(&["-u", "u.db"]).unwrap().validate(0).is_ok());
+        #[cfg(not(feature = "sqlite"))]
+        assert!(parse_dump(&["-u", "u.db"]).unwrap().validate(0).is_err());
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn test_rotate_opts() {
+        let parse_watch = |args: &[&str]| -> Result<RotateOpts> {
+            let cli = Cli::try_parse_from(["fse_dump", "watch"].iter().chain(args))?;
+            match cli.command {
+                Commands::Watch(w) => Ok(w.rotate_opts),
+                _ => unreachable!(),
+            }
+        };
+
+        let rotate = parse_watch(&["--output", "e-%Y%m%d.json.gz", "--rotate", "daily"]).unwrap();
+        assert_eq!(rotate.rotate, Some(Rotation::Daily));
+        assert!(rotate.validate().is_ok());
+        let rotate = parse_watch(&["--output", "e.json", "--rotate-size", "1M"]).unwrap();
+        assert_eq!(rotate.rotate_size, Some(1 << 20));
+
+        assert!(parse_watch(&["--rotate", "hourly"]).is_err());
+        assert!(parse_watch(&["--keep", "3"]).is_err());
+        for args in [
+            &["--output", "e.json", "--keep", "0"][..],
+            &["--output", "e-%"],
+            &["--output", "-"],
+        ] {
+            assert!(parse_watch(args).unwrap().validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_iocs_ignore_case() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let iocs = dir.path().join("iocs.csv");
+        std::fs::write(&iocs, "id,type,value\nevil,path,/users/alice/evil.dylib\n").unwrap();
+        let iocs = iocs.to_str().unwrap();
+
+        let mut rec = Record {
+            path: "Users/Alice/Evil.dylib".to_string(),
+            ..Record::default()
+        };
+        let filter = parse_dump(&["--ioc-file", iocs])
+            .unwrap()
+            .filter_opts
+            .filter();
+        assert!(!filter.unwrap().process(&mut rec));
+
+        let filter = parse_dump(&["-i", "--ioc-file", iocs])
+            .unwrap()
+            .filter_opts
+            .filter()
+            .unwrap();
+        assert!(filter.process(&mut rec));
+        assert_eq!(rec.ioc_ids, Some(vec![Arc::from("evil")]));
+    }
+
+    #[test]
+    fn test_sort_dedupe() {
+        for key in ["event-id", "path"] {
+            let dump = parse_dump(&["--sort", key, "--dedupe"]).unwrap();
+            assert!(dump.validate(0).is_ok());
+        }
+
+        let dump = parse_dump(&["--sort", "node-id", "--dedupe"]).unwrap();
+        assert!(dump.validate(0).is_err());
+        let dump = parse_dump(&["--sort", "node-id"]).unwrap();
+        assert!(dump.validate(0).is_ok());
+    }
+
+    #[test]
+    fn test_buckets() {
+        let dump = parse_dump(&["-u", "u.csv", "--uniques-bucket", "day"]).unwrap();
+        assert_eq!(dump.uniques_output().unwrap().bucket, Some(Bucket::Day));
+        assert!(parse_dump(&["--uniques-bucket", "day"]).is_err());
+        assert!(parse_dump(&["-u", "u.csv", "--uniques-bucket", "week"]).is_err());
+
+        let dump = parse_dump(&["--histogram", "h.csv"]).unwrap();
+        assert_eq!(dump.histogram_opts.histogram_bucket, Bucket::Hour);
+        let dump = parse_dump(&["--histogram", "h.csv", "--histogram-bucket", "file"]).unwrap();
+        assert_eq!(dump.histogram_opts.histogram_bucket, Bucket::File);
+        assert!(parse_dump(&["--histogram-bucket", "file"]).is_err());
+    }
+
+    #[test]
+    fn test_dump_files_reversed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let a = dir.path().join("0000000000000100");
+        let b = dir.path().join("0000000000000200");
+        std::fs::write(&a, b"").unwrap();
+        std::fs::write(&b, b"").unwrap();
+
+        let dump = parse_dump(&[
+            "--bursts",
+            "b.csv",
+            b.to_str().unwrap(),
+            a.to_str().unwrap(),
+        ]);
+        assert_eq!(dump.unwrap().real_files(), vec![a, b]);
+    }
+
+    #[test]
+    fn test_tree_files_reversed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let a = dir.path().join("0000000000000100");
+        let b = dir.path().join("0000000000000200");
+        std::fs::write(&a, b"").unwrap();
+        std::fs::write(&b, b"").unwrap();
+
+        let cli = Cli::try_parse_from([
+            "fse_dump".as_ref(),
+            "tree".as_ref(),
+            b.as_os_str(),
+            a.as_os_str(),
+        ])
+        .unwrap();
+        let Commands::Tree(tree) = cli.command else {
+            unreachable!()
+        };
+        assert_eq!(tree.real_files(), vec![a, b]);
+    }
+
+    #[test]
+    fn test_tree_format() {
+        let parse_tree =
+            |args: &[&str]| match Cli::try_parse_from(["fse_dump", "tree"].iter().chain(args))
+                .unwrap()
+                .command
+            {
+                Commands::Tree(t) => *t,
+                _ => unreachable!(),
+            };
+
+        assert_eq!(parse_tree(&[]).format(), TreeFormat::Text);
+        assert_eq!(parse_tree(&["-o", "tree.csv.gz"]).format(), TreeFormat::Csv);
+        assert_eq!(parse_tree(&["-o", "tree.json"]).format(), TreeFormat::Json);
+        assert_eq!(
+            parse_tree(&["-o", "tree.json", "--format", "text"]).format(),
+            TreeFormat::Text
+        );
+
+        let tree = parse_tree(&["--status", "removed,renamed_from"]);
+        assert_eq!(
+            tree.status,
+            vec![NodeStatus::Removed, NodeStatus::RenamedFrom]
+        );
+    }
+
+    #[test]
+    fn test_parse_when() {
+        assert_eq!(
+            parse_when("2024-03-01T12:00:00Z").unwrap(),
+            "2024-03-01T12:00:00Z".parse::<Timestamp>().unwrap()
+        );
+        assert_eq!(
+            parse_when("2024-03-01").unwrap(),
+            "2024-03-01T00:00:00Z".parse::<Timestamp>().unwrap()
+        );
+
+        let now = Timestamp::now();
+        for span in ["2w", "14 days", "P2W", "2 weeks ago"] {
+            let ago = now.duration_since(parse_when(span).unwrap()).as_secs();
+            assert!(
+                (14 * 86400 - 5..=14 * 86400 + 3700).contains(&ago),
+                "{span}: {ago}"
+            );
+        }
+
+        assert!(parse_when("last tuesday").is_err());
+    }
+
+    #[test]
+    fn test_dump_time_window() {
+        let dump = parse_dump(&[]).unwrap();
+        assert!(
+            time_window(dump.pull_days, dump.since, dump.until)
+                .0
+                .is_some(),
+            "--days defaults to 90"
+        );
+        assert!(
+            time_window(dump.pull_days, dump.since, dump.until)
+                .1
+                .is_none()
+        );
+
+        let dump = parse_dump(&["--until", "2024-01-01"]).unwrap();
+        assert_eq!(
+            time_window(dump.pull_days, dump.since, dump.until),
+            (
+                None,
+                Some("2024-01-01T00:00:00Z".parse::<Timestamp>().unwrap().into())
+            )
+        );
+
+        let dump = parse_dump(&["--since", "2024-01-02", "--until", "2024-01-01"]).unwrap();
+        assert!(dump.validate(0).is_err());
+
+        assert!(parse_dump(&["--days", "5", "--since", "2024-01-01"]).is_err());
+    }
+
+    #[test]
+    fn test_real_files_time_window() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("00000000000000aa"), b"").unwrap();
+        std::fs::write(dir.join("not-hex"), b"").unwrap();
+
+        let dir_arg = dir.to_string_lossy().into_owned();
+        let file_arg = dir.join("00000000000000aa").to_string_lossy().into_owned();
+
+        let dump = parse_dump(&["--since", "1d", &dir_arg]).unwrap();
+        assert_eq!(dump.real_files().len(), 1);
+
+        let dump = parse_dump(&["--until", "2000-01-01", &dir_arg]).unwrap();
+        assert!(dump.real_files().is_empty());
+
+        // Explicit files ignore --days but not an explicit window
+        let dump = parse_dump(&["--days", "1", &file_arg]).unwrap();
+        assert_eq!(dump.real_files().len(), 1);
+        let dump = parse_dump(&["--until", "2000-01-01", &file_arg]).unwrap();
+        assert!(dump.real_files().is_empty());
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("1234").unwrap(), 1234);
+        assert_eq!(parse_id("0x342c4f2").unwrap(), 0x342_c4f2);
+        assert_eq!(parse_id(" 0XFF ").unwrap(), 255);
+        assert!(parse_id("ff").is_err());
+        assert!(parse_id("0x").is_err());
+        assert!(parse_id("-1").is_err());
+    }
+
+    #[test]
+    fn test_load_ids() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("ids.txt");
+        std::fs::write(&path, "# inodes\n1, 2 0x10\n\n3 # trailing\n").unwrap();
+        assert_eq!(load_ids(&path).unwrap(), BTreeSet::from([1, 2, 3, 16]));
+
+        std::fs::write(&path, "1\nnope\n").unwrap();
+        let err = load_ids(&path).unwrap_err().to_string();
+        assert!(err.contains("line 2"), "{err}");
+    }
+
+    #[test]
+    fn test_dump_validate_routes_only() {
+        let dump = Dump {
+            csvs: false,
+            jsons: false,
+            yamls: false,
+            csv: None,
+            json: None,
+            yaml: None,
+            uniques: None,
+            unique_timestamps: false,
+            uniques_depth: None,
+            uniques_by_dir: false,
+            unique_details: vec![],
+            uniques_format: None,
+            uniques_memory: None,
+            uniques_bucket: None,
+            pull_days: 90,
+            since: None,
+            until: None,
+            files: vec![],
+            compress_opts: CompressOpts {
+                glevel: 7,
+                #[cfg(feature = "zstd")]
+                zlevel: 10,
+                #[cfg(feature = "zstd")]
+                zthreads: 2,
+                gzip: false,
+                #[cfg(feature = "zstd")]
+                zstd: false,
+            },
+            csv_opts: CsvOpts::default(),
+            split_opts: SplitOpts::default(),
+            sort_opts: SortOpts::default(),
+            route_opts: RouteOpts {
+                routes: vec![RouteSpec::parse("rm:rm.csv:flags has Removed").unwrap()],
+                routes_file: None,
+            },
+            alert_opts: AlertOpts::default(),
+            burst_opts: BurstOpts::default(),
+            lifecycle_opts: LifecycleOpts::default(),
+            histogram_opts: HistogramOpts::default(),
+            filter_opts: FilterOpts {
+                filter_paths: None,
+                any_flags: vec![],
+                all_flags: vec![],
+                where_expr: None,
+                exclude_paths: vec![],
+                exclude_flags: vec![],
+                noise_profiles: vec![],
+                ioc_files: vec![],
+                ioc_keep_all: false,
+                min_event_id: None,
+                max_event_id: None,
+                event_ids: None,
+                node_ids: None,
+                ignore_case: false,
+                normalize: None,
+                emit_normalized: false,
+                path_encoding: PathEncoding::Lossy,
+                derived_fields: false,
+                canonicalize: false,
+                path_maps: vec![],
+                classify: false,
+                artifact_rules: vec![],
+                categories: vec![],
+            },
+        };
+
+        assert!(dump.validate(0).is_ok(), "A route is an output");
+        assert!(dump.route_opts.routes(PathOpts::default()).is_ok());
+
+        let bad = RouteOpts {
+            routes: vec![RouteSpec::parse("rm:rm.csv:any=Removed").unwrap()],
+            routes_file: None,
+        };
+        assert!(
+            bad.routes(PathOpts::default()).is_err(),
+            "The old filter syntax isn't an expression"
+        );
+    }
+
+    #[test]
+    fn test_route_opts_duplicate_names() {
+        let opts = RouteOpts {
+            routes: vec![
+                RouteSpec::parse("a:a.csv").unwrap(),
+                RouteSpec::parse("a:b.json").unwrap(),
+            ],
+            routes_file: None,
+        };
+        assert!(opts.routes(PathOpts::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_delimiter() {
+        assert_eq!(parse_delimiter(",").unwrap(), b',');
+        assert_eq!(parse_delimiter(";").unwrap(), b';');
+        assert_eq!(parse_delimiter("tab").unwrap(), b'\t');
+        assert_eq!(parse_delimiter("\\t").unwrap(), b'\t');
+        assert!(parse_delimiter(";;").is_err());
+        assert!(parse_delimiter("é").is_err());
+    }
+
     #[cfg(feature = "zstd")]
     #[test]
     fn test_compress_opts_zlvl() {
@@ -0,0 +1,303 @@
+//! Format agnostic record writers
+//!
+//! This module provides a `RecordWriter` that writes records (or anything else that serializes
+//! like them, such as alerts) in any of the supported output formats so outputs that are created
+//! on the fly (like split chunks) don't need a writer function per format.
+
+use std::{
+    cell::Cell,
+    io::{self, BufWriter, Write},
+    rc::Rc,
+};
+
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::opts::{CsvOpts, Format};
+
+/// Writes records to an underlying writer in the wanted format
+pub enum RecordWriter<W>
+where
+    W: Write,
+{
+    Csv(Box<csv::Writer<W>>),
+    Json { writer: W, pretty: bool },
+    Yaml(W),
+}
+
+impl<W> RecordWriter<W>
+where
+    W: Write,
+{
+    /// Creates a new record writer
+    ///
+    /// # Arguments
+    /// * `format` - The format the records should be written as
+    /// * `writer` - The writer to output the data to
+    /// * `csv_opts` - The dialect to use if the format is csv
+    /// * `pretty` - Whether json should be pretty formatted (multi-line)
+    pub fn new(format: Format, writer: W, csv_opts: &CsvOpts, pretty: bool) -> Self {
+        match format {
+            Format::Csv => RecordWriter::Csv(Box::new(csv_opts.writer(writer))),
+            Format::Json => RecordWriter::Json { writer, pretty },
+            Format::Yaml => RecordWriter::Yaml(writer),
+        }
+    }
+
+    /// Writes a single record
+    pub fn write<T: Serialize>(&mut self, rec: &T) -> Result<()> {
+        match self {
+            RecordWriter::Csv(w) => w.serialize(rec)?,
+            RecordWriter::Json { writer, pretty } => {
+                if *pretty {
+                    serde_json::to_writer_pretty(&mut *writer, rec)?;
+                } else {
+                    serde_json::to_writer(&mut *writer, rec)?;
+                }
+                writeln!(writer)?;
+            }
+            RecordWriter::Yaml(w) => {
+                writeln!(w, "---")?;
+                serde_yaml::to_writer(&mut *w, rec)?;
+                writeln!(w)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RecordWriter::Csv(w) => w.flush(),
+            RecordWriter::Json { writer, .. } => writer.flush(),
+            RecordWriter::Yaml(w) => w.flush(),
+        }
+    }
+
+    /// Flushes the records and returns the underlying writer
+    pub fn into_inner(self) -> io::Result<W> {
+        match self {
+            RecordWriter::Csv(w) => w.into_inner().map_err(|err| err.into_error()),
+            RecordWriter::Json { mut writer, .. } | RecordWriter::Yaml(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+        }
+    }
+}
+
+/// A writer whose output has to be finished (like the end of a compression frame) once it's
+/// done, so the errors doing that can be seen instead of being lost when it's dropped
+pub trait FinishWrite: Write {
+    /// Writes out everything that's left, finishing the compression frame
+    fn close(&mut self) -> io::Result<()>;
+}
+
+impl<W> FinishWrite for Box<W>
+where
+    W: FinishWrite + ?Sized,
+{
+    fn close(&mut self) -> io::Result<()> {
+        (**self).close()
+    }
+}
+
+impl<W> FinishWrite for BufWriter<W>
+where
+    W: Write,
+{
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl<W> FinishWrite for flate2::write::GzEncoder<W>
+where
+    W: Write,
+{
+    fn close(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<W> FinishWrite for zstd::stream::write::Encoder<'_, W>
+where
+    W: Write,
+{
+    fn close(&mut self) -> io::Result<()> {
+        self.do_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+/// Ignores flushes, so writers above it can empty their buffers into it without the output
+/// being flushed (and a compression frame sync flushed) every time; the output is only written
+/// out once it's closed
+pub struct NoFlush<W>(pub W)
+where
+    W: FinishWrite;
+
+impl<W> Write for NoFlush<W>
+where
+    W: FinishWrite,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W> FinishWrite for NoFlush<W>
+where
+    W: FinishWrite,
+{
+    fn close(&mut self) -> io::Result<()> {
+        self.0.close()
+    }
+}
+
+/// Finishes the writer when it's dropped, logging any error
+pub struct FinishOnDrop<W>(pub W)
+where
+    W: FinishWrite;
+
+impl<W> Write for FinishOnDrop<W>
+where
+    W: FinishWrite,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W> Drop for FinishOnDrop<W>
+where
+    W: FinishWrite,
+{
+    fn drop(&mut self) {
+        if let Err(err) = self.0.close() {
+            error!("Couldn't finish writing an output: {err}");
+        }
+    }
+}
+
+/// Wraps a writer and keeps a running total of the bytes written through it
+pub struct CountingWriter<W> {
+    inner: W,
+    count: Rc<Cell<u64>>,
+}
+
+impl<W> CountingWriter<W> {
+    /// Wraps the writer, returning it along with a handle to the byte count
+    pub fn new(inner: W) -> (Self, Rc<Cell<u64>>) {
+        let count = Rc::new(Cell::new(0));
+        (
+            Self {
+                inner,
+                count: count.clone(),
+            },
+            count,
+        )
+    }
+
+    /// Returns the wrapped writer
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> Write for CountingWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Record;
+
+    #[test]
+    fn test_json_writer() {
+        let mut w = RecordWriter::new(Format::Json, vec![], &CsvOpts::default(), false);
+        w.write(&Record::at("/a").with_event_id(1)).unwrap();
+        w.write(&Record::at("/b").with_event_id(2)).unwrap();
+
+        let RecordWriter::Json { writer, .. } = w else {
+            panic!("Should be a json writer")
+        };
+        let out = String::from_utf8(writer).unwrap();
+        assert_eq!(out.lines().count(), 2);
+        assert!(out.lines().next().unwrap().contains("\"/a\""));
+    }
+
+    #[test]
+    fn test_yaml_writer() {
+        let mut w = RecordWriter::new(Format::Yaml, vec![], &CsvOpts::default(), false);
+        w.write(&Record::at("/a").with_event_id(1)).unwrap();
+
+        let RecordWriter::Yaml(writer) = w else {
+            panic!("Should be a yaml writer")
+        };
+        let out = String::from_utf8(writer).unwrap();
+        assert!(out.starts_with("---\npath: /a\n"));
+    }
+
+    #[test]
+    fn test_csv_writer_header() {
+        let mut w = RecordWriter::new(Format::Csv, vec![], &CsvOpts::default(), false);
+        w.write(&Record::at("/a").with_event_id(1)).unwrap();
+        w.write(&Record::at("/b").with_event_id(2)).unwrap();
+
+        let RecordWriter::Csv(writer) = w else {
+            panic!("Should be a csv writer")
+        };
+        let out = String::from_utf8((*writer).into_inner().unwrap()).unwrap();
+        assert_eq!(out.lines().count(), 3);
+        assert!(out.starts_with("path,event_id,flags"));
+    }
+
+    #[test]
+    fn test_counting_writer() {
+        let (mut w, count) = CountingWriter::new(vec![]);
+        w.write_all(b"hello").unwrap();
+        w.write_all(b" world").unwrap();
+
+        assert_eq!(count.get(), 11);
+        assert_eq!(w.inner, b"hello world");
+    }
+
+    #[test]
+    fn test_finish_write() {
+        use std::io::Read;
+
+        // The gzip stream is complete once it's closed, without being dropped
+        let mut w = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        w.write_all(b"hello").unwrap();
+        w.close().unwrap();
+
+        let mut out = String::new();
+        flate2::read::GzDecoder::new(w.get_ref().as_slice())
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "hello");
+    }
+}
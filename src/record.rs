@@ -1,21 +1,37 @@
 //! File system event record structures and filtering
 //!
 //! This module defines the `Record` structure representing individual FSEvents
-//! and the `RecordFilter` for selectively processing records based on path patterns
-//! and flag criteria.
+//! and the `RecordFilter` for selectively processing records based on path patterns,
+//! flag criteria and `--where` expressions.
 
+use std::{borrow::Cow, collections::BTreeSet, path::Path, sync::Arc};
+
+use base64::prelude::*;
 use color_eyre::eyre;
 use jiff::Timestamp;
 use regex::Regex;
 #[cfg(feature = "hex")]
 use serde_hex::{CompactCapPfx, SerHex, SerHexOpt};
 
-use crate::flags;
+use crate::{
+    artifact::Classifier,
+    canonical::Canonicalizer,
+    derived,
+    expr::{CmpOp, Expr, NumField, PathMatch},
+    flags,
+    ioc::IocSet,
+    noise::Exclusion,
+    normalize::PathOpts,
+};
 
 /// Represents a file system event record from macOS fseventsd
 #[derive(Clone, Debug, Default, Serialize)]
 pub struct Record {
+    /// The path (invalid UTF-8 is replaced or escaped depending on the `PathEncoding`)
     pub path: String,
+    /// The raw path bytes (only kept when they weren't valid UTF-8)
+    #[serde(skip_serializing)]
+    pub raw_path: Option<Box<[u8]>>,
     #[cfg_attr(feature = "hex", serde(with = "SerHex::<CompactCapPfx>"))]
     pub event_id: u64,
     #[serde(skip_serializing)]
@@ -30,10 +46,202 @@ pub struct Record {
     pub extra_id: Option<u32>,
     #[serde(serialize_with = "serialize_optional_timestamp")]
     pub file_timestamp: Option<Timestamp>,
+    /// If the path wasn't valid UTF-8 so `path` doesn't hold the original bytes
+    pub path_lossy: bool,
+    /// The base64 encoded raw bytes of lossy paths (only present with `PathEncoding::Base64`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_base64: Option<String>,
+    /// The path with the firmlinks and other aliases resolved (only present with
+    /// `--canonicalize`); filters and uniques use it instead of `path`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_path: Option<String>,
+    /// The fields derived from the path (only present with `--derived-fields`; the ones a path
+    /// doesn't have are empty)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ext: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub home_user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<String>,
+    /// The category and name of the artifact the path is (only present with artifact
+    /// classification; empty for the paths that aren't known artifacts)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_category: Option<Arc<str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_name: Option<Arc<str>>,
+    /// The ids of the IOCs the path matched (only present when IOC matching is enabled)
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_ioc_ids"
+    )]
+    pub ioc_ids: Option<Vec<Arc<str>>>,
+    /// The fsevents file the record was parsed from
+    #[serde(skip_serializing)]
+    pub source: Option<Arc<Path>>,
+}
+
+impl Record {
+    /// Decodes the raw path bytes, keeping the raw bytes if they aren't valid UTF-8
+    ///
+    /// # Returns
+    /// The (lossy) path, the raw bytes if needed, and if the path was lossy
+    pub fn decode_path(bytes: &[u8]) -> (String, Option<Box<[u8]>>, bool) {
+        match std::str::from_utf8(bytes) {
+            Ok(path) => (path.to_string(), None, false),
+            Err(_) => (
+                String::from_utf8_lossy(bytes).into_owned(),
+                Some(bytes.into()),
+                true,
+            ),
+        }
+    }
+
+    /// The path filters and aggregations use (the canonical path if there is one)
+    #[inline]
+    pub fn key_path(&self) -> &str {
+        self.canonical_path.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Fills in the fields derived from the (canonical) path
+    pub fn derive_fields(&mut self) {
+        let path = self.key_path();
+        let owned = |v: Option<&str>| Some(v.unwrap_or_default().to_string());
+
+        let dir = Some(derived::dir(path).to_string());
+        let name = Some(derived::name(path).to_string());
+        let ext = owned(derived::ext(path));
+        let depth = Some(derived::depth(path));
+        let home_user = owned(derived::home_user(path));
+        let volume = owned(derived::volume(path));
+
+        self.dir = dir;
+        self.name = name;
+        self.ext = ext;
+        self.depth = depth;
+        self.home_user = home_user;
+        self.volume = volume;
+    }
+
+    /// If the derived fields were filled in
+    pub fn has_derived_fields(&self) -> bool {
+        self.depth.is_some()
+    }
+
+    /// Rewrites a lossy path with the encoding
+    pub fn encode_path(&mut self, encoding: PathEncoding) {
+        match encoding {
+            PathEncoding::Lossy => {}
+            PathEncoding::Escape => {
+                if let Some(raw) = &self.raw_path {
+                    self.path = escape_path(raw);
+                }
+            }
+            PathEncoding::Base64 => {
+                self.path_base64 = Some(
+                    self.raw_path
+                        .as_deref()
+                        .map(|raw| BASE64_STANDARD.encode(raw))
+                        .unwrap_or_default(),
+                );
+            }
+        }
+    }
+}
+
+/// Builders for the records the tests need, so each test only sets the fields it cares about
+#[cfg(test)]
+impl Record {
+    /// A record for the path with every other field left empty
+    pub fn at(path: impl Into<String>) -> Self {
+        Record {
+            path: path.into(),
+            ..Record::default()
+        }
+    }
+
+    /// A record for the event at the path with the flags given by name
+    pub fn event(path: impl Into<String>, event_id: u64, flag_names: &[&str]) -> Self {
+        Self::at(path)
+            .with_event_id(event_id)
+            .with_flags(flag_names)
+    }
+
+    pub fn with_event_id(mut self, event_id: u64) -> Self {
+        self.event_id = event_id;
+        self
+    }
+
+    /// Sets the flag bits
+    pub fn with_flag(mut self, flag: u32) -> Self {
+        self.flag = flag;
+        self
+    }
+
+    /// Sets the flag bits from the flag names
+    pub fn with_flags(self, names: &[&str]) -> Self {
+        let flag = names
+            .iter()
+            .map(|name| flags::flag_id(name).expect("unknown flag name"))
+            .fold(0, |a, b| a | b);
+        self.with_flag(flag)
+    }
+
+    pub fn with_node_id(mut self, node_id: Option<u64>) -> Self {
+        self.node_id = node_id;
+        self
+    }
+
+    pub fn with_timestamp(mut self, file_timestamp: Option<Timestamp>) -> Self {
+        self.file_timestamp = file_timestamp;
+        self
+    }
+
+    /// Sets the source file the record was parsed from
+    pub fn with_source(mut self, source: impl AsRef<Path>) -> Self {
+        self.source = Some(Arc::from(source.as_ref()));
+        self
+    }
+}
+
+/// How paths that aren't valid UTF-8 are written
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum PathEncoding {
+    /// Replace the invalid bytes with U+FFFD
+    #[default]
+    Lossy,
+    /// Escape the invalid bytes as `\xNN` (and `\` as `\\`) in lossy paths
+    Escape,
+    /// Add a `path_base64` column with the raw bytes of lossy paths
+    Base64,
+}
+
+/// Escapes the invalid UTF-8 bytes as `\xNN` and backslashes as `\\` so the bytes can be
+/// recovered
+pub fn escape_path(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() + 8);
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '\\' {
+                out.push_str("\\\\");
+            } else {
+                out.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{b:02X}"));
+        }
+    }
+    out
 }
 
 /// Custom serializer for `Option<Timestamp>` to produce ISO 8601 format
-fn serialize_optional_timestamp<S>(
+pub fn serialize_optional_timestamp<S>(
     timestamp: &Option<Timestamp>,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
@@ -46,12 +254,76 @@ where
     }
 }
 
-/// Filter for selecting which records to process based on path patterns and flags
+/// Serializes the matched IOC ids as a single `|` separated string (so csv can hold it)
+fn serialize_ioc_ids<S>(ids: &Option<Vec<Arc<str>>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match ids {
+        Some(ids) => serializer.serialize_str(&ids.join(" | ")),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Filter for selecting which records to process
+///
+/// Every filter option is compiled into a single `Expr` which is evaluated for each record
 #[derive(Clone, Debug, Default)]
 pub struct RecordFilter {
-    pub path_rex: Option<Regex>,
-    pub any_flag: u32,
-    pub all_flag: u32,
+    pub expr: Expr,
+    /// Rules dropping records that otherwise match (checked in order)
+    pub exclusions: Vec<Exclusion>,
+    /// The IOCs records are tagged with (only the tagged records are wanted unless `ioc_keep_all`)
+    pub ioc: Option<Arc<IocSet>>,
+    pub ioc_keep_all: bool,
+    /// The event id limits used to skip whole files
+    pub event_bounds: EventIdBounds,
+    /// How the paths are matched (case and Unicode normalization)
+    pub path_opts: PathOpts,
+    /// How paths that aren't valid UTF-8 are written
+    pub path_encoding: PathEncoding,
+    /// If the record paths should be replaced by their normalized form
+    pub emit_normalized: bool,
+    /// If the fields derived from the path should be added to the records
+    pub derived_fields: bool,
+    /// Resolves the path aliases into the `canonical_path`
+    pub canon: Option<Arc<Canonicalizer>>,
+    /// Labels the records with the artifact they touch
+    pub artifacts: Option<Arc<Classifier>>,
+    /// The artifact categories wanted (all of them if empty)
+    pub categories: Vec<Arc<str>>,
+}
+
+/// The event id limits of a filter
+///
+/// Every fsevents file is named after the event id following the last one it holds so files
+/// that can't have a wanted event can be skipped without being decompressed
+#[derive(Clone, Debug, Default)]
+pub struct EventIdBounds {
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+    pub ids: Option<Arc<BTreeSet<u64>>>,
+}
+
+impl EventIdBounds {
+    /// If there are any limits
+    pub fn is_set(&self) -> bool {
+        self.min.is_some() || self.max.is_some() || self.ids.is_some()
+    }
+
+    /// If a file holding events with ids between `lo` (if known) and `hi` could have a wanted
+    /// event (both ends are inclusive)
+    pub fn may_match(&self, lo: Option<u64>, hi: u64) -> bool {
+        let lo = lo.unwrap_or(0);
+
+        if self.min.is_some_and(|min| hi < min) || self.max.is_some_and(|max| lo > max) {
+            return false;
+        }
+
+        self.ids
+            .as_ref()
+            .is_none_or(|ids| ids.range(lo..=hi).next().is_some())
+    }
 }
 
 impl RecordFilter {
@@ -79,16 +351,260 @@ impl RecordFilter {
                 flags::flag_id(flag).ok_or_else(|| eyre::eyre!("Unknown all flag id: {flag}"))?;
         }
 
+        let mut terms = vec![];
+        if any_flag > 0 {
+            terms.push(Expr::Flags {
+                mask: any_flag,
+                all: false,
+            });
+        }
+        if all_flag > 0 {
+            terms.push(Expr::Flags {
+                mask: all_flag,
+                all: true,
+            });
+        }
+        if let Some(pat) = pat {
+            terms.push(Expr::Path(PathMatch::Regex(
+                Regex::new(pat).map_err(|e| eyre::eyre!("Invalid pattern: {e}"))?,
+            )));
+        }
+
         Ok(Self {
-            path_rex: pat
-                .as_ref()
-                .map(|pat| Regex::new(pat).map_err(|e| eyre::eyre!("Invalid pattern: {e}")))
-                .transpose()?,
-            any_flag,
-            all_flag,
+            expr: Expr::and(terms),
+            ..Self::default()
+        })
+    }
+
+    /// Adds a `--where` expression that records also have to match
+    pub fn with_where(self, expr: Option<&str>) -> color_eyre::Result<Self> {
+        Ok(match expr {
+            None => self,
+            Some(expr) => Self {
+                expr: Expr::and(vec![
+                    self.expr,
+                    Expr::parse(expr)?.with_path_opts(self.path_opts)?,
+                ]),
+                ..self
+            },
+        })
+    }
+
+    /// Adds exclusions that drop the records they match
+    pub fn with_exclusions(mut self, exclusions: Vec<Exclusion>) -> color_eyre::Result<Self> {
+        for ex in exclusions {
+            self.exclusions.push(ex.with_path_opts(self.path_opts)?);
+        }
+        Ok(self)
+    }
+
+    /// Sets how paths are matched, recompiling the path matches added so far (the ones added
+    /// later use them too)
+    ///
+    /// # Arguments
+    /// * `path_opts` - The case and Unicode normalization options
+    /// * `emit_normalized` - If the record paths should be replaced by their normalized form
+    pub fn with_path_opts(
+        self,
+        path_opts: PathOpts,
+        emit_normalized: bool,
+    ) -> color_eyre::Result<Self> {
+        Ok(Self {
+            expr: self.expr.with_path_opts(path_opts)?,
+            exclusions: self
+                .exclusions
+                .into_iter()
+                .map(|ex| ex.with_path_opts(path_opts))
+                .collect::<color_eyre::Result<_>>()?,
+            path_opts,
+            emit_normalized,
+            ..self
+        })
+    }
+
+    /// Limits the records to event and node id ranges or lists
+    ///
+    /// # Arguments
+    /// * `min_event_id` - The lowest wanted event id
+    /// * `max_event_id` - The highest wanted event id
+    /// * `event_ids` - The only wanted event ids
+    /// * `node_ids` - The only wanted node ids
+    pub fn with_ids(
+        self,
+        min_event_id: Option<u64>,
+        max_event_id: Option<u64>,
+        event_ids: Option<BTreeSet<u64>>,
+        node_ids: Option<BTreeSet<u64>>,
+    ) -> Self {
+        let event_ids = event_ids.map(Arc::new);
+
+        let mut terms = vec![self.expr];
+        if let Some(min) = min_event_id {
+            terms.push(Expr::Num {
+                field: NumField::Event,
+                op: CmpOp::Ge,
+                value: min,
+            });
+        }
+        if let Some(max) = max_event_id {
+            terms.push(Expr::Num {
+                field: NumField::Event,
+                op: CmpOp::Le,
+                value: max,
+            });
+        }
+        if let Some(ids) = &event_ids {
+            terms.push(Expr::IdSet {
+                field: NumField::Event,
+                ids: ids.clone(),
+            });
+        }
+        if let Some(ids) = node_ids {
+            terms.push(Expr::IdSet {
+                field: NumField::Node,
+                ids: Arc::new(ids),
+            });
+        }
+
+        Self {
+            expr: Expr::and(terms),
+            event_bounds: EventIdBounds {
+                min: min_event_id,
+                max: max_event_id,
+                ids: event_ids,
+            },
+            ..self
+        }
+    }
+
+    /// Limits the records to the ones from files modified in the (inclusive) time window
+    ///
+    /// Records without a file timestamp are kept, like the files without a mod time are
+    pub fn with_time_window(self, since: Option<Timestamp>, until: Option<Timestamp>) -> Self {
+        let mut terms = vec![self.expr];
+        if let Some(since) = since {
+            terms.push(Expr::Not(Box::new(Expr::Time {
+                op: CmpOp::Lt,
+                value: since,
+            })));
+        }
+        if let Some(until) = until {
+            terms.push(Expr::Not(Box::new(Expr::Time {
+                op: CmpOp::Gt,
+                value: until,
+            })));
+        }
+
+        Self {
+            expr: Expr::and(terms),
+            ..self
+        }
+    }
+
+    /// Sets how paths that aren't valid UTF-8 are written
+    pub fn with_path_encoding(self, path_encoding: PathEncoding) -> Self {
+        Self {
+            path_encoding,
+            ..self
+        }
+    }
+
+    /// Adds the canonicalization stage filling in the `canonical_path`
+    pub fn with_canonicalizer(self, canon: Canonicalizer) -> Self {
+        Self {
+            canon: Some(Arc::new(canon)),
+            ..self
+        }
+    }
+
+    /// Sets if the fields derived from the path should be added to the records
+    pub fn with_derived_fields(self, derived_fields: bool) -> Self {
+        Self {
+            derived_fields,
+            ..self
+        }
+    }
+
+    /// Adds the artifact classification
+    ///
+    /// # Arguments
+    /// * `artifacts` - The compiled artifact rules
+    /// * `categories` - The categories wanted (every record is wanted if empty)
+    pub fn with_artifacts(
+        self,
+        artifacts: Classifier,
+        categories: &[String],
+    ) -> eyre::Result<Self> {
+        let known = artifacts.categories();
+        for category in categories {
+            if !known.contains(category.as_str()) {
+                return Err(eyre::eyre!(
+                    "Unknown artifact category '{category}' (expected one of: {})",
+                    known.into_iter().collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+
+        Ok(Self {
+            artifacts: Some(Arc::new(artifacts)),
+            categories: categories.iter().map(|c| Arc::from(c.as_str())).collect(),
+            ..self
         })
     }
 
+    /// Adds the IOCs records should be tagged with
+    ///
+    /// # Arguments
+    /// * `ioc` - The compiled indicators
+    /// * `keep_all` - If the records that don't match any indicator should still be wanted
+    pub fn with_iocs(self, ioc: IocSet, keep_all: bool) -> Self {
+        Self {
+            ioc: Some(Arc::new(ioc)),
+            ioc_keep_all: keep_all,
+            ..self
+        }
+    }
+
+    /// Encodes, canonicalizes and normalizes the path, derives the path fields, tags the record
+    /// with any matching IOCs, and then checks if it's wanted
+    #[inline]
+    pub fn process(&self, rec: &mut Record) -> bool {
+        rec.encode_path(self.path_encoding);
+
+        if let Some(canon) = &self.canon {
+            rec.canonical_path = Some(canon.canonicalize(&rec.path));
+        }
+
+        if self.emit_normalized {
+            if let Cow::Owned(path) = self.path_opts.normalize(&rec.path) {
+                rec.path = path;
+            }
+            if let Some(canonical) = &rec.canonical_path
+                && let Cow::Owned(path) = self.path_opts.normalize(canonical)
+            {
+                rec.canonical_path = Some(path);
+            }
+        }
+
+        if self.derived_fields {
+            rec.derive_fields();
+        }
+
+        if let Some(artifacts) = &self.artifacts {
+            let (category, name) = artifacts
+                .classify(&self.path_opts.normalize(rec.key_path()))
+                .unwrap_or((artifacts.none(), artifacts.none()));
+            rec.artifact_category = Some(category.clone());
+            rec.artifact_name = Some(name.clone());
+        }
+
+        if let Some(ioc) = &self.ioc {
+            rec.ioc_ids = Some(ioc.matches(&self.path_opts.normalize(rec.key_path())));
+        }
+
+        self.want(rec)
+    }
+
     /// Determines if a record should be included based on the filter criteria
     ///
     /// # Arguments
@@ -98,26 +614,41 @@ impl RecordFilter {
     /// `true` if the record matches all filter criteria, `false` otherwise
     #[inline]
     pub fn want(&self, rec: &Record) -> bool {
-        self.match_flags(rec, self.any_flag, true)
-            && self.match_flags(rec, self.all_flag, false)
-            && self.match_path(rec)
+        let path = self.path_opts.normalize(rec.key_path());
+        self.expr.eval_path(rec, &path)
+            && self.match_ioc(rec)
+            && self.match_category(rec)
+            && !self
+                .exclusions
+                .iter()
+                .any(|ex| ex.excludes_path(rec, &path))
     }
 
     #[inline]
-    fn match_path(&self, rec: &Record) -> bool {
-        self.path_rex
-            .as_ref()
-            .map(|rex| rex.is_match(&rec.path))
-            .unwrap_or(true)
+    fn match_ioc(&self, rec: &Record) -> bool {
+        self.ioc.is_none()
+            || self.ioc_keep_all
+            || rec.ioc_ids.as_ref().is_some_and(|ids| !ids.is_empty())
     }
 
     #[inline]
-    fn match_flags(&self, rec: &Record, flags: u32, any: bool) -> bool {
-        if flags > 0 {
-            let diff = flags & rec.flag;
-            if any { diff > 0 } else { diff == flags }
-        } else {
-            true
+    fn match_category(&self, rec: &Record) -> bool {
+        self.categories.is_empty()
+            || rec
+                .artifact_category
+                .as_ref()
+                .is_some_and(|c| self.categories.contains(c))
+    }
+
+    /// Logs how many records each exclusion dropped (to stderr even if the logger was quieted)
+    pub fn log_exclusions(&self) {
+        for ex in self.exclusions.iter() {
+            summary!(
+                log::Level::Info,
+                "Exclusion '{}' dropped {} records",
+                ex.name,
+                ex.dropped()
+            );
         }
     }
 }
@@ -131,16 +662,15 @@ mod tests {
     fn make_record(path: &str, flag_bits: u32) -> Record {
         let flag_strs = flags::parse_bits(flag_bits);
         Record {
-            path: path.to_string(),
-            event_id: 12345,
-            flag: flag_bits,
             flags: flag_strs.norm,
             #[cfg(feature = "alt_flags")]
             alt_flags: flag_strs.alt,
-            node_id: Some(67890),
             #[cfg(feature = "extra_id")]
             extra_id: Some(42),
-            file_timestamp: None,
+            ..Record::at(path)
+                .with_event_id(12345)
+                .with_flag(flag_bits)
+                .with_node_id(Some(67890))
         }
     }
 
@@ -375,33 +905,326 @@ mod tests {
     }
 
     #[test]
-    fn test_match_flags_any_with_zero() {
-        let filter = RecordFilter {
-            path_rex: None,
-            any_flag: 0,
-            all_flag: 0,
-        };
+    fn test_filter_empty_flags_compile_to_match_all() {
+        let filter = RecordFilter::new(&None, &[], &[]).unwrap();
+        assert!(matches!(filter.expr, Expr::True));
 
         let rec = make_record("/test", 0x1000_0000);
+        assert!(filter.want(&rec), "No flags should always match");
+    }
+
+    #[test]
+    fn test_filter_any_and_all_flags_together() {
+        let filter = RecordFilter::new(
+            &None,
+            &["Created".to_string(), "Removed".to_string()],
+            &["FileEvent".to_string()],
+        )
+        .unwrap();
+
+        assert!(filter.want(&make_record("/a", 0x0100_8000)));
+        assert!(!filter.want(&make_record("/a", 0x0100_0001)));
+        assert!(!filter.want(&make_record("/a", 0x1000_8000)));
+    }
+
+    #[test]
+    fn test_filter_with_where() {
+        let filter = RecordFilter::new(&Some("^/Users/".to_string()), &[], &[])
+            .unwrap()
+            .with_where(Some("flags has Modified or node_id == 1"))
+            .unwrap();
+
+        assert!(filter.want(&make_record("/Users/a", 0x1000_0000)));
+        assert!(!filter.want(&make_record("/Users/a", 0x0100_0000)));
+        assert!(!filter.want(&make_record("/tmp/a", 0x1000_0000)));
+
         assert!(
-            filter.match_flags(&rec, 0, true),
-            "Zero flags with 'any' should always match"
+            RecordFilter::default()
+                .with_where(Some("flags has Nope"))
+                .is_err()
         );
     }
 
     #[test]
-    fn test_match_flags_all_with_zero() {
-        let filter = RecordFilter {
-            path_rex: None,
-            any_flag: 0,
-            all_flag: 0,
+    fn test_filter_process_tags_iocs() {
+        let mut builder = crate::ioc::IocSetBuilder::default();
+        builder
+            .add("evil", crate::ioc::IocKind::Name, "evil.dylib")
+            .unwrap();
+        let iocs = builder.build().unwrap();
+
+        let filter = RecordFilter::default().with_iocs(iocs, false);
+        let mut hit = make_record("/usr/lib/evil.dylib", 0x1000_0000);
+        let mut miss = make_record("/usr/lib/good.dylib", 0x1000_0000);
+        assert!(filter.process(&mut hit));
+        assert!(!filter.process(&mut miss));
+        assert_eq!(hit.ioc_ids, Some(vec![Arc::from("evil")]));
+        assert_eq!(miss.ioc_ids, Some(vec![]));
+
+        let json = serde_json::to_string(&hit).unwrap();
+        assert!(json.contains(r#""ioc_ids":"evil""#));
+
+        let mut builder = crate::ioc::IocSetBuilder::default();
+        builder
+            .add("evil", crate::ioc::IocKind::Name, "evil.dylib")
+            .unwrap();
+        let keep_all = RecordFilter::default().with_iocs(builder.build().unwrap(), true);
+        assert!(keep_all.process(&mut miss), "Untagged records are kept");
+    }
+
+    #[test]
+    fn test_record_serialization_without_iocs() {
+        let rec = make_record("/test/path.txt", 0x1000_0000);
+        let json = serde_json::to_string(&rec).unwrap();
+        assert!(!json.contains("ioc_ids"));
+    }
+
+    #[test]
+    fn test_filter_with_ids() {
+        let filter = RecordFilter::default().with_ids(
+            Some(100),
+            Some(200),
+            None,
+            Some(BTreeSet::from([67890])),
+        );
+
+        let mut rec = make_record("/a", 0x1000_0000);
+        rec.event_id = 150;
+        assert!(filter.want(&rec));
+        rec.event_id = 201;
+        assert!(!filter.want(&rec));
+        rec.event_id = 100;
+        rec.node_id = Some(1);
+        assert!(!filter.want(&rec));
+
+        let filter =
+            RecordFilter::default().with_ids(None, None, Some(BTreeSet::from([5, 9])), None);
+        rec.event_id = 9;
+        assert!(filter.want(&rec));
+        rec.event_id = 7;
+        assert!(!filter.want(&rec));
+    }
+
+    #[test]
+    fn test_filter_with_time_window() {
+        let ts = |s: &str| s.parse::<Timestamp>().unwrap();
+        let filter = RecordFilter::default().with_time_window(
+            Some(ts("2024-01-01T00:00:00Z")),
+            Some(ts("2024-02-01T00:00:00Z")),
+        );
+
+        let mut rec = Record::default();
+        assert!(filter.want(&rec), "Records without a timestamp are kept");
+
+        rec.file_timestamp = Some(ts("2024-01-01T00:00:00Z"));
+        assert!(filter.want(&rec));
+        rec.file_timestamp = Some(ts("2024-02-01T00:00:00Z"));
+        assert!(filter.want(&rec));
+        rec.file_timestamp = Some(ts("2023-12-31T23:59:59Z"));
+        assert!(!filter.want(&rec));
+        rec.file_timestamp = Some(ts("2024-02-01T00:00:01Z"));
+        assert!(!filter.want(&rec));
+
+        let filter = RecordFilter::default().with_time_window(None, None);
+        assert!(matches!(filter.expr, Expr::True));
+    }
+
+    #[test]
+    fn test_filter_path_opts() {
+        use crate::normalize::NormForm;
+
+        let nfd = "Users/Jose\u{301}/Documents/a.txt";
+        let opts = PathOpts {
+            ignore_case: true,
+            normalize: Some(NormForm::Nfc),
         };
 
-        let rec = make_record("/test", 0x1000_0000);
+        // The options apply to the patterns added before and after them
+        let filter = RecordFilter::new(&Some("^users/josé/".into()), &[], &[])
+            .unwrap()
+            .with_path_opts(opts, false)
+            .unwrap()
+            .with_where(Some(r#"path suffix "DOCUMENTS/A.TXT""#))
+            .unwrap()
+            .with_exclusions(vec![Exclusion::path("glob:**/josé/tmp/*").unwrap()])
+            .unwrap();
+
+        let mut rec = make_record(nfd, 0);
+        assert!(filter.process(&mut rec));
+        assert_eq!(rec.path, nfd, "Paths are only rewritten when asked to");
+        assert!(!filter.want(&make_record("Users/Jose\u{301}/tmp/a.txt", 0)));
+
+        let exact = RecordFilter::new(&Some("^users/josé/".into()), &[], &[]).unwrap();
+        assert!(!exact.want(&rec));
+
+        let emit = RecordFilter::default().with_path_opts(opts, true).unwrap();
+        assert!(emit.process(&mut rec));
+        assert_eq!(rec.path, "Users/Jos\u{e9}/Documents/a.txt");
+    }
+
+    #[test]
+    fn test_escape_path() {
+        assert_eq!(escape_path(b"/a/b c"), "/a/b c");
+        assert_eq!(escape_path(b"/a\\b/\xff\xc3"), r"/a\\b/\xFF\xC3");
+        assert_eq!(escape_path("/é/ÿ".as_bytes()), "/é/ÿ");
+        assert_eq!(escape_path(b"/\xc3\xa9\xe9"), r"/é\xE9");
+    }
+
+    #[test]
+    fn test_encode_path() {
+        let raw = b"/tmp/\xffevil";
+        let (path, raw_path, lossy) = Record::decode_path(raw);
+        let rec = Record {
+            path,
+            path_lossy: lossy,
+            raw_path,
+            ..Record::default()
+        };
+
+        let mut lossy = rec.clone();
+        lossy.encode_path(PathEncoding::Lossy);
+        assert_eq!(lossy.path, "/tmp/\u{fffd}evil");
+        assert!(lossy.path_base64.is_none());
+
+        let mut escaped = rec.clone();
+        escaped.encode_path(PathEncoding::Escape);
+        assert_eq!(escaped.path, r"/tmp/\xFFevil");
+
+        let mut b64 = rec.clone();
+        b64.encode_path(PathEncoding::Base64);
+        assert_eq!(
+            BASE64_STANDARD.decode(b64.path_base64.unwrap()).unwrap(),
+            raw
+        );
+
+        // Valid paths are left alone but still get the (empty) base64 column
+        let mut valid = make_record("/tmp/a\\b", 0);
+        valid.encode_path(PathEncoding::Escape);
+        assert_eq!(valid.path, "/tmp/a\\b");
+        valid.encode_path(PathEncoding::Base64);
+        assert_eq!(valid.path_base64.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_filter_derived_fields() {
+        let filter = RecordFilter::default().with_derived_fields(true);
+
+        let mut rec = make_record("Volumes/Data/Users/x/a.tar.gz", 0);
+        assert!(filter.process(&mut rec));
+        assert_eq!(rec.dir.as_deref(), Some("Volumes/Data/Users/x"));
+        assert_eq!(rec.name.as_deref(), Some("a.tar.gz"));
+        assert_eq!(rec.ext.as_deref(), Some("gz"));
+        assert_eq!(rec.depth, Some(5));
+        assert_eq!(rec.home_user.as_deref(), Some(""));
+        assert_eq!(rec.volume.as_deref(), Some("Data"));
+
+        // Every record gets every column so the csv columns line up
+        let json = serde_json::to_value(&rec).unwrap();
+        assert_eq!(json["home_user"], "");
+        let json = serde_json::to_value(make_record("a", 0)).unwrap();
+        assert!(json.get("dir").is_none());
+    }
+
+    #[test]
+    fn test_filter_canonicalize() {
+        let filter = RecordFilter::default()
+            .with_canonicalizer(Canonicalizer::default())
+            .with_derived_fields(true)
+            .with_where(Some(r#"path prefix "Users/" and home_user == "bob""#))
+            .unwrap();
+
+        let mut rec = make_record("System/Volumes/Data/Users/bob/a.txt", 0);
+        assert!(filter.process(&mut rec));
+        assert_eq!(rec.path, "System/Volumes/Data/Users/bob/a.txt");
+        assert_eq!(rec.canonical_path.as_deref(), Some("Users/bob/a.txt"));
+        assert_eq!(rec.key_path(), "Users/bob/a.txt");
+        assert_eq!(rec.depth, Some(3));
+
+        let mut rec = make_record("System/Volumes/Data/Users/alice/a.txt", 0);
+        assert!(!filter.process(&mut rec));
+
+        // Without the stage the key is the path as recorded
+        let mut rec = make_record("System/Volumes/Data/Users/bob/a.txt", 0);
+        assert!(RecordFilter::default().process(&mut rec));
+        assert!(rec.canonical_path.is_none());
+        assert_eq!(rec.key_path(), rec.path);
+    }
+
+    #[test]
+    fn test_filter_canonical_depth() {
+        let filter = RecordFilter::default()
+            .with_canonicalizer(Canonicalizer::default())
+            .with_where(Some("depth == 3"))
+            .unwrap();
+
+        let mut rec = make_record("System/Volumes/Data/Users/bob/a.txt", 0);
+        assert!(filter.process(&mut rec));
+
+        let mut rec = make_record("Users/bob/a.txt", 0);
+        assert!(filter.process(&mut rec));
+
+        let mut rec = make_record("System/Volumes/Data/Users/bob/b/a.txt", 0);
+        assert!(!filter.process(&mut rec));
+    }
+
+    #[test]
+    fn test_filter_artifact_categories() {
+        let artifacts = || Classifier::load(&[] as &[&Path]).unwrap();
+        let filter = RecordFilter::default()
+            .with_artifacts(artifacts(), &["persistence".to_string()])
+            .unwrap();
+
+        let mut rec = make_record("Library/LaunchDaemons/com.evil.plist", 0);
+        assert!(filter.process(&mut rec));
+        assert_eq!(rec.artifact_category.as_deref(), Some("persistence"));
+        assert_eq!(rec.artifact_name.as_deref(), Some("launch-daemon"));
+
+        let mut rec = make_record("Users/bob/.ssh/id_rsa", 0);
+        assert!(!filter.process(&mut rec));
+        assert_eq!(rec.artifact_category.as_deref(), Some("credentials"));
+
+        // Without a category every record is wanted and the non-artifacts get empty labels
+        let filter = RecordFilter::default()
+            .with_artifacts(artifacts(), &[])
+            .unwrap();
+        let mut rec = make_record("Users/bob/a.txt", 0);
+        assert!(filter.process(&mut rec));
+        assert_eq!(rec.artifact_category.as_deref(), Some(""));
+
+        assert!(
+            RecordFilter::default()
+                .with_artifacts(artifacts(), &["nope".to_string()])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_event_bounds_may_match() {
+        let bounds = EventIdBounds {
+            min: Some(100),
+            max: Some(200),
+            ids: None,
+        };
+        assert!(bounds.is_set());
+        assert!(!bounds.may_match(Some(0), 99));
+        assert!(bounds.may_match(Some(0), 100));
         assert!(
-            filter.match_flags(&rec, 0, false),
-            "Zero flags with 'all' should always match"
+            bounds.may_match(None, 1000),
+            "Unknown start could hold anything"
         );
+        assert!(bounds.may_match(Some(200), 1000));
+        assert!(!bounds.may_match(Some(201), 1000));
+
+        let bounds = EventIdBounds {
+            ids: Some(Arc::new(BTreeSet::from([50, 500]))),
+            ..EventIdBounds::default()
+        };
+        assert!(bounds.may_match(Some(0), 50));
+        assert!(!bounds.may_match(Some(51), 499));
+        assert!(bounds.may_match(Some(51), 500));
+
+        assert!(!EventIdBounds::default().is_set());
+        assert!(EventIdBounds::default().may_match(Some(1), 2));
     }
 
     #[test]
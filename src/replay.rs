@@ -0,0 +1,99 @@
+//! Replays records in event id order
+//!
+//! The records in an fsevents file are grouped by path rather than ordered by event id, but
+//! every file holds a contiguous range of ids. The files of each dir are parsed in event id order
+//! (however they were given) so buffering the records of one file and sorting them is enough for
+//! the analyses that need to see the events in the order they happened.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use bus::BusReader;
+
+use crate::record::Record;
+
+/// Collects the records of a single source file
+#[derive(Debug, Default)]
+pub struct Replay {
+    source: Option<Arc<Path>>,
+    batch: Vec<Arc<Record>>,
+}
+
+impl Replay {
+    /// Adds a record, replaying the previous file's records first if the record is from another
+    /// file
+    pub fn push(&mut self, rec: Arc<Record>, f: &mut impl FnMut(&Arc<Record>)) {
+        if rec.source != self.source {
+            self.flush(f);
+            self.source = rec.source.clone();
+        }
+        self.batch.push(rec);
+    }
+
+    /// Replays the buffered records in event id order
+    pub fn flush(&mut self, f: &mut impl FnMut(&Arc<Record>)) {
+        self.batch.sort_by_key(|r| r.event_id);
+        for rec in self.batch.drain(..) {
+            f(&rec);
+        }
+    }
+}
+
+/// Calls `f` with every record from the receiver in event id order
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving record updates
+/// * `idle` - Replays the buffered records once nothing has been received for this long (so
+///   watch doesn't hold on to a file's records until the next file shows up)
+/// * `f` - Called with every record
+pub fn replay(
+    recv: BusReader<Arc<Record>>,
+    idle: Option<Duration>,
+    mut f: impl FnMut(&Arc<Record>),
+) {
+    let mut replay = Replay::default();
+
+    match idle {
+        None => {
+            for rec in recv {
+                replay.push(rec, &mut f);
+            }
+        }
+        Some(idle) => {
+            let mut recv = recv;
+            loop {
+                match recv.recv_timeout(idle) {
+                    Ok(rec) => replay.push(rec, &mut f),
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => replay.flush(&mut f),
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        }
+    }
+
+    replay.flush(&mut f);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_orders_each_file() {
+        let mut replay = Replay::default();
+        let mut seen = vec![];
+        let mut f = |r: &Arc<Record>| seen.push(r.event_id);
+
+        for rec in [
+            Arc::new(Record::default().with_source("a").with_event_id(3)),
+            Arc::new(Record::default().with_source("a").with_event_id(1)),
+            Arc::new(Record::default().with_source("a").with_event_id(2)),
+            Arc::new(Record::default().with_source("b").with_event_id(5)),
+            Arc::new(Record::default().with_source("b").with_event_id(4)),
+        ] {
+            replay.push(rec, &mut f);
+        }
+        replay.flush(&mut f);
+
+        assert_eq!(seen, vec![1, 2, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,369 @@
+//! Rotating watch outputs
+//!
+//! Watch runs for as long as it's wanted, so instead of a single output it can write the records
+//! into a series of files named from a path template. A new file is started when the hour or day
+//! changes or the current one hits the wanted size, every file is compressed on its own (its
+//! compression frame is finished before the next is started) and only the newest files can be
+//! kept.
+
+use std::{cell::Cell, collections::VecDeque, fs, path::PathBuf, rc::Rc, sync::Arc};
+
+use bus::BusReader;
+use color_eyre::{Result, eyre::eyre};
+use jiff::{Timestamp, fmt::strtime};
+
+use crate::{
+    opts::{CompressOpts, CsvOpts, Format, RotateOpts},
+    output::{CountingWriter, FinishWrite, RecordWriter},
+    record::Record,
+    split,
+};
+
+/// How often a new output file is started
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// The period the time is in (the hours or days since the epoch, in UTC)
+    fn period(self, now: Timestamp) -> i64 {
+        let secs = match self {
+            Rotation::Hourly => 60 * 60,
+            Rotation::Daily => 60 * 60 * 24,
+        };
+        now.as_second().div_euclid(secs)
+    }
+}
+
+/// Expands the `strftime` specifiers of the template with the time (in UTC)
+pub fn expand_template(template: &str, now: Timestamp) -> Result<PathBuf> {
+    strtime::format(template, now)
+        .map(PathBuf::from)
+        .map_err(|err| eyre!("Invalid output template '{template}': {err}"))
+}
+
+/// The file that is currently being written
+struct Current {
+    writer: RecordWriter<CountingWriter<Box<dyn FinishWrite>>>,
+    bytes: Rc<Cell<u64>>,
+    path: PathBuf,
+    records: u64,
+    period: Option<i64>,
+}
+
+/// Writes records into files named from a template, rotating them by time or size
+pub struct RotatingWriter {
+    template: String,
+    format: Format,
+    pretty: bool,
+    copts: CompressOpts,
+    csv_opts: CsvOpts,
+    rotate: Option<Rotation>,
+    max_bytes: Option<u64>,
+    keep: Option<usize>,
+    cur: Option<Current>,
+    /// The files written so far (oldest first) so all but the newest can be removed
+    written: VecDeque<PathBuf>,
+    /// The last expanded template and the number its file was given
+    last: Option<(PathBuf, usize)>,
+}
+
+impl RotatingWriter {
+    pub fn new(
+        template: String,
+        format: Format,
+        pretty: bool,
+        copts: CompressOpts,
+        csv_opts: CsvOpts,
+        opts: &RotateOpts,
+    ) -> Self {
+        Self {
+            template,
+            format,
+            pretty,
+            copts,
+            csv_opts,
+            rotate: opts.rotate,
+            max_bytes: opts.rotate_size,
+            keep: opts.keep,
+            cur: None,
+            written: VecDeque::new(),
+            last: None,
+        }
+    }
+
+    /// If the current file is done and a new one should be started at `now`
+    fn needs_rotation(&self, cur: &Current, now: Timestamp) -> bool {
+        if let Some(rotate) = self.rotate
+            && cur.period != Some(rotate.period(now))
+        {
+            return true;
+        }
+
+        if let Some(max) = self.max_bytes
+            && cur.bytes.get() >= max
+        {
+            return true;
+        }
+
+        false
+    }
+
+    /// Finishes the current file (if any), flushing it and closing the compression frame
+    fn finish_file(&mut self) {
+        if let Some(cur) = self.cur.take() {
+            let finished = cur.writer.into_inner().and_then(|w| w.into_inner().close());
+            if let Err(err) = finished {
+                error!("Couldn't finish {}: {err}", cur.path.display());
+            }
+
+            info!(
+                "Finished {} with {} records",
+                cur.path.display(),
+                cur.records
+            );
+        }
+    }
+
+    /// The path of the next file, numbered like the split chunks if the expanded template was
+    /// already used (or is left over from an earlier run)
+    fn next_path(&mut self, now: Timestamp) -> Result<PathBuf> {
+        let base = expand_template(&self.template, now)?;
+        // The numbers keep going up even if the earlier files were removed
+        let start = match &self.last {
+            Some((last, num)) if *last == base => num + 1,
+            _ if !base.exists() => {
+                self.last = Some((base.clone(), 0));
+                return Ok(base);
+            }
+            _ => 1,
+        };
+
+        let (num, path) = (start..)
+            .map(|num| (num, split::chunk_path(&base, num)))
+            .find(|(_, p)| !p.exists())
+            .expect("there's always a free chunk number");
+        self.last = Some((base, num));
+        Ok(path)
+    }
+
+    fn start_file(&mut self, now: Timestamp) -> Result<()> {
+        let path = self.next_path(now)?;
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let (writer, bytes) = CountingWriter::new(self.copts.make_finish_file(&path)?);
+        info!("Writing to {}", path.display());
+
+        self.written.push_back(path.clone());
+        self.cur = Some(Current {
+            writer: RecordWriter::new(self.format, writer, &self.csv_opts, self.pretty),
+            bytes,
+            path,
+            records: 0,
+            period: self.rotate.map(|r| r.period(now)),
+        });
+
+        self.remove_old();
+        Ok(())
+    }
+
+    /// Removes the oldest finished files until only the wanted number are left
+    fn remove_old(&mut self) {
+        let Some(keep) = self.keep else {
+            return;
+        };
+
+        while self.written.len() > keep {
+            let Some(old) = self.written.pop_front() else {
+                break;
+            };
+            match fs::remove_file(&old) {
+                Ok(()) => info!("Removed the old output {}", old.display()),
+                Err(err) => error!("Couldn't remove the old output {}: {err}", old.display()),
+            }
+        }
+    }
+
+    /// Writes the record to the current file as of `now`, rotating it first if needed
+    pub fn write_at(&mut self, rec: &Record, now: Timestamp) -> Result<()> {
+        if self
+            .cur
+            .as_ref()
+            .is_some_and(|cur| self.needs_rotation(cur, now))
+        {
+            self.finish_file();
+        }
+
+        if self.cur.is_none() {
+            self.start_file(now)?;
+        }
+
+        let cur = self.cur.as_mut().expect("file was just started");
+        cur.records += 1;
+        cur.writer.write(rec)?;
+        // Watch never really finishes so every record is flushed as it's written
+        cur.writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the record to the current file, rotating it first if needed
+    pub fn write(&mut self, rec: &Record) -> Result<()> {
+        self.write_at(rec, Timestamp::now())
+    }
+
+    /// Finishes the last file
+    pub fn finish(mut self) {
+        self.finish_file();
+    }
+}
+
+/// Writes records from a bus receiver into rotating files
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving record updates
+/// * `writer` - The rotating writer the records are written to
+pub fn rotate_write(recv: BusReader<Arc<Record>>, mut writer: RotatingWriter) {
+    for rec in recv {
+        if let Err(err) = writer.write(&rec) {
+            error!("Couldn't write the rotated record: {err}");
+        }
+    }
+
+    writer.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Read, path::Path};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn copts() -> CompressOpts {
+        CompressOpts {
+            glevel: 5,
+            #[cfg(feature = "zstd")]
+            zlevel: 10,
+            #[cfg(feature = "zstd")]
+            zthreads: 0,
+            gzip: false,
+            #[cfg(feature = "zstd")]
+            zstd: false,
+        }
+    }
+
+    fn writer(dir: &TempDir, template: &str, opts: RotateOpts) -> RotatingWriter {
+        RotatingWriter::new(
+            dir.path().join(template).to_string_lossy().into_owned(),
+            Format::Json,
+            false,
+            copts(),
+            CsvOpts::default(),
+            &opts,
+        )
+    }
+
+    fn gunzip(path: &Path) -> String {
+        let mut data = String::new();
+        flate2::read::GzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let now: Timestamp = "2024-03-01T13:05:00Z".parse().unwrap();
+        assert_eq!(
+            expand_template("/logs/events-%Y%m%d-%H.json.gz", now).unwrap(),
+            PathBuf::from("/logs/events-20240301-13.json.gz")
+        );
+        assert_eq!(
+            expand_template("events.json", now).unwrap(),
+            PathBuf::from("events.json")
+        );
+        assert!(expand_template("events-%", now).is_err());
+    }
+
+    #[test]
+    fn test_rotate_hourly() {
+        let dir = TempDir::new().unwrap();
+        let mut w = writer(
+            &dir,
+            "events-%Y%m%dT%H.json.gz",
+            RotateOpts {
+                rotate: Some(Rotation::Hourly),
+                ..RotateOpts::default()
+            },
+        );
+        for (id, time) in [
+            (1, "2024-03-01T12:00:00Z"),
+            (2, "2024-03-01T12:59:59Z"),
+            (3, "2024-03-01T13:00:00Z"),
+        ] {
+            w.write_at(
+                &Record::at(format!("/test/{id}")).with_event_id(id),
+                time.parse().unwrap(),
+            )
+            .unwrap();
+        }
+        w.finish();
+
+        // Each file is a complete gzip stream on its own
+        let first = gunzip(&dir.path().join("events-20240301T12.json.gz"));
+        assert_eq!(first.lines().count(), 2);
+        let second = gunzip(&dir.path().join("events-20240301T13.json.gz"));
+        assert!(second.contains("/test/3"));
+    }
+
+    #[test]
+    fn test_rotate_size_and_keep() {
+        let dir = TempDir::new().unwrap();
+        let mut w = writer(
+            &dir,
+            "events.json",
+            RotateOpts {
+                rotate_size: Some(1),
+                keep: Some(2),
+                ..RotateOpts::default()
+            },
+        );
+        let now = Timestamp::now();
+        for id in 1..=4 {
+            w.write_at(&Record::at(format!("/test/{id}")).with_event_id(id), now)
+                .unwrap();
+        }
+        w.finish();
+
+        // The names are numbered once the template's taken and only the newest two are kept
+        let base = dir.path().join("events.json");
+        assert!(!base.exists());
+        assert!(!split::chunk_path(&base, 1).exists());
+        let third = fs::read_to_string(split::chunk_path(&base, 2)).unwrap();
+        assert!(third.contains("/test/3"));
+        let fourth = fs::read_to_string(split::chunk_path(&base, 3)).unwrap();
+        assert!(fourth.contains("/test/4"));
+    }
+
+    #[test]
+    fn test_existing_files_kept() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("events.json");
+        fs::write(&base, "earlier run\n").unwrap();
+
+        let mut w = writer(&dir, "events.json", RotateOpts::default());
+        w.write_at(&Record::at("/test/1").with_event_id(1), Timestamp::now())
+            .unwrap();
+        w.finish();
+
+        assert_eq!(fs::read_to_string(&base).unwrap(), "earlier run\n");
+        assert!(split::chunk_path(&base, 1).exists());
+    }
+}
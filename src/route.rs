@@ -0,0 +1,341 @@
+//! Output routes with their own filters
+//!
+//! A route is an extra output that only receives the records matching its own filter. Every
+//! route reads from the same bus so any number of them are fed by a single parse pass.
+
+use std::{
+    fs::File,
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bus::BusReader;
+use clap::ValueEnum;
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
+
+use crate::{
+    opts::{CompressOpts, CsvOpts, Format},
+    output::RecordWriter,
+    record::{Record, RecordFilter},
+};
+
+/// The definition of a route, either from the command line or a routes file
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+pub struct RouteSpec {
+    pub name: String,
+    pub path: PathBuf,
+    /// The output format; inferred from the path's extension if not given
+    #[serde(default)]
+    pub format: Option<Format>,
+    #[serde(default)]
+    pub pretty: bool,
+    #[serde(default)]
+    pub path_filter: Option<String>,
+    #[serde(default)]
+    pub any_flags: Vec<String>,
+    #[serde(default)]
+    pub all_flags: Vec<String>,
+    /// A filter expression (the same language as `--where`)
+    #[serde(default, rename = "where")]
+    pub where_expr: Option<String>,
+}
+
+impl RouteSpec {
+    /// Parses a route given on the command line: `<name>[@<format>]:<path>[:<filter>]`
+    ///
+    /// The format is only needed if the path (like `-` for stdout) has no known extension. The
+    /// filter is a `--where` expression so it may contain further `:`s
+    pub fn parse(s: &str) -> Result<Self> {
+        let (name, rest) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("Routes must look like <name>[@<format>]:<path>[:<filter>]"))?;
+        let (path, filter) = rest.split_once(':').unwrap_or((rest, ""));
+        let (name, format) = match name.split_once('@') {
+            Some((name, format)) => (
+                name,
+                Some(
+                    Format::from_str(format, true)
+                        .map_err(|_| eyre!("Unknown format '{format}' for route '{name}'"))?,
+                ),
+            ),
+            None => (name, None),
+        };
+
+        if name.is_empty() || path.is_empty() {
+            return Err(eyre!("Routes need both a name and a path: '{s}'"));
+        }
+
+        Ok(RouteSpec {
+            name: name.to_string(),
+            path: path.into(),
+            format,
+            where_expr: Some(filter.trim())
+                .filter(|f| !f.is_empty())
+                .map(String::from),
+            ..RouteSpec::default()
+        })
+    }
+
+    /// The format of the route, inferring it from the path's extension if needed
+    pub fn format(&self) -> Result<Format> {
+        if let Some(f) = self.format {
+            return Ok(f);
+        }
+
+        infer_format(&self.path).ok_or_else(|| {
+            eyre!(
+                "Couldn't infer the format of route '{}'; give it as <name>@<format>",
+                self.name
+            )
+        })
+    }
+
+    /// Compiles the spec into a route that can be written to
+    pub fn build(&self) -> Result<Route> {
+        Ok(Route {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            format: self.format()?,
+            pretty: self.pretty,
+            filter: RecordFilter::new(&self.path_filter, &self.any_flags, &self.all_flags)
+                .and_then(|f| f.with_where(self.where_expr.as_deref()))
+                .wrap_err_with(|| format!("Invalid filter for route '{}'", self.name))?,
+        })
+    }
+}
+
+/// Infers the output format from the path's extension (ignoring any compression extension)
+pub fn infer_format(path: &Path) -> Option<Format> {
+    let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+    let name = ["gz", "gzip", "zst", "zstd"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(&format!(".{ext}")))
+        .unwrap_or(&name);
+
+    match name.rsplit_once('.')?.1 {
+        "csv" | "tsv" => Some(Format::Csv),
+        "json" | "jsonl" | "ndjson" => Some(Format::Json),
+        "yaml" | "yml" => Some(Format::Yaml),
+        _ => None,
+    }
+}
+
+/// Loads the route specs from a yaml file (a list of routes)
+pub fn load_routes(path: &Path) -> Result<Vec<RouteSpec>> {
+    let f = File::open(path)
+        .wrap_err_with(|| format!("Couldn't open the routes file {}", path.display()))?;
+
+    serde_yaml::from_reader(BufReader::new(f))
+        .wrap_err_with(|| format!("Invalid routes file {}", path.display()))
+}
+
+/// A compiled route
+#[derive(Debug)]
+pub struct Route {
+    pub name: String,
+    pub path: PathBuf,
+    pub format: Format,
+    pub pretty: bool,
+    pub filter: RecordFilter,
+}
+
+impl Route {
+    /// If the route writes to stdout
+    pub fn is_stdout(&self) -> bool {
+        self.path.as_os_str() == "-"
+    }
+
+    /// Opens the route's output
+    pub fn open(&self, copts: &CompressOpts) -> Result<Box<dyn Write>> {
+        copts.make_output(&self.path).wrap_err_with(|| {
+            format!(
+                "Couldn't create the output for route '{}' at {}",
+                self.name,
+                self.path.display()
+            )
+        })
+    }
+}
+
+/// Writes the records from a bus receiver that match the route's filter
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving record updates
+/// * `route` - The route to write
+/// * `copts` - The compression options used to open the route's output
+/// * `csv_opts` - The csv dialect to use
+/// * `flush_all` - Whether to flush after each record
+pub fn route_write(
+    recv: BusReader<Arc<Record>>,
+    route: Route,
+    copts: CompressOpts,
+    csv_opts: CsvOpts,
+    flush_all: bool,
+) {
+    let writer = match route.open(&copts) {
+        Ok(w) => w,
+        Err(err) => {
+            error!("{err:#}");
+            return;
+        }
+    };
+
+    let mut out = RecordWriter::new(route.format, writer, &csv_opts, route.pretty);
+    let mut count = 0u64;
+
+    for rec in recv {
+        if !route.filter.want(&rec) {
+            continue;
+        }
+
+        count += 1;
+        if let Err(err) = out.write(&rec) {
+            error!("Couldn't write record to route '{}': {err}", route.name);
+        }
+        if flush_all && let Err(err) = out.flush() {
+            error!("Couldn't flush route '{}': {err}", route.name);
+        }
+    }
+
+    if let Err(err) = out.flush() {
+        error!("Couldn't flush route '{}': {err}", route.name);
+    }
+    info!("Wrote {count} records to route '{}'", route.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_route_no_filter() {
+        let spec = RouteSpec::parse("all:out/all.json.gz").unwrap();
+        assert_eq!(spec.name, "all");
+        assert_eq!(spec.path, PathBuf::from("out/all.json.gz"));
+        assert!(spec.path_filter.is_none());
+        assert_eq!(spec.format().unwrap(), Format::Json);
+    }
+
+    #[test]
+    fn test_parse_route_with_filter() {
+        let spec = RouteSpec::parse(
+            r#"persist:persist.csv:path ~ "/Launch(Agents|Daemons)/" and flags any Created, Modified"#,
+        )
+        .unwrap();
+        assert_eq!(spec.name, "persist");
+        assert_eq!(spec.path, PathBuf::from("persist.csv"));
+        assert_eq!(
+            spec.where_expr.as_deref(),
+            Some(r#"path ~ "/Launch(Agents|Daemons)/" and flags any Created, Modified"#)
+        );
+        assert_eq!(spec.format().unwrap(), Format::Csv);
+
+        let route = spec.build().unwrap();
+        let rec = Record {
+            path: "Library/LaunchAgents/evil.plist".into(),
+            flag: 0x0100_0000,
+            ..Record::default()
+        };
+        assert!(route.filter.want(&rec));
+    }
+
+    #[test]
+    fn test_parse_route_colons_in_filter() {
+        let spec =
+            RouteSpec::parse(r#"late:late.json:file_timestamp > "2024-01-01T00:00:00Z""#).unwrap();
+        assert_eq!(
+            spec.where_expr.as_deref(),
+            Some(r#"file_timestamp > "2024-01-01T00:00:00Z""#)
+        );
+        assert!(spec.build().is_ok());
+    }
+
+    #[test]
+    fn test_parse_route_stdout() {
+        let spec = RouteSpec::parse("del@csv:-:flags has Removed").unwrap();
+        assert_eq!(spec.name, "del");
+        assert_eq!(spec.path, PathBuf::from("-"));
+        assert_eq!(spec.where_expr.as_deref(), Some("flags has Removed"));
+        assert_eq!(spec.format().unwrap(), Format::Csv);
+        assert!(spec.build().unwrap().is_stdout());
+
+        // The format overrides the extension
+        let spec = RouteSpec::parse("all@YAML:all.json").unwrap();
+        assert_eq!(spec.format().unwrap(), Format::Yaml);
+
+        assert!(
+            RouteSpec::parse("del:-:flags has Removed")
+                .unwrap()
+                .build()
+                .is_err()
+        );
+        assert!(RouteSpec::parse("del@xml:-").is_err());
+    }
+
+    #[test]
+    fn test_parse_route_invalid() {
+        assert!(RouteSpec::parse("nopath").is_err());
+        assert!(RouteSpec::parse(":x.json").is_err());
+        assert!(RouteSpec::parse("a:").is_err());
+    }
+
+    #[test]
+    fn test_build_route_invalid_filter() {
+        assert!(
+            RouteSpec::parse("a:x.json:flags has NotAFlag")
+                .unwrap()
+                .build()
+                .is_err()
+        );
+        assert!(RouteSpec::parse("a:x.json:bogus").unwrap().build().is_err());
+        assert!(RouteSpec::parse("a:x.txt").unwrap().build().is_err());
+    }
+
+    #[test]
+    fn test_infer_format() {
+        assert_eq!(infer_format(Path::new("a.csv")), Some(Format::Csv));
+        assert_eq!(infer_format(Path::new("a.TSV.gz")), Some(Format::Csv));
+        assert_eq!(infer_format(Path::new("a.jsonl.zst")), Some(Format::Json));
+        assert_eq!(infer_format(Path::new("dir/a.yml")), Some(Format::Yaml));
+        assert_eq!(infer_format(Path::new("a.txt")), None);
+        assert_eq!(infer_format(Path::new("a")), None);
+        assert_eq!(infer_format(Path::new("-")), None);
+    }
+
+    #[test]
+    fn test_routes_yaml() {
+        let specs: Vec<RouteSpec> = serde_yaml::from_str(
+            r#"
+- name: deletions
+  path: deletions.csv
+  any_flags: [Removed]
+- name: docs
+  path: docs.out
+  format: yaml
+  path_filter: '\.docx?$'
+  where: not flags has Removed
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].any_flags, vec!["Removed"]);
+        assert_eq!(specs[1].format().unwrap(), Format::Yaml);
+
+        let route = specs[1].build().unwrap();
+        let mut rec = Record {
+            path: "/Users/a/b.docx".into(),
+            ..Record::default()
+        };
+        assert!(route.filter.want(&rec));
+        rec.flag = 0x0200_0000;
+        assert!(!route.filter.want(&rec));
+        rec.flag = 0;
+        rec.path = "/Users/a/b.pdf".into();
+        assert!(!route.filter.want(&rec));
+    }
+}
@@ -0,0 +1,522 @@
+//! Globally sorted record output
+//!
+//! This module collects every record from all of the input files, sorts them by the wanted
+//! key using a bounded memory external merge sort, and re-broadcasts them in order (optionally
+//! dropping the duplicates that overlapping collections produce).
+
+use std::{
+    cmp::Ordering,
+    io::{self, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use bus::{Bus, BusReader};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use jiff::Timestamp;
+
+use crate::{
+    extsort::{self, ExternalSorter, Spill},
+    flags,
+    opts::{SortKey, SortOpts},
+    record::Record,
+};
+
+/// A record along with the key it should be sorted by
+pub struct SortItem {
+    key: SortKey,
+    pub rec: Record,
+}
+
+impl SortItem {
+    pub fn new(key: SortKey, rec: Record) -> Self {
+        Self { key, rec }
+    }
+
+    /// If the two records are duplicates of each other (same event id, path and flags)
+    pub fn is_dupe(a: &Record, b: &Record) -> bool {
+        a.event_id == b.event_id && a.flag == b.flag && a.path == b.path
+    }
+}
+
+impl Ord for SortItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (&self.rec, &other.rec);
+        match self.key {
+            SortKey::EventId => (a.event_id, &a.path, a.flag, a.node_id)
+                .cmp(&(b.event_id, &b.path, b.flag, b.node_id)),
+            SortKey::Path => (&a.path, a.event_id, a.flag, a.node_id)
+                .cmp(&(&b.path, b.event_id, b.flag, b.node_id)),
+            SortKey::NodeId => (a.node_id, a.event_id, &a.path, a.flag)
+                .cmp(&(b.node_id, b.event_id, &b.path, b.flag)),
+        }
+    }
+}
+
+impl PartialOrd for SortItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SortItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortItem {}
+
+impl Spill for SortItem {
+    fn spill<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let rec = &self.rec;
+
+        w.write_u8(self.key as u8)?;
+        extsort::write_bytes(w, rec.path.as_bytes())?;
+        w.write_u8(rec.path_lossy as u8)?;
+        write_opt_bytes(w, rec.path_base64.as_ref().map(|p| p.as_bytes()))?;
+        write_opt_bytes(w, rec.raw_path.as_deref())?;
+        write_opt_bytes(w, rec.canonical_path.as_ref().map(|p| p.as_bytes()))?;
+        // The derived fields are cheaper to recompute than to spill
+        w.write_u8(rec.has_derived_fields() as u8)?;
+        write_opt_bytes(w, rec.artifact_category.as_ref().map(|c| c.as_bytes()))?;
+        write_opt_bytes(w, rec.artifact_name.as_ref().map(|n| n.as_bytes()))?;
+        w.write_u64::<LittleEndian>(rec.event_id)?;
+        w.write_u32::<LittleEndian>(rec.flag)?;
+        extsort::write_opt_u64(w, rec.node_id)?;
+        #[cfg(feature = "extra_id")]
+        extsort::write_opt_u64(w, rec.extra_id.map(u64::from))?;
+
+        match rec.file_timestamp {
+            None => w.write_u8(0)?,
+            Some(ts) => {
+                w.write_u8(1)?;
+                w.write_i64::<LittleEndian>(ts.as_second())?;
+                w.write_i32::<LittleEndian>(ts.subsec_nanosecond())?;
+            }
+        }
+
+        match &rec.ioc_ids {
+            None => w.write_u8(0)?,
+            Some(ids) => {
+                w.write_u8(1)?;
+                w.write_u32::<LittleEndian>(ids.len() as u32)?;
+                for id in ids {
+                    extsort::write_bytes(w, id.as_bytes())?;
+                }
+            }
+        }
+
+        match &rec.source {
+            None => w.write_u8(0),
+            Some(src) => {
+                w.write_u8(1)?;
+                extsort::write_os_str(w, src.as_os_str())
+            }
+        }
+    }
+
+    fn unspill<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+        let key = match r.read_u8() {
+            Ok(k) => SortKey::from_u8(k),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let path = extsort::read_string(r)?;
+        let path_lossy = r.read_u8()? != 0;
+        let path_base64 = match r.read_u8()? {
+            0 => None,
+            _ => Some(extsort::read_string(r)?),
+        };
+        let raw_path = match r.read_u8()? {
+            0 => None,
+            _ => Some(extsort::read_bytes(r)?.into_boxed_slice()),
+        };
+        let canonical_path = match r.read_u8()? {
+            0 => None,
+            _ => Some(extsort::read_string(r)?),
+        };
+        let derived_fields = r.read_u8()? != 0;
+        let artifact_category = match r.read_u8()? {
+            0 => None,
+            _ => Some(Arc::from(extsort::read_string(r)?)),
+        };
+        let artifact_name = match r.read_u8()? {
+            0 => None,
+            _ => Some(Arc::from(extsort::read_string(r)?)),
+        };
+        let event_id = r.read_u64::<LittleEndian>()?;
+        let flag = r.read_u32::<LittleEndian>()?;
+        let node_id = extsort::read_opt_u64(r)?;
+        #[cfg(feature = "extra_id")]
+        let extra_id = extsort::read_opt_u64(r)?.map(|e| e as u32);
+
+        let file_timestamp = match r.read_u8()? {
+            0 => None,
+            _ => {
+                let secs = r.read_i64::<LittleEndian>()?;
+                let nanos = r.read_i32::<LittleEndian>()?;
+                Timestamp::new(secs, nanos).ok()
+            }
+        };
+
+        let ioc_ids = match r.read_u8()? {
+            0 => None,
+            _ => {
+                let len = r.read_u32::<LittleEndian>()? as usize;
+                let mut ids = Vec::with_capacity(len);
+                for _ in 0..len {
+                    ids.push(Arc::from(extsort::read_string(r)?));
+                }
+                Some(ids)
+            }
+        };
+
+        let source = match r.read_u8()? {
+            0 => None,
+            _ => Some(Arc::from(PathBuf::from(extsort::read_os_string(r)?))),
+        };
+
+        let flag_strs = flags::parse_bits(flag);
+        let mut rec = Record {
+            path,
+            path_lossy,
+            path_base64,
+            canonical_path,
+            dir: None,
+            name: None,
+            ext: None,
+            depth: None,
+            home_user: None,
+            volume: None,
+            artifact_category,
+            artifact_name,
+            raw_path,
+            event_id,
+            flag,
+            flags: flag_strs.norm,
+            #[cfg(feature = "alt_flags")]
+            alt_flags: flag_strs.alt,
+            node_id,
+            #[cfg(feature = "extra_id")]
+            extra_id,
+            file_timestamp,
+            ioc_ids,
+            source,
+        };
+        if derived_fields {
+            rec.derive_fields();
+        }
+
+        Ok(Some(SortItem { key, rec }))
+    }
+}
+
+fn write_opt_bytes<W: Write>(w: &mut W, bytes: Option<&[u8]>) -> io::Result<()> {
+    match bytes {
+        None => w.write_u8(0),
+        Some(b) => {
+            w.write_u8(1)?;
+            extsort::write_bytes(w, b)
+        }
+    }
+}
+
+impl SortKey {
+    fn from_u8(k: u8) -> Self {
+        match k {
+            0 => SortKey::EventId,
+            1 => SortKey::Path,
+            _ => SortKey::NodeId,
+        }
+    }
+}
+
+/// Sorts every record from the receiver and broadcasts them in order on the output bus
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving the parsed records
+/// * `opts` - The sort options
+/// * `out` - The bus the sorted records are broadcast on
+pub fn sort_write(recv: BusReader<Arc<Record>>, opts: SortOpts, mut out: Bus<Arc<Record>>) {
+    let Some(key) = opts.sort else {
+        return;
+    };
+
+    let tmp_dir = opts.sort_tmp.clone().unwrap_or_else(std::env::temp_dir);
+    let mut sorter = ExternalSorter::new(opts.sort_buffer, tmp_dir);
+
+    for rec in recv {
+        if let Err(err) = sorter.push(SortItem::new(key, Arc::unwrap_or_clone(rec))) {
+            error!("Couldn't spill the sort buffer: {err}");
+            return;
+        }
+    }
+
+    let sorted = match sorter.finish() {
+        Ok(s) => s,
+        Err(err) => {
+            error!("Couldn't merge the sorted records: {err}");
+            return;
+        }
+    };
+
+    let mut prev: Option<Arc<Record>> = None;
+    let mut dupes = 0u64;
+
+    for item in sorted {
+        let rec = match item {
+            Ok(item) => item.rec,
+            Err(err) => {
+                error!("Couldn't read the sorted records: {err}");
+                return;
+            }
+        };
+
+        if opts.dedupe
+            && let Some(p) = &prev
+            && SortItem::is_dupe(p, &rec)
+        {
+            dupes += 1;
+            continue;
+        }
+
+        let rec = Arc::new(rec);
+        if opts.dedupe {
+            prev = Some(rec.clone());
+        }
+        out.broadcast(rec);
+    }
+
+    if opts.dedupe {
+        info!("Removed {dupes} duplicate records");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// A record with every field set (and listed, so a new field can't be missed) so the spill
+    /// round trip covers them all
+    fn full_record(path: &str, event_id: u64, node_id: Option<u64>) -> Record {
+        let flag_strs = flags::parse_bits(0x1000_0000);
+        Record {
+            path: path.to_string(),
+            path_lossy: true,
+            path_base64: Some("L2Ev/w==".into()),
+            canonical_path: None,
+            dir: None,
+            name: None,
+            ext: None,
+            depth: None,
+            home_user: None,
+            volume: None,
+            artifact_category: None,
+            artifact_name: None,
+            raw_path: Some(Box::from(&b"/a/\xff"[..])),
+            event_id,
+            flag: 0x1000_0000,
+            flags: flag_strs.norm,
+            #[cfg(feature = "alt_flags")]
+            alt_flags: flag_strs.alt,
+            node_id,
+            #[cfg(feature = "extra_id")]
+            extra_id: Some(7),
+            file_timestamp: Timestamp::new(1_700_000_000, 1234).ok(),
+            ioc_ids: Some(vec![Arc::from("ioc-1"), Arc::from("ioc-2")]),
+            source: Some(Arc::from(Path::new("/fse/0000000000000001"))),
+        }
+    }
+
+    fn run_sort(opts: SortOpts, recs: Vec<Record>) -> Vec<Arc<Record>> {
+        let mut in_bus = Bus::new(64);
+        let recv = in_bus.add_rx();
+        let mut out_bus = Bus::new(64);
+        let mut out_recv = out_bus.add_rx();
+
+        let handle = std::thread::spawn(move || sort_write(recv, opts, out_bus));
+        for r in recs {
+            in_bus.broadcast(Arc::new(r));
+        }
+        drop(in_bus);
+
+        let out = out_recv.iter().collect();
+        handle.join().unwrap();
+        out
+    }
+
+    #[test]
+    fn test_spill_derived_fields() {
+        let mut rec = full_record("Users/bob/a.txt", 1, None);
+        rec.derive_fields();
+        let item = SortItem::new(SortKey::EventId, rec);
+
+        let mut buf = vec![];
+        item.spill(&mut buf).unwrap();
+        let back = SortItem::unspill(&mut buf.as_slice()).unwrap().unwrap();
+
+        assert_eq!(back.rec.home_user.as_deref(), Some("bob"));
+        assert_eq!(back.rec.ext.as_deref(), Some("txt"));
+        assert_eq!(back.rec.depth, Some(3));
+    }
+
+    #[test]
+    fn test_spill_round_trip() {
+        let mut rec = full_record("/a/b", 42, Some(9));
+        rec.canonical_path = Some("/private/a/b".into());
+        rec.artifact_category = Some(Arc::from("persistence"));
+        rec.artifact_name = Some(Arc::from(""));
+        let item = SortItem::new(SortKey::Path, rec);
+
+        let mut buf = vec![];
+        item.spill(&mut buf).unwrap();
+        let mut r = buf.as_slice();
+        let back = SortItem::unspill(&mut r).unwrap().unwrap();
+
+        assert_eq!(back.key, SortKey::Path);
+        assert_eq!(back.rec.path, "/a/b");
+        assert!(back.rec.path_lossy);
+        assert_eq!(back.rec.path_base64, item.rec.path_base64);
+        assert_eq!(back.rec.raw_path, item.rec.raw_path);
+        assert_eq!(back.rec.canonical_path.as_deref(), Some("/private/a/b"));
+        assert!(!back.rec.has_derived_fields());
+        assert_eq!(back.rec.artifact_category.as_deref(), Some("persistence"));
+        assert_eq!(back.rec.artifact_name.as_deref(), Some(""));
+        assert_eq!(back.rec.event_id, 42);
+        assert_eq!(back.rec.flag, 0x1000_0000);
+        assert_eq!(back.rec.flags, "Modified");
+        assert_eq!(back.rec.node_id, Some(9));
+        #[cfg(feature = "extra_id")]
+        assert_eq!(back.rec.extra_id, Some(7));
+        assert_eq!(back.rec.file_timestamp, item.rec.file_timestamp);
+        assert_eq!(back.rec.ioc_ids, item.rec.ioc_ids);
+        assert_eq!(back.rec.source, item.rec.source);
+        assert!(SortItem::unspill(&mut r).unwrap().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spill_non_utf8_source() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let source = OsStr::from_bytes(b"fse/src\xff");
+        let item = SortItem::new(
+            SortKey::EventId,
+            full_record("/a", 1, None).with_source(source),
+        );
+
+        let mut buf = vec![];
+        item.spill(&mut buf).unwrap();
+        let back = SortItem::unspill(&mut buf.as_slice()).unwrap().unwrap();
+
+        assert_eq!(back.rec.source.as_deref(), Some(Path::new(source)));
+    }
+
+    #[test]
+    fn test_sort_by_event_id_spilled() {
+        let recs: Vec<_> = (0..500u64)
+            .map(|i| full_record(&format!("/p{i}"), (i * 7919) % 500, None))
+            .collect();
+
+        let out = run_sort(
+            SortOpts {
+                sort: Some(SortKey::EventId),
+                sort_buffer: 50,
+                ..SortOpts::default()
+            },
+            recs,
+        );
+
+        assert_eq!(out.len(), 500);
+        assert!(out.windows(2).all(|w| w[0].event_id <= w[1].event_id));
+    }
+
+    #[test]
+    fn test_sort_by_path_and_node_id() {
+        let recs = vec![
+            full_record("/c", 1, Some(3)),
+            full_record("/a", 2, Some(2)),
+            full_record("/b", 3, None),
+        ];
+
+        let out = run_sort(
+            SortOpts {
+                sort: Some(SortKey::Path),
+                ..SortOpts::default()
+            },
+            recs.clone(),
+        );
+        let paths: Vec<_> = out.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["/a", "/b", "/c"]);
+
+        let out = run_sort(
+            SortOpts {
+                sort: Some(SortKey::NodeId),
+                ..SortOpts::default()
+            },
+            recs,
+        );
+        let nodes: Vec<_> = out.iter().map(|r| r.node_id).collect();
+        assert_eq!(nodes, vec![None, Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn test_sort_dedupe() {
+        let mut dupe = full_record("/a", 1, Some(1));
+        dupe.source = Some(Arc::from(Path::new("/other/0000000000000001")));
+
+        let recs = vec![
+            full_record("/a", 1, Some(1)),
+            full_record("/b", 2, Some(1)),
+            dupe,
+            full_record("/a", 3, Some(1)),
+        ];
+
+        let out = run_sort(
+            SortOpts {
+                sort: Some(SortKey::EventId),
+                dedupe: true,
+                sort_buffer: 2,
+                ..SortOpts::default()
+            },
+            recs.clone(),
+        );
+        assert_eq!(out.len(), 3);
+
+        let out = run_sort(
+            SortOpts {
+                sort: Some(SortKey::EventId),
+                ..SortOpts::default()
+            },
+            recs,
+        );
+        assert_eq!(out.len(), 4, "Dupes are kept unless asked for");
+    }
+
+    #[test]
+    fn test_sort_dedupe_mixed_node_ids() {
+        // The same event from a v1 file (no node id) and a v2 file, with other records between
+        let recs = vec![
+            full_record("/a", 1, None),
+            full_record("/a", 2, Some(5)),
+            full_record("/b", 1, Some(1)),
+            full_record("/a", 1, Some(9)),
+        ];
+
+        for key in [SortKey::EventId, SortKey::Path] {
+            let out = run_sort(
+                SortOpts {
+                    sort: Some(key),
+                    dedupe: true,
+                    sort_buffer: 2,
+                    ..SortOpts::default()
+                },
+                recs.clone(),
+            );
+            assert_eq!(out.len(), 3, "{key:?}");
+        }
+    }
+}
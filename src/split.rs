@@ -0,0 +1,436 @@
+//! Splitting the combined outputs into numbered chunks
+//!
+//! This module writes records into a series of numbered chunk files, starting a new chunk
+//! once the current one hits the wanted record count, byte size or number of input files.
+//! Every chunk is compressed independently and an index of the chunks is written at the end.
+
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+};
+
+use bus::BusReader;
+use color_eyre::Result;
+#[cfg(feature = "hex")]
+use serde_hex::{CompactCapPfx, SerHexOpt};
+
+use crate::{
+    opts::{CompressOpts, CsvOpts, Format, SplitOpts},
+    output::{CountingWriter, FinishWrite, NoFlush, RecordWriter},
+    record::Record,
+};
+
+/// Information about a single chunk, written to the index file
+#[derive(Debug, Serialize)]
+pub struct ChunkInfo {
+    pub chunk: usize,
+    pub path: PathBuf,
+    pub records: u64,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub first_event_id: Option<u64>,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub last_event_id: Option<u64>,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub min_event_id: Option<u64>,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub max_event_id: Option<u64>,
+}
+
+impl ChunkInfo {
+    fn new(chunk: usize, path: PathBuf) -> Self {
+        Self {
+            chunk,
+            path,
+            records: 0,
+            first_event_id: None,
+            last_event_id: None,
+            min_event_id: None,
+            max_event_id: None,
+        }
+    }
+
+    fn update(&mut self, event_id: u64) {
+        self.records += 1;
+        self.first_event_id.get_or_insert(event_id);
+        self.last_event_id = Some(event_id);
+        self.min_event_id = Some(self.min_event_id.map_or(event_id, |e| e.min(event_id)));
+        self.max_event_id = Some(self.max_event_id.map_or(event_id, |e| e.max(event_id)));
+    }
+}
+
+/// The chunk that is currently being written
+struct Chunk {
+    writer: RecordWriter<CountingWriter<NoFlush<Box<dyn FinishWrite>>>>,
+    bytes: Rc<Cell<u64>>,
+    info: ChunkInfo,
+    sources: usize,
+    last_source: Option<Arc<Path>>,
+}
+
+/// Returns the path of the numbered chunk for the given output path
+///
+/// The number is inserted before the first extension so any compression extension is kept
+/// (`events.json.gz` -> `events.0001.json.gz`)
+pub fn chunk_path(base: &Path, num: usize) -> PathBuf {
+    let name = base
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let chunk_name = match name.split_once('.') {
+        Some((stem, ext)) => format!("{stem}.{num:04}.{ext}"),
+        None => format!("{name}.{num:04}"),
+    };
+
+    base.with_file_name(chunk_name)
+}
+
+/// Returns the path of the chunk index for the given output path
+pub fn index_path(base: &Path) -> PathBuf {
+    let mut p = base.as_os_str().to_owned();
+    p.push(".index.json");
+    p.into()
+}
+
+/// Writes records into numbered chunks of the base path
+pub struct SplitWriter {
+    base: PathBuf,
+    format: Format,
+    copts: CompressOpts,
+    csv_opts: CsvOpts,
+    split: SplitOpts,
+    cur: Option<Chunk>,
+    index: Vec<ChunkInfo>,
+}
+
+impl SplitWriter {
+    pub fn new(
+        base: PathBuf,
+        format: Format,
+        copts: CompressOpts,
+        csv_opts: CsvOpts,
+        split: SplitOpts,
+    ) -> Self {
+        Self {
+            base,
+            format,
+            copts,
+            csv_opts,
+            split,
+            cur: None,
+            index: Vec::new(),
+        }
+    }
+
+    /// If the current chunk is full and a new one should be started before writing `rec`
+    fn chunk_full(&self, chunk: &Chunk, rec: &Record) -> bool {
+        if let Some(max) = self.split.split_records
+            && chunk.info.records >= max
+        {
+            return true;
+        }
+
+        if let Some(max) = self.split.split_bytes
+            && chunk.bytes.get() >= max
+        {
+            return true;
+        }
+
+        if let Some(max) = self.split.split_files
+            && chunk.sources >= max
+            && rec.source != chunk.last_source
+        {
+            return true;
+        }
+
+        false
+    }
+
+    /// Finishes the current chunk (if any), flushing it and closing the compression frame
+    fn finish_chunk(&mut self) {
+        if let Some(chunk) = self.cur.take() {
+            let finished = chunk
+                .writer
+                .into_inner()
+                .and_then(|w| w.into_inner().close());
+            if let Err(err) = finished {
+                error!("Couldn't finish {}: {err}", chunk.info.path.display());
+            }
+
+            info!(
+                "Finished chunk {} with {} records",
+                chunk.info.path.display(),
+                chunk.info.records
+            );
+            self.index.push(chunk.info);
+        }
+    }
+
+    fn start_chunk(&mut self) -> Result<()> {
+        let num = self.index.len() + 1;
+        let path = chunk_path(&self.base, num);
+
+        let (writer, bytes) = CountingWriter::new(NoFlush(self.copts.make_finish_file(&path)?));
+
+        self.cur = Some(Chunk {
+            writer: RecordWriter::new(self.format, writer, &self.csv_opts, false),
+            bytes,
+            info: ChunkInfo::new(num, path),
+            sources: 0,
+            last_source: None,
+        });
+
+        Ok(())
+    }
+
+    /// Writes the record to the current chunk, starting a new one first if needed
+    pub fn write(&mut self, rec: &Record) -> Result<()> {
+        if self
+            .cur
+            .as_ref()
+            .is_some_and(|chunk| self.chunk_full(chunk, rec))
+        {
+            self.finish_chunk();
+        }
+
+        if self.cur.is_none() {
+            self.start_chunk()?;
+        }
+
+        let chunk = self.cur.as_mut().expect("chunk was just started");
+        if chunk.last_source.is_none() || rec.source != chunk.last_source {
+            chunk.sources += 1;
+            chunk.last_source = rec.source.clone();
+        }
+
+        chunk.info.update(rec.event_id);
+        chunk.writer.write(rec)?;
+        if self.split.split_bytes.is_some() {
+            // The csv writer buffers the records above the byte count, so they're only counted
+            // once it's flushed (which goes no further than the count)
+            chunk.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the last chunk and writes the chunk index
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_chunk();
+
+        let path = index_path(&self.base);
+        let mut w = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer_pretty(&mut w, &self.index)?;
+        writeln!(w)?;
+        w.flush()?;
+
+        info!("Wrote the chunk index {}", path.display());
+        Ok(())
+    }
+}
+
+/// Writes records from a bus receiver into numbered chunks
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving record updates
+/// * `writer` - The split writer the records are written to
+pub fn split_write(recv: BusReader<Arc<Record>>, mut writer: SplitWriter) {
+    for rec in recv {
+        if let Err(err) = writer.write(&rec) {
+            error!("Couldn't write split record: {err}");
+        }
+    }
+
+    if let Err(err) = writer.finish() {
+        error!("Couldn't finish the split output: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn copts() -> CompressOpts {
+        CompressOpts {
+            glevel: 5,
+            #[cfg(feature = "zstd")]
+            zlevel: 10,
+            #[cfg(feature = "zstd")]
+            zthreads: 0,
+            gzip: false,
+            #[cfg(feature = "zstd")]
+            zstd: false,
+        }
+    }
+
+    #[test]
+    fn test_chunk_path() {
+        assert_eq!(
+            chunk_path(Path::new("/out/events.json.gz"), 1),
+            PathBuf::from("/out/events.0001.json.gz")
+        );
+        assert_eq!(
+            chunk_path(Path::new("events"), 12),
+            PathBuf::from("events.0012")
+        );
+        assert_eq!(
+            index_path(Path::new("/out/events.csv")),
+            PathBuf::from("/out/events.csv.index.json")
+        );
+    }
+
+    #[test]
+    fn test_split_by_records() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("events.json");
+        let src: Arc<Path> = Arc::from(Path::new("src1"));
+
+        let mut w = SplitWriter::new(
+            base.clone(),
+            Format::Json,
+            copts(),
+            CsvOpts::default(),
+            SplitOpts {
+                split_records: Some(2),
+                ..SplitOpts::default()
+            },
+        );
+        for id in 1..=5 {
+            w.write(
+                &Record::at(format!("/test/{id}"))
+                    .with_event_id(id)
+                    .with_source(&src),
+            )
+            .unwrap();
+        }
+        assert_eq!(w.index.len(), 2);
+        w.finish().unwrap();
+
+        for (num, lines) in [(1, 2), (2, 2), (3, 1)] {
+            let data = fs::read_to_string(chunk_path(&base, num)).unwrap();
+            assert_eq!(data.lines().count(), lines);
+        }
+        assert!(!chunk_path(&base, 4).exists());
+
+        let index: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(index_path(&base)).unwrap()).unwrap();
+        assert_eq!(index.as_array().unwrap().len(), 3);
+        assert_eq!(index[2]["records"], 1);
+    }
+
+    #[test]
+    fn test_split_by_files() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("events.csv");
+        let src1: Arc<Path> = Arc::from(Path::new("src1"));
+        let src2: Arc<Path> = Arc::from(Path::new("src2"));
+        let src3: Arc<Path> = Arc::from(Path::new("src3"));
+
+        let mut w = SplitWriter::new(
+            base.clone(),
+            Format::Csv,
+            copts(),
+            CsvOpts::default(),
+            SplitOpts {
+                split_files: Some(2),
+                ..SplitOpts::default()
+            },
+        );
+        for (id, src) in [(1, &src1), (2, &src1), (3, &src2), (4, &src3), (5, &src3)] {
+            w.write(
+                &Record::at(format!("/test/{id}"))
+                    .with_event_id(id)
+                    .with_source(src),
+            )
+            .unwrap();
+        }
+        w.finish().unwrap();
+
+        // Every chunk gets its own header
+        let first = fs::read_to_string(chunk_path(&base, 1)).unwrap();
+        assert_eq!(first.lines().count(), 4);
+        let second = fs::read_to_string(chunk_path(&base, 2)).unwrap();
+        assert_eq!(second.lines().count(), 3);
+        assert!(second.starts_with("path,"));
+    }
+
+    #[test]
+    fn test_split_by_bytes_csv() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("events.csv");
+        let src: Arc<Path> = Arc::from(Path::new("src1"));
+
+        let mut w = SplitWriter::new(
+            base.clone(),
+            Format::Csv,
+            copts(),
+            CsvOpts::default(),
+            SplitOpts {
+                split_bytes: Some(1),
+                ..SplitOpts::default()
+            },
+        );
+        for id in 1..=3 {
+            w.write(
+                &Record::at(format!("/test/{id}"))
+                    .with_event_id(id)
+                    .with_source(&src),
+            )
+            .unwrap();
+        }
+        w.finish().unwrap();
+
+        // The records the csv writer buffered are counted so each one gets its own chunk
+        for num in 1..=3 {
+            let data = fs::read_to_string(chunk_path(&base, num)).unwrap();
+            assert_eq!(data.lines().count(), 2);
+        }
+        assert!(!chunk_path(&base, 4).exists());
+    }
+
+    #[test]
+    fn test_split_by_bytes_gzip() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("events.yaml.gz");
+        let src: Arc<Path> = Arc::from(Path::new("src1"));
+
+        let mut w = SplitWriter::new(
+            base.clone(),
+            Format::Yaml,
+            copts(),
+            CsvOpts::default(),
+            SplitOpts {
+                split_bytes: Some(1),
+                ..SplitOpts::default()
+            },
+        );
+        for id in 1..=3 {
+            w.write(
+                &Record::at(format!("/test/{id}"))
+                    .with_event_id(id)
+                    .with_source(&src),
+            )
+            .unwrap();
+        }
+        w.finish().unwrap();
+
+        // Each chunk should be a complete gzip stream on its own
+        for num in 1..=3 {
+            let mut data = String::new();
+            std::io::Read::read_to_string(
+                &mut flate2::read::GzDecoder::new(File::open(chunk_path(&base, num)).unwrap()),
+                &mut data,
+            )
+            .unwrap();
+            assert!(data.starts_with("---\n"));
+            assert!(data.contains(&format!("/test/{num}")));
+        }
+    }
+}
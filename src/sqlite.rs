@@ -0,0 +1,196 @@
+//! Writes serializable rows into a sqlite table
+//!
+//! The rows go through `serde_json` so the table has the same columns (named and ordered like
+//! the struct's fields) and values as the other formats. The table is created from a template
+//! row when the writer is opened (so it's there even without any rows) and every row is inserted
+//! in a single transaction that's committed by `finish`.
+
+use std::path::Path;
+
+use color_eyre::{Result, eyre::eyre};
+use rusqlite::{Connection, params_from_iter, types::Value as SqlValue};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Writes rows into a (recreated) sqlite table
+pub struct SqliteWriter {
+    conn: Connection,
+    insert: String,
+}
+
+/// Quotes an identifier
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The sqlite value of a json value (nested values are stored as their json text)
+fn sql_value(value: Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(b.into()),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => SqlValue::Text(s),
+        value => SqlValue::Text(value.to_string()),
+    }
+}
+
+/// The column type for a json value (untyped if the template's value is null)
+fn sql_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "",
+        Value::Bool(_) => " INTEGER",
+        Value::Number(n) if n.is_i64() => " INTEGER",
+        Value::Number(_) => " REAL",
+        _ => " TEXT",
+    }
+}
+
+/// The columns and values of a row
+fn row_object<T: Serialize>(row: &T) -> Result<serde_json::Map<String, Value>> {
+    match serde_json::to_value(row)? {
+        Value::Object(row) => Ok(row),
+        _ => Err(eyre!("Only structs can be written as sqlite rows")),
+    }
+}
+
+impl SqliteWriter {
+    /// Opens (or creates) the database and (re)creates the table
+    ///
+    /// # Arguments
+    /// * `path` - The sqlite database file
+    /// * `table` - The table the rows are written to
+    /// * `template` - A row with the same columns as the rows that will be written (its values
+    ///   only give the column types)
+    pub fn create<T: Serialize>(path: &Path, table: &str, template: &T) -> Result<Self> {
+        let template = row_object(template)?;
+        let table = quote(table);
+        let columns: Vec<_> = template
+            .iter()
+            .map(|(k, v)| quote(k) + sql_type(v))
+            .collect();
+
+        let conn = Connection::open(path)?;
+        conn.execute_batch(&format!(
+            "DROP TABLE IF EXISTS {table}; BEGIN; CREATE TABLE {table} ({});",
+            columns.join(", ")
+        ))?;
+
+        let names: Vec<_> = template.keys().map(|k| quote(k)).collect();
+        let params: Vec<_> = (1..=names.len()).map(|i| format!("?{i}")).collect();
+        let insert = format!(
+            "INSERT INTO {table} ({}) VALUES ({})",
+            names.join(", "),
+            params.join(", ")
+        );
+
+        Ok(Self { conn, insert })
+    }
+
+    /// Writes a single row
+    pub fn write<T: Serialize>(&mut self, row: &T) -> Result<()> {
+        let row = row_object(row)?;
+        self.conn
+            .prepare_cached(&self.insert)?
+            .execute(params_from_iter(row.into_iter().map(|(_, v)| sql_value(v))))?;
+        Ok(())
+    }
+
+    /// Commits the rows
+    pub fn finish(self) -> Result<()> {
+        self.conn.execute_batch("COMMIT;")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Row {
+        path: &'static str,
+        counts: u64,
+        flags: Option<&'static str>,
+    }
+
+    const TEMPLATE: Row = Row {
+        path: "",
+        counts: 0,
+        flags: Some(""),
+    };
+
+    #[test]
+    fn test_sqlite_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.sqlite");
+
+        // The table is recreated each time
+        for _ in 0..2 {
+            let mut writer = SqliteWriter::create(&path, "uniques", &TEMPLATE).unwrap();
+            writer
+                .write(&Row {
+                    path: "a",
+                    counts: 2,
+                    flags: Some("Created"),
+                })
+                .unwrap();
+            writer
+                .write(&Row {
+                    path: "b",
+                    counts: 1,
+                    flags: None,
+                })
+                .unwrap();
+            writer.finish().unwrap();
+        }
+
+        let conn = Connection::open(&path).unwrap();
+        let rows: Vec<(String, i64, Option<String>)> = conn
+            .prepare("SELECT path, counts, flags FROM uniques ORDER BY path")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("a".to_string(), 2, Some("Created".to_string())),
+                ("b".to_string(), 1, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sqlite_no_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.sqlite");
+
+        SqliteWriter::create(&path, "uniques", &TEMPLATE)
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let columns: Vec<(String, String)> = conn
+            .prepare("SELECT name, type FROM pragma_table_info('uniques')")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            columns,
+            [("path", "TEXT"), ("counts", "INTEGER"), ("flags", "TEXT")]
+                .map(|(n, t)| (n.to_string(), t.to_string()))
+        );
+
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM uniques", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}
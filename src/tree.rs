@@ -0,0 +1,584 @@
+//! The directory tree the records leave behind
+//!
+//! The records are replayed in event id order into an in memory tree: any event marks a node
+//! (and the directories above it) as present, a removal marks it and everything below it as
+//! removed and a rename moves the node's children over to its new path. The two records of a
+//! rename are paired by node id, or by consecutive event ids when the records don't have node
+//! ids. Nodes are never dropped so the end state shows what was removed or renamed away along
+//! with what was still there when the logs end.
+
+use std::{collections::BTreeMap, io::Write, sync::Arc};
+
+use color_eyre::Result;
+use serde::Serialize;
+#[cfg(feature = "hex")]
+use serde_hex::{CompactCapPfx, SerHexOpt};
+
+use crate::{
+    flags,
+    opts::{CsvOpts, TreeFormat},
+    record::Record,
+};
+
+/// What the records say about a node at the end of the logs
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeStatus {
+    /// The node still existed
+    Present,
+    /// The node was removed (or a directory above it was)
+    Removed,
+    /// The node was renamed to another path
+    #[value(name = "renamed_from")]
+    RenamedFrom,
+    /// The node was renamed without a pair (moved in or out of the volume) or only shows up as a
+    /// parent of nodes that didn't stay
+    Unknown,
+}
+
+impl NodeStatus {
+    fn name(self) -> &'static str {
+        match self {
+            NodeStatus::Present => "present",
+            NodeStatus::Removed => "removed",
+            NodeStatus::RenamedFrom => "renamed_from",
+            NodeStatus::Unknown => "unknown",
+        }
+    }
+}
+
+/// What a node is (from the record flags)
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+/// A node of the tree
+#[derive(Debug, Default)]
+struct Node {
+    /// `None` for the directories that only show up as the parent of other nodes
+    status: Option<NodeStatus>,
+    kind: Option<NodeKind>,
+    node_id: Option<u64>,
+    first_event_id: Option<u64>,
+    last_event_id: Option<u64>,
+    events: u64,
+    renamed_from: Option<String>,
+    renamed_to: Option<String>,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn status(&self) -> NodeStatus {
+        self.status.unwrap_or(NodeStatus::Unknown)
+    }
+
+    /// If the node (as far as we know) still exists
+    fn live(&self) -> bool {
+        matches!(self.status, None | Some(NodeStatus::Present))
+    }
+
+    fn touch(&mut self, rec: &Record, kind: Option<NodeKind>) {
+        self.events += 1;
+        self.first_event_id.get_or_insert(rec.event_id);
+        self.last_event_id = Some(rec.event_id);
+        self.node_id = rec.node_id.or(self.node_id);
+        self.kind = kind.or(self.kind);
+    }
+
+    /// Marks the node and every node below it that still existed as removed
+    fn remove(&mut self) {
+        self.status = Some(NodeStatus::Removed);
+        self.renamed_to = None;
+        for child in self.children.values_mut() {
+            if child.live() {
+                child.remove();
+            }
+        }
+    }
+
+    /// Keeps the nodes with one of the statuses (and the directories leading to them), returning
+    /// if anything is left
+    fn retain(&mut self, statuses: &[NodeStatus]) -> bool {
+        self.children.retain(|_, child| child.retain(statuses));
+        statuses.contains(&self.status()) || !self.children.is_empty()
+    }
+}
+
+/// A flattened node
+#[derive(Debug, Serialize)]
+pub struct TreeEntry<'a> {
+    pub path: &'a str,
+    pub status: NodeStatus,
+    pub kind: Option<NodeKind>,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub node_id: Option<u64>,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub first_event_id: Option<u64>,
+    #[cfg_attr(feature = "hex", serde(with = "SerHexOpt::<CompactCapPfx>"))]
+    pub last_event_id: Option<u64>,
+    /// How many records touched the node
+    pub events: u64,
+    /// The path a node was renamed from
+    pub renamed_from: Option<&'a str>,
+    /// The path a `renamed_from` node was renamed to
+    pub renamed_to: Option<&'a str>,
+}
+
+/// The flags that decide what happens to a node
+#[derive(Debug)]
+struct Masks {
+    removed: u32,
+    renamed: u32,
+    dir: u32,
+    file: u32,
+    symlink: u32,
+}
+
+/// Replays the records (given in event id order) into a directory tree
+#[derive(Debug)]
+pub struct DirTree {
+    root: Node,
+    masks: Masks,
+    /// The renamed record waiting for the other half of its pair
+    rename_from: Option<Arc<Record>>,
+}
+
+impl Default for DirTree {
+    fn default() -> Self {
+        let flag = |name| flags::flag_id(name).expect("Known flag");
+        Self {
+            root: Node::default(),
+            masks: Masks {
+                removed: flag("Removed"),
+                renamed: flag("Renamed"),
+                dir: flag("FolderEvent"),
+                file: flag("FileEvent"),
+                symlink: flag("SymbolicLink"),
+            },
+            rename_from: None,
+        }
+    }
+}
+
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+impl DirTree {
+    /// Adds the next record
+    pub fn push(&mut self, rec: &Arc<Record>) {
+        if rec.flag & self.masks.renamed != 0 {
+            match self.rename_from.take() {
+                Some(from) if Self::paired(&from, rec) => self.rename(&from, rec),
+                from => {
+                    if let Some(from) = from {
+                        self.unpaired(&from);
+                    }
+                    self.rename_from = Some(rec.clone());
+                }
+            }
+            return;
+        }
+
+        if let Some(from) = self.rename_from.take() {
+            self.unpaired(&from);
+        }
+        let status = if rec.flag & self.masks.removed != 0 {
+            NodeStatus::Removed
+        } else {
+            NodeStatus::Present
+        };
+        self.apply(rec, status);
+    }
+
+    /// Settles the rename that's still waiting for its pair
+    pub fn finish(&mut self) {
+        if let Some(from) = self.rename_from.take() {
+            self.unpaired(&from);
+        }
+    }
+
+    /// Keeps the nodes with one of the statuses (and the directories leading to them)
+    pub fn retain(&mut self, statuses: &[NodeStatus]) {
+        if !statuses.is_empty() {
+            self.root.children.retain(|_, child| child.retain(statuses));
+        }
+    }
+
+    fn paired(from: &Record, to: &Record) -> bool {
+        match (from.node_id, to.node_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => to.event_id == from.event_id + 1,
+        }
+    }
+
+    fn kind(&self, flag: u32) -> Option<NodeKind> {
+        if flag & self.masks.dir != 0 {
+            Some(NodeKind::Dir)
+        } else if flag & self.masks.symlink != 0 {
+            Some(NodeKind::Symlink)
+        } else if flag & self.masks.file != 0 {
+            Some(NodeKind::File)
+        } else {
+            None
+        }
+    }
+
+    /// Finds (or adds) the node at the path, marking the directories above it as present if the
+    /// node is
+    fn node_mut(&mut self, path: &str, live: bool) -> Option<&mut Node> {
+        let comps = components(path);
+        let (last, parents) = comps.split_last()?;
+
+        let mut node = &mut self.root;
+        for comp in parents {
+            node = node.children.entry(comp.to_string()).or_default();
+            if live {
+                node.status = Some(NodeStatus::Present);
+                node.kind = Some(NodeKind::Dir);
+                node.renamed_to = None;
+            }
+        }
+        Some(node.children.entry(last.to_string()).or_default())
+    }
+
+    fn apply(&mut self, rec: &Record, status: NodeStatus) {
+        let kind = self.kind(rec.flag);
+        let Some(node) = self.node_mut(rec.key_path(), status == NodeStatus::Present) else {
+            return;
+        };
+
+        node.touch(rec, kind);
+        match status {
+            NodeStatus::Removed => node.remove(),
+            status => {
+                node.status = Some(status);
+                node.renamed_to = None;
+            }
+        }
+    }
+
+    /// A rename without a pair moved the node in or out of the volume, so there's no telling if
+    /// it's still there
+    fn unpaired(&mut self, rec: &Record) {
+        let status = if rec.flag & self.masks.removed != 0 {
+            NodeStatus::Removed
+        } else {
+            NodeStatus::Unknown
+        };
+        self.apply(rec, status);
+    }
+
+    fn rename(&mut self, from: &Record, to: &Record) {
+        let (from_path, to_path) = (from.key_path(), to.key_path());
+        if components(from_path) == components(to_path) {
+            self.apply(to, NodeStatus::Present);
+            return;
+        }
+
+        let kind = self.kind(from.flag | to.flag);
+        let from_removed = from.flag & self.masks.removed != 0;
+        let to_removed = to.flag & self.masks.removed != 0;
+
+        // The children that still existed move along with the node
+        let mut moved = BTreeMap::new();
+        if let Some(node) = self.node_mut(from_path, false) {
+            node.touch(from, kind);
+            let (live, stale) = std::mem::take(&mut node.children)
+                .into_iter()
+                .partition(|(_, child)| child.live());
+            moved = live;
+            node.children = stale;
+
+            if from_removed {
+                node.remove();
+            } else {
+                node.status = Some(NodeStatus::RenamedFrom);
+                node.renamed_to = Some(to_path.to_string());
+            }
+        }
+
+        if let Some(node) = self.node_mut(to_path, !to_removed) {
+            node.touch(to, kind);
+            node.renamed_from = Some(from_path.to_string());
+            node.renamed_to = None;
+            node.children.extend(moved);
+
+            if to_removed {
+                node.remove();
+            } else {
+                node.status = Some(NodeStatus::Present);
+            }
+        }
+    }
+
+    /// Writes the tree in the wanted format
+    ///
+    /// # Arguments
+    /// * `format` - How the tree should be written
+    /// * `writer` - The writer to output the tree to
+    /// * `csv_opts` - The csv dialect to use
+    pub fn write<W: Write>(
+        &self,
+        format: TreeFormat,
+        mut writer: W,
+        csv_opts: &CsvOpts,
+    ) -> Result<()> {
+        match format {
+            TreeFormat::Csv => {
+                let mut writer = csv_opts.writer(writer);
+                write_csv(&self.root, &mut String::new(), &mut writer)?;
+                writer.flush()?;
+            }
+            TreeFormat::Json => {
+                serde_json::to_writer(&mut writer, &json_children(&self.root, ""))?;
+                writeln!(writer)?;
+                writer.flush()?;
+            }
+            TreeFormat::Text => {
+                write_text(&self.root, "", true, &mut writer)?;
+                writer.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+fn entry<'a>(path: &'a str, node: &'a Node) -> TreeEntry<'a> {
+    TreeEntry {
+        path,
+        status: node.status(),
+        kind: node.kind,
+        node_id: node.node_id,
+        first_event_id: node.first_event_id,
+        last_event_id: node.last_event_id,
+        events: node.events,
+        renamed_from: node.renamed_from.as_deref(),
+        renamed_to: node.renamed_to.as_deref(),
+    }
+}
+
+/// Writes a row for every node below `node` (parents before their children)
+fn write_csv<W: Write>(node: &Node, path: &mut String, writer: &mut csv::Writer<W>) -> Result<()> {
+    for (name, child) in node.children.iter() {
+        let len = path.len();
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str(name);
+
+        writer.serialize(entry(path, child))?;
+        write_csv(child, path, writer)?;
+        path.truncate(len);
+    }
+
+    Ok(())
+}
+
+/// A node with its children for the json output (built while it's serialized)
+struct JsonNode<'a> {
+    name: &'a str,
+    path: String,
+    node: &'a Node,
+}
+
+impl Serialize for JsonNode<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Fields<'a> {
+            name: &'a str,
+            #[serde(flatten)]
+            entry: TreeEntry<'a>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            children: Vec<JsonNode<'a>>,
+        }
+
+        Fields {
+            name: self.name,
+            entry: entry(&self.path, self.node),
+            children: json_children(self.node, &self.path),
+        }
+        .serialize(serializer)
+    }
+}
+
+fn json_children<'a>(node: &'a Node, path: &str) -> Vec<JsonNode<'a>> {
+    node.children
+        .iter()
+        .map(|(name, child)| JsonNode {
+            name,
+            path: join(path, name),
+            node: child,
+        })
+        .collect()
+}
+
+/// Writes the nodes below `node` as an indented tree
+fn write_text<W: Write>(node: &Node, prefix: &str, top: bool, writer: &mut W) -> Result<()> {
+    let count = node.children.len();
+    for (i, (name, child)) in node.children.iter().enumerate() {
+        let last = i + 1 == count;
+        let (branch, indent) = match (top, last) {
+            (true, _) => ("", ""),
+            (false, true) => ("└── ", "    "),
+            (false, false) => ("├── ", "│   "),
+        };
+
+        write!(writer, "{prefix}{branch}{name}")?;
+        if child.kind == Some(NodeKind::Dir) || !child.children.is_empty() {
+            write!(writer, "/")?;
+        }
+        match (child.status(), &child.renamed_from, &child.renamed_to) {
+            (NodeStatus::Present, Some(from), _) => write!(writer, "  [renamed from {from}]")?,
+            (NodeStatus::Present, None, _) => {}
+            (NodeStatus::RenamedFrom, _, Some(to)) => write!(writer, "  [renamed to {to}]")?,
+            (status, _, _) => write!(writer, "  [{}]", status.name())?,
+        }
+        writeln!(writer)?;
+
+        write_text(child, &format!("{prefix}{indent}"), false, writer)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(tree: &DirTree) -> Vec<(String, NodeStatus)> {
+        let mut out = vec![];
+        let mut writer = csv::Writer::from_writer(vec![]);
+        write_csv(&tree.root, &mut String::new(), &mut writer).unwrap();
+        let data = writer.into_inner().unwrap();
+        for row in csv::Reader::from_reader(data.as_slice()).records() {
+            let row = row.unwrap();
+            let status = match &row[1] {
+                "present" => NodeStatus::Present,
+                "removed" => NodeStatus::Removed,
+                "renamed_from" => NodeStatus::RenamedFrom,
+                _ => NodeStatus::Unknown,
+            };
+            out.push((row[0].to_string(), status));
+        }
+        out
+    }
+
+    #[test]
+    fn test_tree_replay() {
+        use NodeStatus::*;
+
+        let mut tree = DirTree::default();
+        for rec in [
+            Record::event("a/keep.txt", 1, &["Created", "FileEvent"]).with_node_id(Some(1)),
+            Record::event("a/gone", 2, &["Created", "FolderEvent"]).with_node_id(Some(2)),
+            Record::event("a/gone/x", 3, &["Created", "FileEvent"]).with_node_id(Some(3)),
+            // A directory removed with everything in it
+            Record::event("a/gone", 4, &["Removed", "FolderEvent"]).with_node_id(Some(2)),
+            // A directory renamed with the file in it
+            Record::event("b/old", 5, &["Created", "FolderEvent"]).with_node_id(Some(4)),
+            Record::event("b/old/f", 6, &["Created", "FileEvent"]).with_node_id(Some(5)),
+            Record::event("b/old", 7, &["Renamed", "FolderEvent"]).with_node_id(Some(4)),
+            Record::event("b/new", 8, &["Renamed", "FolderEvent"]).with_node_id(Some(4)),
+            // Moved out of the volume
+            Record::event("c/moved", 9, &["Renamed", "FileEvent"]).with_node_id(Some(6)),
+            Record::event("c/other", 10, &["Modified", "FileEvent"]).with_node_id(Some(7)),
+            Record::event("c/other", 11, &["Removed", "FileEvent"]).with_node_id(Some(7)),
+        ] {
+            tree.push(&Arc::new(rec));
+        }
+        tree.finish();
+
+        assert_eq!(
+            statuses(&tree),
+            vec![
+                ("a".into(), Present),
+                ("a/gone".into(), Removed),
+                ("a/gone/x".into(), Removed),
+                ("a/keep.txt".into(), Present),
+                ("b".into(), Present),
+                ("b/new".into(), Present),
+                ("b/new/f".into(), Present),
+                ("b/old".into(), RenamedFrom),
+                ("c".into(), Present),
+                ("c/moved".into(), Unknown),
+                ("c/other".into(), Removed),
+            ]
+        );
+
+        let new = &tree.root.children["b"].children["new"];
+        assert_eq!(new.renamed_from.as_deref(), Some("b/old"));
+        assert_eq!((new.first_event_id, new.last_event_id), (Some(8), Some(8)));
+
+        // Renames pair up by event id without node ids
+        let mut tree = DirTree::default();
+        for rec in [
+            Record::event("d/x.tmp", 20, &["Created", "Renamed"]),
+            Record::event("d/x", 21, &["Renamed"]),
+        ] {
+            tree.push(&Arc::new(rec));
+        }
+        tree.finish();
+        assert_eq!(
+            statuses(&tree),
+            vec![
+                ("d".into(), Present),
+                ("d/x".into(), Present),
+                ("d/x.tmp".into(), RenamedFrom),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tree_retain_and_text() {
+        let mut tree = DirTree::default();
+        for rec in [
+            Record::event("a/b/c", 1, &["Created", "FileEvent"]).with_node_id(Some(1)),
+            Record::event("a/b/d", 2, &["Created", "FileEvent"]).with_node_id(Some(2)),
+            Record::event("a/b/d", 3, &["Removed", "FileEvent"]).with_node_id(Some(2)),
+            Record::event("a/e", 4, &["Renamed", "FileEvent"]).with_node_id(Some(3)),
+            Record::event("a/f", 5, &["Renamed", "FileEvent"]).with_node_id(Some(3)),
+        ] {
+            tree.push(&Arc::new(rec));
+        }
+        tree.finish();
+
+        let mut out = vec![];
+        tree.write(TreeFormat::Text, &mut out, &CsvOpts::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a/\n\
+             ├── b/\n\
+             │   ├── c\n\
+             │   └── d  [removed]\n\
+             ├── e  [renamed to a/f]\n\
+             └── f  [renamed from a/e]\n"
+        );
+
+        tree.retain(&[NodeStatus::Removed]);
+        let mut out = vec![];
+        tree.write(TreeFormat::Json, &mut out, &CsvOpts::default())
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let d = &json[0]["children"][0]["children"][0];
+        assert_eq!(json[0]["children"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            (d["path"].as_str(), d["status"].as_str()),
+            (Some("a/b/d"), Some("removed"))
+        );
+    }
+}
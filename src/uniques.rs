@@ -1,11 +1,32 @@
 //! Unique path aggregation and counting
 //!
 //! This module provides structures to aggregate FSEvents records by path,
-//! combining their flags and counting occurrences.
-
+//! combining their flags and counting occurrences. The unique paths can also be
+//! rolled up into their directories to see which areas of the disk were busy.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bus::BusReader;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use hashbrown::HashSet;
 use jiff::Timestamp;
+use serde::{Serialize, ser::SerializeStruct};
 
-use crate::flags as f;
+use crate::{
+    extsort::{self, ExternalSorter, Sorted, Spill},
+    flags as f,
+    histogram::Bucket,
+    opts::{CompressOpts, CsvOpts, Format, UniquesFormat},
+    output::RecordWriter,
+    record::Record,
+};
 
 /// Aggregates counts and flags for a unique path
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
@@ -52,6 +73,23 @@ impl UniqueCounts {
         }
     }
 
+    /// Combines the counts of another path into these
+    ///
+    /// # Arguments
+    /// * `other` - The counts to add
+    pub fn merge(&mut self, other: &UniqueCounts) {
+        self.counts += other.counts;
+        self.flags |= other.flags;
+        self.earliest_timestamp = match (self.earliest_timestamp, other.earliest_timestamp) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.latest_timestamp = match (self.latest_timestamp, other.latest_timestamp) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
     /// Converts internal counts to output format with parsed flag strings
     ///
     /// # Arguments
@@ -63,11 +101,19 @@ impl UniqueCounts {
     pub fn into_unique_out(self, path: String) -> UniqueOut {
         let flags = f::parse_bits(self.flags);
         UniqueOut {
+            bucket: None,
             path,
             counts: self.counts,
             flags: flags.norm,
             #[cfg(feature = "alt_flags")]
             alt_flags: flags.alt,
+            children: None,
+            flag_counts: None,
+            first_event_id: None,
+            last_event_id: None,
+            node_ids: None,
+            source_files: None,
+            timestamps: true,
             earliest_timestamp: self.earliest_timestamp,
             latest_timestamp: self.latest_timestamp,
         }
@@ -84,11 +130,19 @@ impl UniqueCounts {
     pub fn into_unique_out_no_timestamps(self, path: String) -> UniqueOut {
         let flags = f::parse_bits(self.flags);
         UniqueOut {
+            bucket: None,
             path,
             counts: self.counts,
             flags: flags.norm,
             #[cfg(feature = "alt_flags")]
             alt_flags: flags.alt,
+            children: None,
+            flag_counts: None,
+            first_event_id: None,
+            last_event_id: None,
+            node_ids: None,
+            source_files: None,
+            timestamps: false,
             earliest_timestamp: None,
             latest_timestamp: None,
         }
@@ -96,41 +150,811 @@ impl UniqueCounts {
 }
 
 /// Output structure for unique path aggregation results
-#[derive(Debug, Serialize)]
+///
+/// The optional columns are written based on what's wanted rather than on their values so every
+/// row has the same columns in every format
+#[derive(Debug)]
 pub struct UniqueOut {
+    /// The time bucket of the row (only for bucketed uniques)
+    pub bucket: Option<String>,
     pub path: String,
     pub counts: u64,
     pub flags: &'static str,
     #[cfg(feature = "alt_flags")]
     pub alt_flags: &'static str,
-    #[serde(
-        skip_serializing_if = "should_skip_timestamp",
-        serialize_with = "serialize_optional_timestamp"
-    )]
+    /// How many distinct paths below the directory had events (only for rolled up uniques)
+    pub children: Option<u64>,
+    /// How many records had each flag (`Created=2 | Modified=40`)
+    pub flag_counts: Option<String>,
+    pub first_event_id: Option<u64>,
+    pub last_event_id: Option<u64>,
+    /// The distinct node ids separated by ` | `
+    pub node_ids: Option<String>,
+    /// How many source files the path was in
+    pub source_files: Option<u64>,
+    /// If the timestamp columns are written
+    timestamps: bool,
     pub earliest_timestamp: Option<Timestamp>,
-    #[serde(
-        skip_serializing_if = "should_skip_timestamp",
-        serialize_with = "serialize_optional_timestamp"
-    )]
     pub latest_timestamp: Option<Timestamp>,
 }
 
-/// Helper to determine if timestamp should be skipped during serialization
-fn should_skip_timestamp(ts: &Option<Timestamp>) -> bool {
-    ts.is_none()
+#[cfg(feature = "sqlite")]
+impl UniqueOut {
+    /// A row with the columns every row of the output has (its values are only placeholders of
+    /// the right types) so a table can be created before there are any rows
+    pub fn template(out: &UniquesOutput) -> Self {
+        let wanted = |detail: UniqueDetail| detail.wanted(&out.details);
+        UniqueOut {
+            bucket: out.bucket.map(|_| String::new()),
+            path: String::new(),
+            counts: 0,
+            flags: "",
+            #[cfg(feature = "alt_flags")]
+            alt_flags: "",
+            children: out.rollup.map(|_| 0),
+            flag_counts: wanted(UniqueDetail::FlagCounts).then(String::new),
+            first_event_id: wanted(UniqueDetail::EventIds).then_some(0),
+            last_event_id: wanted(UniqueDetail::EventIds).then_some(0),
+            node_ids: wanted(UniqueDetail::NodeIds).then(String::new),
+            source_files: wanted(UniqueDetail::SourceFiles).then_some(0),
+            timestamps: out.timestamps,
+            earliest_timestamp: Some(Timestamp::UNIX_EPOCH),
+            latest_timestamp: Some(Timestamp::UNIX_EPOCH),
+        }
+    }
 }
 
-/// Custom serializer for `Option<Timestamp>` to produce ISO 8601 format
-fn serialize_optional_timestamp<S>(
-    timestamp: &Option<Timestamp>,
-    serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    match timestamp {
-        Some(ts) => serializer.serialize_str(&ts.to_string()),
-        None => serializer.serialize_none(),
+impl Serialize for UniqueOut {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        #[cfg(feature = "hex")]
+        let event_id = |id: Option<u64>| id.map(|id| format!("{id:#X}"));
+        #[cfg(not(feature = "hex"))]
+        let event_id = |id: Option<u64>| id;
+
+        let mut s = serializer.serialize_struct("UniqueOut", 14)?;
+        if let Some(bucket) = &self.bucket {
+            s.serialize_field("bucket", bucket)?;
+        }
+        s.serialize_field("path", &self.path)?;
+        s.serialize_field("counts", &self.counts)?;
+        s.serialize_field("flags", self.flags)?;
+        #[cfg(feature = "alt_flags")]
+        s.serialize_field("alt_flags", self.alt_flags)?;
+
+        if let Some(children) = self.children {
+            s.serialize_field("children", &children)?;
+        }
+        if let Some(flag_counts) = &self.flag_counts {
+            s.serialize_field("flag_counts", flag_counts)?;
+        }
+        if self.first_event_id.is_some() {
+            s.serialize_field("first_event_id", &event_id(self.first_event_id))?;
+            s.serialize_field("last_event_id", &event_id(self.last_event_id))?;
+        }
+        if let Some(node_ids) = &self.node_ids {
+            s.serialize_field("node_ids", node_ids)?;
+        }
+        if let Some(source_files) = self.source_files {
+            s.serialize_field("source_files", &source_files)?;
+        }
+
+        if self.timestamps {
+            s.serialize_field(
+                "earliest_timestamp",
+                &self.earliest_timestamp.map(|ts| ts.to_string()),
+            )?;
+            s.serialize_field(
+                "latest_timestamp",
+                &self.latest_timestamp.map(|ts| ts.to_string()),
+            )?;
+        }
+
+        s.end()
+    }
+}
+
+/// The optional detail columns of the uniques
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum UniqueDetail {
+    /// How many records had each flag
+    FlagCounts,
+    /// The first and last event id
+    EventIds,
+    /// The distinct node ids
+    NodeIds,
+    /// How many source files the path was in
+    SourceFiles,
+    /// Every detail column
+    All,
+}
+
+impl UniqueDetail {
+    fn wanted(self, details: &[UniqueDetail]) -> bool {
+        details.contains(&self) || details.contains(&UniqueDetail::All)
+    }
+}
+
+/// The details kept for a unique path when any of the detail columns are wanted (kept apart
+/// from `UniqueCounts` since they don't fit in a `Copy` type)
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UniqueDetails {
+    /// How many records had each flag bit
+    flag_counts: BTreeMap<u32, u64>,
+    first_event_id: Option<u64>,
+    last_event_id: Option<u64>,
+    node_ids: BTreeSet<u64>,
+    sources: HashSet<Arc<Path>>,
+}
+
+impl UniqueDetails {
+    /// Adds a record to the details
+    pub fn update(&mut self, rec: &Record) {
+        for bit in f::iter_bits(rec.flag) {
+            *self.flag_counts.entry(bit).or_default() += 1;
+        }
+
+        self.first_event_id = Some(
+            self.first_event_id
+                .map_or(rec.event_id, |id| id.min(rec.event_id)),
+        );
+        self.last_event_id = Some(
+            self.last_event_id
+                .map_or(rec.event_id, |id| id.max(rec.event_id)),
+        );
+        self.node_ids.extend(rec.node_id);
+        if let Some(source) = &rec.source
+            && !self.sources.contains(source)
+        {
+            self.sources.insert(source.clone());
+        }
+    }
+
+    /// Combines the details of another path into these
+    pub fn merge(&mut self, other: UniqueDetails) {
+        for (bit, count) in other.flag_counts {
+            *self.flag_counts.entry(bit).or_default() += count;
+        }
+        self.first_event_id = match (self.first_event_id, other.first_event_id) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.last_event_id = match (self.last_event_id, other.last_event_id) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        self.node_ids.extend(other.node_ids);
+        self.sources.extend(other.sources);
+    }
+
+    /// Fills in the wanted detail columns
+    fn fill(self, out: &mut UniqueOut, details: &[UniqueDetail]) {
+        if UniqueDetail::FlagCounts.wanted(details) {
+            let mut counts = String::new();
+            for (bit, count) in self.flag_counts {
+                if !counts.is_empty() {
+                    counts.push_str(" | ");
+                }
+                let _ = write!(counts, "{}={count}", f::parse_bits(bit).norm);
+            }
+            out.flag_counts = Some(counts);
+        }
+
+        if UniqueDetail::EventIds.wanted(details) {
+            out.first_event_id = self.first_event_id;
+            out.last_event_id = self.last_event_id;
+        }
+
+        if UniqueDetail::NodeIds.wanted(details) {
+            let mut ids = String::new();
+            for id in self.node_ids {
+                if !ids.is_empty() {
+                    ids.push_str(" | ");
+                }
+                #[cfg(feature = "hex")]
+                let _ = write!(ids, "{id:#X}");
+                #[cfg(not(feature = "hex"))]
+                let _ = write!(ids, "{id}");
+            }
+            out.node_ids = Some(ids);
+        }
+
+        if UniqueDetail::SourceFiles.wanted(details) {
+            out.source_files = Some(self.sources.len() as u64);
+        }
+    }
+}
+
+/// Everything kept for a unique path (or a rolled up directory)
+#[derive(Clone, Debug, Default)]
+pub struct UniqueEntry {
+    pub counts: UniqueCounts,
+    /// Only kept when any of the detail columns are wanted
+    pub details: Option<UniqueDetails>,
+    /// How many distinct paths were rolled into the entry (only for rolled up uniques)
+    pub children: Option<u64>,
+}
+
+impl UniqueEntry {
+    /// Creates an empty entry, keeping the details if they're wanted
+    pub fn new(details: bool) -> Self {
+        Self {
+            details: details.then(UniqueDetails::default),
+            ..Self::default()
+        }
+    }
+
+    /// Adds a record to the entry
+    #[inline]
+    pub fn update(&mut self, rec: &Record) {
+        self.counts.update(rec.flag, rec.file_timestamp);
+        if let Some(details) = self.details.as_mut() {
+            details.update(rec);
+        }
+    }
+
+    /// A rough count of the bytes the entry (and its path) takes up in the aggregation map
+    fn mem_size(&self, path: &str) -> usize {
+        let details = self.details.as_ref().map_or(0, |d| {
+            std::mem::size_of::<UniqueDetails>()
+                + d.flag_counts.len() * 32
+                + d.node_ids.len() * 16
+                + d.sources.len() * 24
+        });
+
+        // The map's node overhead is about the size of an entry
+        path.len() + 2 * std::mem::size_of::<(String, UniqueEntry)>() + details
+    }
+
+    /// Combines the entry of another path into this one
+    fn merge(&mut self, other: UniqueEntry) {
+        self.counts.merge(&other.counts);
+        match (self.details.as_mut(), other.details) {
+            (Some(details), Some(other)) => details.merge(other),
+            (None, other) => self.details = other,
+            (_, None) => {}
+        }
+    }
+
+    /// Converts the entry to the output format
+    ///
+    /// # Arguments
+    /// * `path` - The path of the entry
+    /// * `timestamps` - If the earliest/latest timestamps should be included
+    /// * `details` - The detail columns to include
+    pub fn into_unique_out(
+        self,
+        path: String,
+        timestamps: bool,
+        details: &[UniqueDetail],
+    ) -> UniqueOut {
+        let mut out = if timestamps {
+            self.counts.into_unique_out(path)
+        } else {
+            self.counts.into_unique_out_no_timestamps(path)
+        };
+        out.children = self.children;
+        if let Some(d) = self.details {
+            d.fill(&mut out, details);
+        }
+        out
+    }
+}
+
+/// How the unique paths are rolled up into directories
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rollup {
+    /// Into the directory prefix with this many components (shallower paths are kept)
+    Depth(usize),
+    /// Into the directory each path is in
+    Parent,
+}
+
+impl Rollup {
+    /// The directory the path is rolled up into (`/` for the root)
+    pub fn key(self, path: &str) -> &str {
+        let path = path.trim_end_matches('/');
+        let start = usize::from(path.starts_with('/'));
+
+        let key = match self {
+            Rollup::Depth(depth) => path[start..]
+                .match_indices('/')
+                .nth(depth.saturating_sub(1))
+                .map_or(path, |(i, _)| &path[..start + i]),
+            Rollup::Parent => path.rsplit_once('/').map_or("", |(dir, _)| dir),
+        };
+
+        if key.is_empty() { "/" } else { key }
+    }
+
+    /// Rolls the unique paths up into their directories
+    ///
+    /// # Returns
+    /// The combined entries of every directory, with how many distinct paths below it had events
+    /// as their `children`
+    pub fn roll_up(
+        self,
+        uniques: impl IntoIterator<Item = (String, UniqueEntry)>,
+    ) -> BTreeMap<String, UniqueEntry> {
+        let mut dirs: BTreeMap<String, UniqueEntry> = BTreeMap::new();
+
+        for (path, entry) in uniques {
+            let key = self.key(&path);
+            let is_child = key != path.trim_end_matches('/');
+            let dir = match dirs.get_mut(key) {
+                Some(dir) => dir,
+                None => dirs.entry(key.to_string()).or_default(),
+            };
+
+            dir.merge(entry);
+            *dir.children.get_or_insert(0) += u64::from(is_child);
+        }
+
+        dirs
+    }
+}
+
+/// A partial aggregate spilled to disk (ordered by the bucket and path alone so the runs of a
+/// path merge next to each other)
+#[derive(Debug)]
+struct SpilledUnique {
+    bucket: String,
+    path: String,
+    entry: UniqueEntry,
+}
+
+impl SpilledUnique {
+    fn key(&self) -> (&str, &str) {
+        (&self.bucket, &self.path)
+    }
+}
+
+impl PartialEq for SpilledUnique {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for SpilledUnique {}
+
+impl PartialOrd for SpilledUnique {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SpilledUnique {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+fn write_opt_ts<W: Write>(w: &mut W, ts: Option<Timestamp>) -> io::Result<()> {
+    match ts {
+        None => w.write_u8(0),
+        Some(ts) => {
+            w.write_u8(1)?;
+            w.write_i128::<LittleEndian>(ts.as_nanosecond())
+        }
+    }
+}
+
+fn read_opt_ts<R: Read>(r: &mut R) -> io::Result<Option<Timestamp>> {
+    Ok(match r.read_u8()? {
+        0 => None,
+        _ => Some(
+            Timestamp::from_nanosecond(r.read_i128::<LittleEndian>()?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        ),
+    })
+}
+
+impl Spill for SpilledUnique {
+    fn spill<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let SpilledUnique {
+            bucket,
+            path,
+            entry,
+        } = self;
+        // Every item starts with a marker byte so the end of the run can be told apart
+        w.write_u8(1)?;
+        extsort::write_bytes(w, bucket.as_bytes())?;
+        extsort::write_bytes(w, path.as_bytes())?;
+
+        let counts = &entry.counts;
+        w.write_u64::<LittleEndian>(counts.counts)?;
+        w.write_u32::<LittleEndian>(counts.flags)?;
+        write_opt_ts(w, counts.earliest_timestamp)?;
+        write_opt_ts(w, counts.latest_timestamp)?;
+        extsort::write_opt_u64(w, entry.children)?;
+
+        match &entry.details {
+            None => w.write_u8(0)?,
+            Some(d) => {
+                w.write_u8(1)?;
+                w.write_u32::<LittleEndian>(d.flag_counts.len() as u32)?;
+                for (bit, count) in d.flag_counts.iter() {
+                    w.write_u32::<LittleEndian>(*bit)?;
+                    w.write_u64::<LittleEndian>(*count)?;
+                }
+                extsort::write_opt_u64(w, d.first_event_id)?;
+                extsort::write_opt_u64(w, d.last_event_id)?;
+                w.write_u32::<LittleEndian>(d.node_ids.len() as u32)?;
+                for id in d.node_ids.iter() {
+                    w.write_u64::<LittleEndian>(*id)?;
+                }
+                w.write_u32::<LittleEndian>(d.sources.len() as u32)?;
+                for src in d.sources.iter() {
+                    extsort::write_os_str(w, src.as_os_str())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unspill<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+        match r.read_u8() {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let bucket = extsort::read_string(r)?;
+        let path = extsort::read_string(r)?;
+        let counts = UniqueCounts {
+            counts: r.read_u64::<LittleEndian>()?,
+            flags: r.read_u32::<LittleEndian>()?,
+            earliest_timestamp: read_opt_ts(r)?,
+            latest_timestamp: read_opt_ts(r)?,
+        };
+        let children = extsort::read_opt_u64(r)?;
+
+        let details = match r.read_u8()? {
+            0 => None,
+            _ => {
+                let mut d = UniqueDetails::default();
+                for _ in 0..r.read_u32::<LittleEndian>()? {
+                    let bit = r.read_u32::<LittleEndian>()?;
+                    d.flag_counts.insert(bit, r.read_u64::<LittleEndian>()?);
+                }
+                d.first_event_id = extsort::read_opt_u64(r)?;
+                d.last_event_id = extsort::read_opt_u64(r)?;
+                for _ in 0..r.read_u32::<LittleEndian>()? {
+                    d.node_ids.insert(r.read_u64::<LittleEndian>()?);
+                }
+                for _ in 0..r.read_u32::<LittleEndian>()? {
+                    d.sources
+                        .insert(Arc::from(PathBuf::from(extsort::read_os_string(r)?)));
+                }
+                Some(d)
+            }
+        };
+
+        Ok(Some(SpilledUnique {
+            bucket,
+            path,
+            entry: UniqueEntry {
+                counts,
+                details,
+                children,
+            },
+        }))
+    }
+}
+
+/// A bucket and path with its aggregate
+type UniqueRow = (String, String, UniqueEntry);
+
+/// Merges the spilled runs, combining the partial aggregates of each path
+struct MergedUniques {
+    sorted: Sorted<SpilledUnique>,
+    pending: Option<SpilledUnique>,
+}
+
+impl Iterator for MergedUniques {
+    type Item = io::Result<UniqueRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut cur = match self.pending.take() {
+            Some(cur) => cur,
+            None => match self.sorted.next()? {
+                Ok(cur) => cur,
+                Err(err) => return Some(Err(err)),
+            },
+        };
+
+        loop {
+            match self.sorted.next() {
+                None => break,
+                Some(Err(err)) => return Some(Err(err)),
+                Some(Ok(next)) if next == cur => cur.entry.merge(next.entry),
+                Some(Ok(next)) => {
+                    self.pending = Some(next);
+                    break;
+                }
+            }
+        }
+
+        Some(Ok((cur.bucket, cur.path, cur.entry)))
+    }
+}
+
+/// Aggregates the records by bucket and path, spilling the partial aggregates to disk (as sorted
+/// runs) once they take up more than the memory budget
+pub struct UniquesAggregator {
+    /// The aggregates of each bucket (a single empty one if the uniques aren't bucketed)
+    uniques: BTreeMap<String, BTreeMap<String, UniqueEntry>>,
+    details: bool,
+    bucket: Option<Bucket>,
+    /// The memory budget in bytes (unbounded if not set)
+    budget: Option<usize>,
+    used: usize,
+    tmp_dir: PathBuf,
+    runs: Option<ExternalSorter<SpilledUnique>>,
+}
+
+impl UniquesAggregator {
+    /// Creates a new aggregator
+    ///
+    /// # Arguments
+    /// * `details` - If the detail columns are wanted
+    /// * `bucket` - What the records are bucketed by (if they are)
+    /// * `budget` - How many bytes the in memory aggregates may take before they're spilled
+    /// * `tmp_dir` - Where the spilled runs are written
+    pub fn new(
+        details: bool,
+        bucket: Option<Bucket>,
+        budget: Option<u64>,
+        tmp_dir: PathBuf,
+    ) -> Self {
+        Self {
+            uniques: BTreeMap::new(),
+            details,
+            bucket,
+            budget: budget.map(|b| b as usize),
+            used: 0,
+            tmp_dir,
+            runs: None,
+        }
+    }
+
+    /// Adds a record to its bucket and path's aggregate
+    pub fn push(&mut self, rec: &Record) -> io::Result<()> {
+        let bucket = self.bucket.map(|b| b.key(rec)).unwrap_or_default();
+        let uniques = match self.uniques.get_mut(&bucket) {
+            Some(uniques) => uniques,
+            None => {
+                self.used += bucket.len() + std::mem::size_of::<(String, BTreeMap<(), ()>)>();
+                self.uniques.entry(bucket).or_default()
+            }
+        };
+
+        let Some(budget) = self.budget else {
+            uniques
+                .entry(rec.key_path().to_string())
+                .or_insert_with(|| UniqueEntry::new(self.details))
+                .update(rec);
+            return Ok(());
+        };
+
+        let path = rec.key_path();
+        let entry = match uniques.get_mut(path) {
+            Some(entry) => entry,
+            None => {
+                let entry = UniqueEntry::new(self.details);
+                self.used += entry.mem_size(path);
+                uniques.entry(path.to_string()).or_insert(entry)
+            }
+        };
+        let before = entry.mem_size(path);
+        entry.update(rec);
+        self.used += entry.mem_size(path) - before;
+
+        if self.used > budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let runs = self.runs.get_or_insert_with(|| {
+            // The runs are pushed whole so the sorter's own buffer is never used
+            ExternalSorter::new(1, self.tmp_dir.clone())
+        });
+        runs.push_run(std::mem::take(&mut self.uniques).into_iter().flat_map(
+            |(bucket, uniques)| {
+                uniques.into_iter().map(move |(path, entry)| SpilledUnique {
+                    bucket: bucket.clone(),
+                    path,
+                    entry,
+                })
+            },
+        ))?;
+        self.used = 0;
+        Ok(())
+    }
+
+    /// Finishes the aggregation, returning every bucket and path with its aggregate in order
+    pub fn finish(mut self) -> io::Result<Box<dyn Iterator<Item = io::Result<UniqueRow>>>> {
+        if self.runs.is_none() {
+            return Ok(Box::new(self.uniques.into_iter().flat_map(
+                |(bucket, uniques)| {
+                    uniques
+                        .into_iter()
+                        .map(move |(path, entry)| Ok((bucket.clone(), path, entry)))
+                },
+            )));
+        }
+
+        if !self.uniques.is_empty() {
+            self.spill()?;
+        }
+        let sorted = self.runs.take().expect("Spilled runs").finish()?;
+        Ok(Box::new(MergedUniques {
+            sorted,
+            pending: None,
+        }))
+    }
+}
+
+/// Where and how the uniques are written
+#[derive(Clone, Debug)]
+pub struct UniquesOutput {
+    pub path: PathBuf,
+    pub format: UniquesFormat,
+    /// If the earliest/latest timestamps are included
+    pub timestamps: bool,
+    /// How the paths are rolled up into directories (if they are)
+    pub rollup: Option<Rollup>,
+    /// The detail columns to include
+    pub details: Vec<UniqueDetail>,
+    /// What the uniques are bucketed by (if they are)
+    pub bucket: Option<Bucket>,
+    /// The memory budget of the aggregation in bytes (unbounded if not set)
+    pub memory: Option<u64>,
+    /// Where the aggregates are spilled when they go over the memory budget
+    pub tmp_dir: PathBuf,
+}
+
+/// The writer the unique rows go to
+enum UniquesWriter {
+    Records(RecordWriter<Box<dyn std::io::Write>>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(crate::sqlite::SqliteWriter),
+}
+
+impl UniquesWriter {
+    fn open(
+        out: &UniquesOutput,
+        copts: CompressOpts,
+        csv_opts: &CsvOpts,
+    ) -> color_eyre::Result<Self> {
+        let format = match out.format {
+            UniquesFormat::Csv => Format::Csv,
+            UniquesFormat::Json => Format::Json,
+            UniquesFormat::Yaml => Format::Yaml,
+            #[cfg(feature = "sqlite")]
+            UniquesFormat::Sqlite => {
+                return Ok(UniquesWriter::Sqlite(crate::sqlite::SqliteWriter::create(
+                    &out.path,
+                    "uniques",
+                    &UniqueOut::template(out),
+                )?));
+            }
+            #[cfg(not(feature = "sqlite"))]
+            UniquesFormat::Sqlite => unreachable!("sqlite feature not enabled"),
+        };
+
+        let writer = copts.make_output(&out.path)?;
+        Ok(UniquesWriter::Records(RecordWriter::new(
+            format, writer, csv_opts, false,
+        )))
+    }
+
+    fn write(&mut self, row: &UniqueOut) -> color_eyre::Result<()> {
+        match self {
+            UniquesWriter::Records(w) => w.write(row),
+            #[cfg(feature = "sqlite")]
+            UniquesWriter::Sqlite(w) => w.write(row),
+        }
+    }
+
+    fn finish(self) -> color_eyre::Result<()> {
+        match self {
+            UniquesWriter::Records(mut w) => Ok(w.flush()?),
+            #[cfg(feature = "sqlite")]
+            UniquesWriter::Sqlite(w) => w.finish(),
+        }
+    }
+}
+
+/// Aggregates records by path and writes unique path counts with combined flags
+///
+/// # Arguments
+/// * `recv` - Bus reader receiving record updates
+/// * `out` - Where and how the uniques are written
+/// * `copts` - The compression options used to open the output
+/// * `csv_opts` - The csv dialect to use
+pub fn uniques_write(
+    recv: BusReader<Arc<Record>>,
+    out: UniquesOutput,
+    copts: CompressOpts,
+    csv_opts: CsvOpts,
+) {
+    let mut writer = match UniquesWriter::open(&out, copts, &csv_opts) {
+        Ok(w) => w,
+        Err(err) => {
+            error!(
+                "Couldn't create the uniques output {}: {err}",
+                out.path.display()
+            );
+            return;
+        }
+    };
+
+    let mut aggregator = UniquesAggregator::new(
+        !out.details.is_empty(),
+        out.bucket,
+        out.memory,
+        out.tmp_dir.clone(),
+    );
+    for rec in recv {
+        if let Err(err) = aggregator.push(&rec) {
+            error!("Couldn't spill the uniques: {err}");
+            return;
+        }
+    }
+
+    let merged = match aggregator.finish() {
+        Ok(merged) => merged,
+        Err(err) => {
+            error!("Couldn't merge the uniques: {err}");
+            return;
+        }
+    };
+    let mut merged = merged
+        .map_while(|item| match item {
+            Ok(item) => Some(item),
+            Err(err) => {
+                error!("Couldn't read the spilled uniques: {err}");
+                None
+            }
+        })
+        .peekable();
+
+    let mut write = |bucket: &str, path: String, entry: UniqueEntry| {
+        let mut row = entry.into_unique_out(path, out.timestamps, &out.details);
+        if out.bucket.is_some() {
+            row.bucket = Some(bucket.to_string());
+        }
+        if let Err(err) = writer.write(&row) {
+            error!("Error writing the uniques: {err}");
+        }
+    };
+
+    // The rows come ordered by bucket so each bucket is rolled up on its own
+    while let Some((bucket, path, entry)) = merged.next() {
+        let Some(rollup) = out.rollup else {
+            write(&bucket, path, entry);
+            continue;
+        };
+
+        let rest = std::iter::from_fn(|| {
+            merged
+                .next_if(|(b, _, _)| *b == bucket)
+                .map(|(_, path, entry)| (path, entry))
+        });
+        let dirs = rollup.roll_up(std::iter::once((path, entry)).chain(rest));
+        for (path, entry) in dirs {
+            write(&bucket, path, entry);
+        }
+    }
+
+    if let Err(err) = writer.finish() {
+        error!("Couldn't finish the uniques: {err}");
     }
 }
 
@@ -461,6 +1285,294 @@ mod tests {
         assert!(uc.earliest_timestamp < uc.latest_timestamp);
     }
 
+    #[test]
+    fn test_unique_out_consistent_columns() {
+        let mut with_ts = UniqueEntry::new(false);
+        with_ts.update(
+            &Record::default()
+                .with_flag(0x1000_0000)
+                .with_timestamp(ts(1))
+                .with_event_id(1)
+                .with_source("a"),
+        );
+        let mut without_ts = UniqueEntry::new(false);
+        without_ts.update(&Record {
+            flag: 0x1000_0000,
+            ..Record::default()
+        });
+
+        for timestamps in [false, true] {
+            let mut writer = RecordWriter::new(Format::Csv, vec![], &CsvOpts::default(), false);
+            for entry in [with_ts.clone(), without_ts.clone()] {
+                writer
+                    .write(&entry.into_unique_out("p".into(), timestamps, &[]))
+                    .unwrap();
+            }
+            let RecordWriter::Csv(w) = writer else {
+                unreachable!()
+            };
+            let out = String::from_utf8(w.into_inner().unwrap()).unwrap();
+            let header = out.lines().next().unwrap();
+            assert_eq!(header.contains("earliest_timestamp"), timestamps);
+            let columns = header.split(',').count();
+            assert!(
+                out.lines().all(|l| l.split(',').count() == columns),
+                "{out}"
+            );
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_unique_out_template_columns() {
+        let columns = |row: &UniqueOut| -> Vec<String> {
+            let serde_json::Value::Object(row) = serde_json::to_value(row).unwrap() else {
+                unreachable!()
+            };
+            row.keys().cloned().collect()
+        };
+
+        for (timestamps, rollup, details, bucket) in [
+            (false, None, vec![], None),
+            (
+                true,
+                Some(Rollup::Parent),
+                vec![UniqueDetail::All],
+                Some(Bucket::Day),
+            ),
+            (true, None, vec![UniqueDetail::NodeIds], None),
+        ] {
+            let out = UniquesOutput {
+                path: "-".into(),
+                format: UniquesFormat::Json,
+                timestamps,
+                rollup,
+                details,
+                bucket,
+                memory: None,
+                tmp_dir: PathBuf::new(),
+            };
+
+            let mut entry = UniqueEntry::new(!out.details.is_empty());
+            entry.update(&Record::default().with_flag(0x1000_0000).with_source("a"));
+            let mut row = match out.rollup {
+                Some(rollup) => rollup.roll_up([("a/b".to_string(), entry)]),
+                None => BTreeMap::from([("a/b".to_string(), entry)]),
+            }
+            .pop_first()
+            .map(|(path, entry)| entry.into_unique_out(path, out.timestamps, &out.details))
+            .unwrap();
+            if out.bucket.is_some() {
+                row.bucket = Some("2024-03-01".into());
+            }
+
+            assert_eq!(columns(&UniqueOut::template(&out)), columns(&row));
+        }
+    }
+
+    #[test]
+    fn test_rollup_key() {
+        assert_eq!(Rollup::Depth(2).key("Users/me/Documents/a.txt"), "Users/me");
+        assert_eq!(
+            Rollup::Depth(2).key("/Users/me/Documents/a.txt"),
+            "/Users/me"
+        );
+        assert_eq!(Rollup::Depth(2).key("Users/me"), "Users/me");
+        assert_eq!(Rollup::Depth(3).key("Users/me"), "Users/me");
+        assert_eq!(Rollup::Depth(1).key("private/var/"), "private");
+        assert_eq!(Rollup::Parent.key("Users/me/a.txt"), "Users/me");
+        assert_eq!(Rollup::Parent.key("a.txt"), "/");
+        assert_eq!(Rollup::Parent.key("/a.txt"), "/");
+    }
+
+    #[test]
+    fn test_roll_up() {
+        let mut uniques = BTreeMap::new();
+        for (path, flag, secs) in [
+            ("Users/me", 0x0000_0001, 50),
+            ("Users/me/a.txt", 0x0100_0000, 100),
+            ("Users/me/a.txt", 0x1000_0000, 300),
+            ("Users/me/b/c.txt", 0x0200_0000, 200),
+            ("tmp/x", 0x1000_0000, 400),
+        ] {
+            uniques
+                .entry(path.to_string())
+                .or_insert_with(|| UniqueEntry::new(false))
+                .update(
+                    &Record::default()
+                        .with_flag(flag)
+                        .with_timestamp(ts(secs))
+                        .with_event_id(0)
+                        .with_source("a"),
+                );
+        }
+
+        let dirs = Rollup::Depth(2).roll_up(uniques);
+        assert_eq!(dirs.keys().collect::<Vec<_>>(), vec!["Users/me", "tmp/x"]);
+
+        // The directory's own events count but it isn't one of its children
+        let me = dirs["Users/me"].counts;
+        assert_eq!((me.counts, dirs["Users/me"].children), (4, Some(2)));
+        assert_eq!(
+            me.flags,
+            0x0000_0001 | 0x0100_0000 | 0x1000_0000 | 0x0200_0000
+        );
+        assert_eq!(
+            (me.earliest_timestamp, me.latest_timestamp),
+            (ts(50), ts(300))
+        );
+        assert_eq!(
+            (dirs["tmp/x"].counts.counts, dirs["tmp/x"].children),
+            (1, Some(0))
+        );
+    }
+
+    #[test]
+    fn test_spilled_uniques_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let records: Vec<_> = (0..60u64)
+            .map(|i| Record {
+                path: format!("dir{}/file{}", i % 3, i % 7),
+                ..Record::default()
+                    .with_flag(1 << (i % 5))
+                    .with_timestamp(ts(i as i64))
+                    .with_event_id(i)
+                    .with_node_id(Some(i % 4))
+                    .with_source(["a", "b"][i as usize % 2])
+            })
+            .collect();
+
+        // A budget of a byte spills after every record
+        let rows = |bucket, budget| {
+            let mut aggregator =
+                UniquesAggregator::new(true, bucket, budget, dir.path().to_path_buf());
+            for rec in records.iter() {
+                aggregator.push(rec).unwrap();
+            }
+            aggregator
+                .finish()
+                .unwrap()
+                .map(|item| {
+                    let (bucket, path, entry) = item.unwrap();
+                    let out = entry.into_unique_out(path, true, &[UniqueDetail::All]);
+                    format!("{bucket} {}", serde_json::to_string(&out).unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let in_memory = rows(None, None);
+        assert_eq!(in_memory.len(), 21);
+        assert_eq!(rows(None, Some(1)), in_memory);
+        assert_eq!(rows(None, Some(4096)), in_memory);
+
+        // Each path is split between the files it was in
+        let by_file = rows(Some(Bucket::File), None);
+        assert_eq!(by_file.len(), 42);
+        assert!(by_file[..21].iter().all(|row| row.starts_with("a ")));
+        assert_eq!(rows(Some(Bucket::File), Some(1)), by_file);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spilled_non_utf8_sources() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        // Both names are the same once they're made utf8
+        let sources = [b"src\xfe".as_slice(), b"src\xff".as_slice()]
+            .map(|name| Arc::<Path>::from(Path::new(OsStr::from_bytes(name))));
+
+        let source_files = |budget| {
+            let mut aggregator =
+                UniquesAggregator::new(true, None, budget, dir.path().to_path_buf());
+            for source in sources.iter() {
+                let rec = Record {
+                    path: "a".into(),
+                    source: Some(source.clone()),
+                    ..Record::default()
+                        .with_flag(1)
+                        .with_timestamp(ts(1))
+                        .with_event_id(1)
+                        .with_source("")
+                };
+                aggregator.push(&rec).unwrap();
+            }
+            let (_, path, entry) = aggregator.finish().unwrap().next().unwrap().unwrap();
+            entry
+                .into_unique_out(path, false, &[UniqueDetail::SourceFiles])
+                .source_files
+        };
+
+        assert_eq!(source_files(None), Some(2));
+        assert_eq!(source_files(Some(1)), Some(2));
+    }
+
+    #[test]
+    fn test_unique_details() {
+        let mut entry = UniqueEntry::new(true);
+        entry.update(
+            &Record::default()
+                .with_flag(0x0100_0000 | 0x1000_0000)
+                .with_timestamp(ts(1))
+                .with_event_id(20)
+                .with_node_id(Some(7))
+                .with_source("a"),
+        );
+        entry.update(
+            &Record::default()
+                .with_flag(0x1000_0000)
+                .with_timestamp(ts(1))
+                .with_event_id(10)
+                .with_node_id(Some(7))
+                .with_source("a"),
+        );
+        entry.update(
+            &Record::default()
+                .with_flag(0x0200_0000)
+                .with_timestamp(ts(2))
+                .with_event_id(30)
+                .with_node_id(Some(9))
+                .with_source("b"),
+        );
+
+        let mut other = UniqueEntry::new(true);
+        other.update(
+            &Record::default()
+                .with_flag(0x1000_0000)
+                .with_timestamp(ts(3))
+                .with_event_id(5)
+                .with_source("c"),
+        );
+        entry.merge(other);
+
+        let out = entry
+            .clone()
+            .into_unique_out("a".into(), false, &[UniqueDetail::All]);
+        assert_eq!(out.counts, 4);
+        assert_eq!(
+            out.flag_counts.as_deref(),
+            Some("Created=1 | Removed=1 | Modified=3")
+        );
+        assert_eq!((out.first_event_id, out.last_event_id), (Some(5), Some(30)));
+        #[cfg(feature = "hex")]
+        assert_eq!(out.node_ids.as_deref(), Some("0x7 | 0x9"));
+        #[cfg(not(feature = "hex"))]
+        assert_eq!(out.node_ids.as_deref(), Some("7 | 9"));
+        assert_eq!(out.source_files, Some(3));
+
+        // Only the wanted columns are filled in
+        let out = entry.into_unique_out("a".into(), false, &[UniqueDetail::SourceFiles]);
+        assert_eq!(
+            (
+                out.flag_counts,
+                out.first_event_id,
+                out.node_ids,
+                out.source_files
+            ),
+            (None, None, None, Some(3))
+        );
+    }
+
     #[test]
     fn test_timestamp_with_same_values() {
         let mut uc = UniqueCounts::default();
@@ -127,10 +127,10 @@ where
         } else {
             debug!("Reading path done");
 
-            let path = String::from_utf8_lossy(&sbuf[..rlen - 1]).into_owned();
+            let (path, raw_path, path_lossy) = Record::decode_path(&sbuf[..rlen - 1]);
             debug!("Found path {path}");
 
-            let event_id = reader.read_u64::<BigEndian>()?;
+            let event_id = reader.read_u64::<LittleEndian>()?;
             debug!("Found event id {event_id}");
 
             let flag = reader.read_u32::<BigEndian>()?;
@@ -159,6 +159,18 @@ where
                 tlen,
                 Record {
                     path,
+                    path_lossy,
+                    path_base64: None,
+                    canonical_path: None,
+                    dir: None,
+                    name: None,
+                    ext: None,
+                    depth: None,
+                    home_user: None,
+                    volume: None,
+                    artifact_category: None,
+                    artifact_name: None,
+                    raw_path,
                     event_id,
                     flag,
                     flags: flags.norm,
@@ -168,6 +180,8 @@ where
                     #[cfg(feature = "extra_id")]
                     extra_id,
                     file_timestamp: None,
+                    ioc_ids: None,
+                    source: None,
                 },
             )))
         }
@@ -263,7 +277,7 @@ mod tests {
     fn test_v1_parser_basic_record() {
         let mut data = Vec::new();
         data.extend_from_slice(b"/test/path\0"); // path with null terminator
-        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_be_bytes()); // event_id
+        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_le_bytes()); // event_id
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes()); // flag (Modified)
 
         let mut cursor = Cursor::new(data);
@@ -283,7 +297,7 @@ mod tests {
     fn test_v2_parser_basic_record() {
         let mut data = Vec::new();
         data.extend_from_slice(b"/test/path\0");
-        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_be_bytes()); // event_id
+        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_le_bytes()); // event_id
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes()); // flag
         data.extend_from_slice(&0x0000_0000_0000_1234u64.to_le_bytes()); // node_id (little endian)
 
@@ -303,7 +317,7 @@ mod tests {
     fn test_v3_parser_basic_record() {
         let mut data = Vec::new();
         data.extend_from_slice(b"/test/path\0");
-        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_be_bytes()); // event_id
+        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_le_bytes()); // event_id
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes()); // flag
         data.extend_from_slice(&0x0000_0000_0000_1234u64.to_le_bytes()); // node_id
         data.extend_from_slice(&0x5678u32.to_ne_bytes()); // extra_id (native endian)
@@ -326,7 +340,7 @@ mod tests {
     fn test_parser_empty_path() {
         let mut data = Vec::new();
         data.push(b'\0'); // Empty path
-        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_be_bytes());
+        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_le_bytes());
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
 
         let mut cursor = Cursor::new(data);
@@ -343,7 +357,7 @@ mod tests {
         let mut data = Vec::new();
         data.extend_from_slice(long_path.as_bytes());
         data.push(b'\0');
-        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_be_bytes());
+        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_le_bytes());
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
 
         let mut cursor = Cursor::new(data);
@@ -360,7 +374,7 @@ mod tests {
         let mut data = Vec::new();
         data.extend_from_slice(unicode_path.as_bytes());
         data.push(b'\0');
-        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_be_bytes());
+        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_le_bytes());
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
 
         let mut cursor = Cursor::new(data);
@@ -383,7 +397,7 @@ mod tests {
         let flags: u32 = 0x1000_0000 | 0x0100_0000 | 0x0000_8000; // Modified | Created | FileEvent
         let mut data = Vec::new();
         data.extend_from_slice(b"/test\0");
-        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_be_bytes());
+        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_le_bytes());
         data.extend_from_slice(&flags.to_be_bytes());
 
         let mut cursor = Cursor::new(data);
@@ -401,7 +415,7 @@ mod tests {
     fn test_parser_zero_event_id() {
         let mut data = Vec::new();
         data.extend_from_slice(b"/test\0");
-        data.extend_from_slice(&0u64.to_be_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
 
         let mut cursor = Cursor::new(data);
@@ -416,7 +430,7 @@ mod tests {
     fn test_parser_max_event_id() {
         let mut data = Vec::new();
         data.extend_from_slice(b"/test\0");
-        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        data.extend_from_slice(&u64::MAX.to_le_bytes());
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
 
         let mut cursor = Cursor::new(data);
@@ -427,13 +441,51 @@ mod tests {
         assert_eq!(record.event_id, u64::MAX);
     }
 
+    #[test]
+    fn test_parser_event_id_little_endian() {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"/test\0");
+        data.extend_from_slice(&[0xf2, 0xc4, 0x42, 0x03, 0, 0, 0, 0]);
+        data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
+
+        let mut cursor = Cursor::new(data);
+        let (_, record) = V1::parse_record(&mut cursor).unwrap().unwrap();
+        assert_eq!(record.event_id, 0x342_c4f2);
+    }
+
+    #[test]
+    fn test_parser_keeps_invalid_utf8_path() {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"/tmp/bad\xff\xfe.bin\0");
+        data.extend_from_slice(&42u64.to_le_bytes());
+        data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
+        data.extend_from_slice(b"/tmp/good\0");
+        data.extend_from_slice(&43u64.to_le_bytes());
+        data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
+
+        let mut cursor = Cursor::new(data);
+        let (size, record) = V1::parse_record(&mut cursor).unwrap().unwrap();
+        assert_eq!(size, 15 + 8 + 4);
+        assert_eq!(record.path, "/tmp/bad\u{fffd}\u{fffd}.bin");
+        assert!(record.path_lossy);
+        assert_eq!(
+            record.raw_path.as_deref(),
+            Some(&b"/tmp/bad\xff\xfe.bin"[..])
+        );
+
+        let (_, record) = V1::parse_record(&mut cursor).unwrap().unwrap();
+        assert_eq!(record.path, "/tmp/good");
+        assert!(!record.path_lossy);
+        assert!(record.raw_path.is_none());
+    }
+
     #[test]
     fn test_parser_calculates_size_v1() {
         let path = "/test/path";
         let mut data = Vec::new();
         data.extend_from_slice(path.as_bytes());
         data.push(b'\0');
-        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_be_bytes());
+        data.extend_from_slice(&0x0000_0000_0000_0042u64.to_le_bytes());
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
 
         let expected_size = path.len() + 1 + 8 + 4; // path + null + u64 + u32
@@ -452,7 +504,7 @@ mod tests {
         let mut data = Vec::new();
         data.extend_from_slice(path.as_bytes());
         data.push(b'\0');
-        data.extend_from_slice(&0x42u64.to_be_bytes());
+        data.extend_from_slice(&0x42u64.to_le_bytes());
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
         data.extend_from_slice(&0x1234u64.to_le_bytes());
 
@@ -472,7 +524,7 @@ mod tests {
         let mut data = Vec::new();
         data.extend_from_slice(path.as_bytes());
         data.push(b'\0');
-        data.extend_from_slice(&0x42u64.to_be_bytes());
+        data.extend_from_slice(&0x42u64.to_le_bytes());
         data.extend_from_slice(&0x1000_0000u32.to_be_bytes());
         data.extend_from_slice(&0x1234u64.to_le_bytes());
         data.extend_from_slice(&0x5678u32.to_ne_bytes());